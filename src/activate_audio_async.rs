@@ -1,130 +1,205 @@
-use std::sync::Mutex;
-
-use future_handles::{
-    self, HandleError,
-    sync::{self, CompleteHandle},
+use std::{
+    mem::{self, ManuallyDrop},
+    slice,
+    time::Duration,
 };
+
 use thiserror::Error;
 use windows::{
     Win32::{
-        Foundation::{self, CloseHandle},
         Media::Audio::{
-            AUDIOCLIENT_ACTIVATION_PARAMS, AUDIOCLIENT_ACTIVATION_TYPE_PROCESS_LOOPBACK,
+            AUDIOCLIENT_ACTIVATION_PARAMS, AUDIOCLIENT_ACTIVATION_PARAMS_0,
+            AUDIOCLIENT_ACTIVATION_TYPE_PROCESS_LOOPBACK, AUDIOCLIENT_PROCESS_LOOPBACK_PARAMS,
             ActivateAudioInterfaceAsync, IActivateAudioInterfaceAsyncOperation,
             IActivateAudioInterfaceCompletionHandler,
             IActivateAudioInterfaceCompletionHandler_Impl, IAudioClient,
+            PROCESS_LOOPBACK_MODE_EXCLUDE_TARGET_PROCESS_TREE,
             PROCESS_LOOPBACK_MODE_INCLUDE_TARGET_PROCESS_TREE,
             VIRTUAL_AUDIO_DEVICE_PROCESS_LOOPBACK,
         },
-        System::{
-            Com::StructuredStorage::PROPVARIANT,
-            Threading::{CreateEventW, INFINITE, SetEvent, WaitForSingleObject},
-            Variant::VT_BLOB,
-        },
+        System::{Com::StructuredStorage::PROPVARIANT, Variant::VT_BLOB},
     },
     core::{HRESULT, IUnknown, Interface, implement},
 };
 
-pub fn capture_process_sync(
-    pid: u32,
-    capture_tree: bool,
-) -> Result<IAudioClient, windows::core::Error> {
-    use std::mem;
-    let mut params = AUDIOCLIENT_ACTIVATION_PARAMS::default();
-    params.ActivationType = AUDIOCLIENT_ACTIVATION_TYPE_PROCESS_LOOPBACK;
-    if capture_tree {
-        params.Anonymous.ProcessLoopbackParams.ProcessLoopbackMode =
-            PROCESS_LOOPBACK_MODE_INCLUDE_TARGET_PROCESS_TREE;
-    }
-    params.Anonymous.ProcessLoopbackParams.TargetProcessId = pid;
-    let mut pv = PROPVARIANT::default();
+use crate::{
+    completion::{CancelToken, Completer, Completion, WaitError},
+    process::ProcessLoopbackMode,
+};
 
-    unsafe {
-        (*pv.Anonymous.Anonymous).vt = VT_BLOB;
-        (*pv.Anonymous.Anonymous).Anonymous.blob.cbSize =
-            mem::size_of::<AUDIOCLIENT_ACTIVATION_PARAMS>() as u32;
-        (*pv.Anonymous.Anonymous).Anonymous.blob.pBlobData = &params as *const _ as *mut u8;
+/// Builds the `AUDIOCLIENT_ACTIVATION_PARAMS` passed to `ActivateAudioInterfaceAsync`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ActivationParamsBuilder {
+    target_pid: u32,
+    mode: ProcessLoopbackMode,
+}
+
+impl ActivationParamsBuilder {
+    pub fn process_loopback(target_pid: u32) -> Self {
+        Self {
+            target_pid,
+            mode: ProcessLoopbackMode::default(),
+        }
+    }
+
+    pub fn mode(mut self, mode: ProcessLoopbackMode) -> Self {
+        self.mode = mode;
+        self
+    }
 
-        let aud_client: IAudioClient =
-            activate_audio_interface_sync(VIRTUAL_AUDIO_DEVICE_PROCESS_LOOPBACK, Some(&pv))?;
-        mem::forget(pv);
-        Ok(aud_client)
+    pub fn build(&self) -> ActivationParams {
+        let mode = match self.mode {
+            ProcessLoopbackMode::Include => PROCESS_LOOPBACK_MODE_INCLUDE_TARGET_PROCESS_TREE,
+            ProcessLoopbackMode::Exclude => PROCESS_LOOPBACK_MODE_EXCLUDE_TARGET_PROCESS_TREE,
+        };
+
+        ActivationParams(AUDIOCLIENT_ACTIVATION_PARAMS {
+            ActivationType: AUDIOCLIENT_ACTIVATION_TYPE_PROCESS_LOOPBACK,
+            Anonymous: AUDIOCLIENT_ACTIVATION_PARAMS_0 {
+                ProcessLoopbackParams: AUDIOCLIENT_PROCESS_LOOPBACK_PARAMS {
+                    TargetProcessId: self.target_pid,
+                    ProcessLoopbackMode: mode,
+                },
+            },
+        })
     }
 }
 
+/// Owns the blob referenced by the activation `PROPVARIANT`. The variant only lives for the
+/// duration of `activate`/`activate_sync`, which borrow `self`, so it can never dangle
+pub struct ActivationParams(AUDIOCLIENT_ACTIVATION_PARAMS);
+
+impl ActivationParams {
+    pub fn params(&self) -> &AUDIOCLIENT_ACTIVATION_PARAMS {
+        &self.0
+    }
+
+    pub fn blob(&self) -> &[u8] {
+        unsafe {
+            slice::from_raw_parts(
+                &self.0 as *const _ as *const u8,
+                mem::size_of::<AUDIOCLIENT_ACTIVATION_PARAMS>(),
+            )
+        }
+    }
+
+    // `PROPVARIANT` frees its blob with `CoTaskMemFree` on drop, which must not happen to ours
+    fn propvariant(&self) -> ManuallyDrop<PROPVARIANT> {
+        let blob = self.blob();
+        let mut pv = PROPVARIANT::default();
+        unsafe {
+            (*pv.Anonymous.Anonymous).vt = VT_BLOB;
+            (*pv.Anonymous.Anonymous).Anonymous.blob.cbSize = blob.len() as u32;
+            (*pv.Anonymous.Anonymous).Anonymous.blob.pBlobData = blob.as_ptr() as *mut u8;
+        }
+        ManuallyDrop::new(pv)
+    }
+
+    pub fn activate_sync<Out: Interface>(
+        &self,
+        options: &ActivationOptions,
+    ) -> Result<Out, ActivationError> {
+        let pv = self.propvariant();
+        unsafe {
+            activate_audio_interface_sync_with(
+                VIRTUAL_AUDIO_DEVICE_PROCESS_LOOPBACK,
+                Some(&*pv),
+                options,
+            )
+        }
+    }
+
+    pub async fn activate<Out: Interface>(
+        &self,
+        options: &ActivationOptions,
+    ) -> Result<Out, ActivationError> {
+        let pv = self.propvariant();
+        unsafe {
+            activate_audio_interface_async_with(
+                VIRTUAL_AUDIO_DEVICE_PROCESS_LOOPBACK,
+                Some(&*pv),
+                options,
+            )
+            .await
+        }
+    }
+}
+
+pub fn capture_process_sync(
+    pid: u32,
+    mode: ProcessLoopbackMode,
+    options: &ActivationOptions,
+) -> Result<IAudioClient, ActivationError> {
+    ActivationParamsBuilder::process_loopback(pid)
+        .mode(mode)
+        .build()
+        .activate_sync(options)
+}
+
 pub async fn capture_process(
     pid: u32,
-    capture_tree: bool,
+    mode: ProcessLoopbackMode,
+    options: &ActivationOptions,
 ) -> Result<IAudioClient, ActivationError> {
-    use std::mem;
-    let mut params = AUDIOCLIENT_ACTIVATION_PARAMS::default();
-    params.ActivationType = AUDIOCLIENT_ACTIVATION_TYPE_PROCESS_LOOPBACK;
-    if capture_tree {
-        params.Anonymous.ProcessLoopbackParams.ProcessLoopbackMode =
-            PROCESS_LOOPBACK_MODE_INCLUDE_TARGET_PROCESS_TREE;
-    }
-    params.Anonymous.ProcessLoopbackParams.TargetProcessId = pid;
-    let mut pv = PROPVARIANT::default();
+    ActivationParamsBuilder::process_loopback(pid)
+        .mode(mode)
+        .build()
+        .activate(options)
+        .await
+}
 
-    unsafe {
-        (*pv.Anonymous.Anonymous).vt = VT_BLOB;
-        (*pv.Anonymous.Anonymous).Anonymous.blob.cbSize =
-            mem::size_of::<AUDIOCLIENT_ACTIVATION_PARAMS>() as u32;
-        (*pv.Anonymous.Anonymous).Anonymous.blob.pBlobData = &params as *const _ as *mut u8;
+/// How long to wait for `ActivateAudioInterfaceAsync` to complete, and how to give up early
+#[derive(Clone, Default)]
+pub struct ActivationOptions {
+    pub timeout: Option<Duration>,
+    pub cancel: Option<CancelToken>,
+}
+
+impl ActivationOptions {
+    pub fn timeout(timeout: Duration) -> Self {
+        Self {
+            timeout: Some(timeout),
+            cancel: None,
+        }
+    }
 
-        let aud_client: IAudioClient =
-            activate_audio_interface_async(VIRTUAL_AUDIO_DEVICE_PROCESS_LOOPBACK, Some(&pv))
-                .await?;
-        // `PROPVARIANT` referencing `AUDIOCLIENT_ACTIVATION_PARAMS`! Do not drop it before the returned value
-        // Treat it like into_propvariant(client: &AUDIOCLIENT_ACTIVATION_PARAMS) -> PROPVARIANT + '_
-        mem::forget(pv);
-        Ok(aud_client)
+    pub fn cancel(mut self, token: CancelToken) -> Self {
+        self.cancel = Some(token);
+        self
     }
 }
 
 pub unsafe fn activate_audio_interface_sync<P0, Out>(
     deviceinterfacepath: P0,
     activationparams: ::core::option::Option<*const PROPVARIANT>,
-) -> Result<Out, windows::core::Error>
+) -> Result<Out, ActivationError>
 where
     P0: ::windows::core::Param<::windows::core::PCWSTR>,
     Out: Interface,
 {
     unsafe {
-        let ev = CreateEventW(None, false, false, None)?;
-        let completionhandler: IActivateAudioInterfaceCompletionHandler = SyncHandler(ev).into();
-        let result = ActivateAudioInterfaceAsync(
+        activate_audio_interface_sync_with(
             deviceinterfacepath,
-            &Out::IID,
             activationparams,
-            &completionhandler,
-        )?;
-
-        WaitForSingleObject(ev, INFINITE);
-
-        let mut hr = HRESULT(0);
-        let mut ai: Option<IUnknown> = None;
-        result.GetActivateResult(&mut hr, &mut ai)?;
-        CloseHandle(ev)?;
-
-        if let Some(comi) = ai {
-            Ok(comi.cast()?)
-        } else {
-            let err = windows::core::Error::from(hr);
-            Err(err.into())
-        }
+            &ActivationOptions::default(),
+        )
     }
 }
 
-#[implement(IActivateAudioInterfaceCompletionHandler)]
-struct SyncHandler(Foundation::HANDLE);
-
-impl IActivateAudioInterfaceCompletionHandler_Impl for SyncHandler_Impl {
-    fn ActivateCompleted(
-        &self,
-        _: windows_core::Ref<IActivateAudioInterfaceAsyncOperation>,
-    ) -> windows_core::Result<()> {
-        unsafe { SetEvent(self.0) }
+pub unsafe fn activate_audio_interface_sync_with<P0, Out>(
+    deviceinterfacepath: P0,
+    activationparams: ::core::option::Option<*const PROPVARIANT>,
+    options: &ActivationOptions,
+) -> Result<Out, ActivationError>
+where
+    P0: ::windows::core::Param<::windows::core::PCWSTR>,
+    Out: Interface,
+{
+    unsafe {
+        let (completion, operation) =
+            start_activation::<_, Out>(deviceinterfacepath, activationparams, options)?;
+        completion.wait(options.timeout)?;
+        activate_result(&operation)
     }
 }
 
@@ -136,45 +211,83 @@ where
     P0: ::windows::core::Param<::windows::core::PCWSTR>,
     Out: Interface,
 {
-    let (future, handle) = sync::create();
+    unsafe {
+        activate_audio_interface_async_with(
+            deviceinterfacepath,
+            activationparams,
+            &ActivationOptions::default(),
+        )
+        .await
+    }
+}
+
+pub async unsafe fn activate_audio_interface_async_with<P0, Out>(
+    deviceinterfacepath: P0,
+    activationparams: ::core::option::Option<*const PROPVARIANT>,
+    options: &ActivationOptions,
+) -> Result<Out, ActivationError>
+where
+    P0: ::windows::core::Param<::windows::core::PCWSTR>,
+    Out: Interface,
+{
+    let (completion, operation) =
+        unsafe { start_activation::<_, Out>(deviceinterfacepath, activationparams, options)? };
+    completion.wait_async(options.timeout).await?;
+    activate_result(&operation)
+}
+
+unsafe fn start_activation<P0, Out>(
+    deviceinterfacepath: P0,
+    activationparams: ::core::option::Option<*const PROPVARIANT>,
+    options: &ActivationOptions,
+) -> Result<(Completion, IActivateAudioInterfaceAsyncOperation), ActivationError>
+where
+    P0: ::windows::core::Param<::windows::core::PCWSTR>,
+    Out: Interface,
+{
+    let (completion, completer) = match &options.cancel {
+        Some(token) => Completion::with_cancel(token),
+        None => Completion::new(),
+    };
 
     let completionhandler: IActivateAudioInterfaceCompletionHandler =
-        AsyncHandler(Mutex::new(Some(handle))).into();
-    unsafe {
-        let result = ActivateAudioInterfaceAsync(
+        CompletionHandler(completer).into();
+    let operation = unsafe {
+        ActivateAudioInterfaceAsync(
             deviceinterfacepath,
             &Out::IID,
             activationparams,
             &completionhandler,
-        )?;
-        future.await?;
-
-        let mut hr = HRESULT(0);
-        // let mut hr: MaybeUninit<HRESULT> = MaybeUninit::uninit();
-        let mut ai: Option<IUnknown> = None;
-        result.GetActivateResult(&mut hr, &mut ai)?;
-
-        if let Some(comi) = ai {
-            Ok(comi.cast()?)
-        } else {
-            let err = windows::core::Error::from(hr);
-            Err(err.into())
-        }
+        )?
+    };
+    Ok((completion, operation))
+}
+
+fn activate_result<Out: Interface>(
+    operation: &IActivateAudioInterfaceAsyncOperation,
+) -> Result<Out, ActivationError> {
+    let mut hr = HRESULT(0);
+    let mut ai: Option<IUnknown> = None;
+    unsafe { operation.GetActivateResult(&mut hr, &mut ai)? };
+
+    if let Some(comi) = ai {
+        Ok(comi.cast()?)
+    } else {
+        let err = windows::core::Error::from(hr);
+        Err(err.into())
     }
 }
 
+// The same handler serves the blocking and the async paths, only the waiting side differs
 #[implement(IActivateAudioInterfaceCompletionHandler)]
-struct AsyncHandler(Mutex<Option<CompleteHandle<()>>>);
+struct CompletionHandler(Completer);
 
-impl IActivateAudioInterfaceCompletionHandler_Impl for AsyncHandler_Impl {
+impl IActivateAudioInterfaceCompletionHandler_Impl for CompletionHandler_Impl {
     fn ActivateCompleted(
         &self,
         _: windows::core::Ref<IActivateAudioInterfaceAsyncOperation>,
     ) -> windows::core::Result<()> {
-        let val = self.0.lock().unwrap().take();
-        if let Some(handle) = val {
-            handle.complete(());
-        }
+        self.0.complete();
         Ok(())
     }
 }
@@ -184,13 +297,23 @@ pub enum ActivationError {
     #[error(transparent)]
     WindowError(windows::core::Error),
 
-    #[error(transparent)]
-    HandleError(HandleError),
+    #[error("activation timed out after {0:?}")]
+    Timeout(Duration),
+
+    #[error("activation was cancelled")]
+    Cancelled,
+
+    #[error("activation completion handler was released without completing")]
+    Abandoned,
 }
 
-impl From<HandleError> for ActivationError {
-    fn from(value: HandleError) -> Self {
-        ActivationError::HandleError(value)
+impl From<WaitError> for ActivationError {
+    fn from(value: WaitError) -> Self {
+        match value {
+            WaitError::Timeout(timeout) => ActivationError::Timeout(timeout),
+            WaitError::Cancelled => ActivationError::Cancelled,
+            WaitError::Abandoned => ActivationError::Abandoned,
+        }
     }
 }
 
@@ -199,3 +322,40 @@ impl From<windows::core::Error> for ActivationError {
         ActivationError::WindowError(value)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_u32(blob: &[u8], offset: usize) -> u32 {
+        u32::from_ne_bytes(blob[offset..offset + 4].try_into().unwrap())
+    }
+
+    #[test]
+    fn blob_layout() {
+        for (mode, expected) in [
+            (
+                ProcessLoopbackMode::Include,
+                PROCESS_LOOPBACK_MODE_INCLUDE_TARGET_PROCESS_TREE,
+            ),
+            (
+                ProcessLoopbackMode::Exclude,
+                PROCESS_LOOPBACK_MODE_EXCLUDE_TARGET_PROCESS_TREE,
+            ),
+        ] {
+            let params = ActivationParamsBuilder::process_loopback(0x1234_5678)
+                .mode(mode)
+                .build();
+            let blob = params.blob();
+            assert_eq!(blob.len(), mem::size_of::<AUDIOCLIENT_ACTIVATION_PARAMS>());
+            // ActivationType, then the loopback params: TargetProcessId, ProcessLoopbackMode
+            assert_eq!(blob.len(), 12);
+            assert_eq!(
+                read_u32(blob, 0),
+                AUDIOCLIENT_ACTIVATION_TYPE_PROCESS_LOOPBACK.0 as u32
+            );
+            assert_eq!(read_u32(blob, 4), 0x1234_5678);
+            assert_eq!(read_u32(blob, 8), expected.0 as u32);
+        }
+    }
+}
@@ -0,0 +1,316 @@
+use std::{thread, time::Duration};
+
+use anyhow::{Result, bail};
+use clap::Parser;
+use tracing::{debug, level_filters::LevelFilter};
+use tracing_subscriber::EnvFilter;
+use wasapi_low_latency::{
+    activate_audio_async::{ActivationOptions, capture_process_sync},
+    device::{DeviceSelector, DeviceState, DeviceStateMask, Flow},
+    duck::Sidechain,
+    endpoints::{device_infos, get_device},
+    engine::InputKind,
+    generate::GeneratorPipe,
+    loudness::{LoudnessMeter, LoudnessReadings},
+    measure::RoundTripMeter,
+    mix::{MixPipe, MixSource},
+    pipe::{ClientSource, PipeStreamInfo},
+    process::{ProcessSelector, audio_processes},
+    processor::Chain,
+    roundtrip::Stimulus,
+    system_processes::SystemProcesses,
+    utils::WaveFormat,
+};
+use windows::Win32::{
+    Media::Audio::{IAudioClient, IMMDevice},
+    System::{
+        Com::{CLSCTX_ALL, COINIT_MULTITHREADED, COINIT_SPEED_OVER_MEMORY, CoInitializeEx},
+        Threading::AvSetMmThreadCharacteristicsW,
+    },
+};
+use windows_strings::w;
+
+use crate::{config::Config, prompt::prompt};
+
+pub fn run() -> Result<()> {
+    let config = Config::parse();
+    init_logging(&config);
+    unsafe {
+        CoInitializeEx(None, COINIT_SPEED_OVER_MEMORY | COINIT_MULTITHREADED).ok()?;
+        if config.list_devices {
+            return list_devices(config.device_states);
+        }
+
+        let mixing = config.mix_inputs > 1;
+        if mixing && config.outputs > 1 {
+            bail!("mixing into several outputs is not supported, pick --mix-inputs or --outputs");
+        }
+        if let Some(key) = config.duck_key
+            && !(mixing && (1..=config.mix_inputs).contains(&key))
+        {
+            bail!("--duck-key {key} needs --mix-inputs of at least {key} and 2");
+        }
+        if config.generate.is_some() && (mixing || config.outputs > 1) {
+            bail!("--generate plays into a single output, without --mix-inputs or --outputs");
+        }
+        if config.measure_latency.is_some()
+            && (mixing || config.outputs > 1 || config.generate.is_some())
+        {
+            bail!("--measure-latency needs one input and one output");
+        }
+        // A generated signal takes the place of every input
+        let input_count = match config.generate {
+            Some(_) => 0,
+            None => config.mix_inputs.max(1),
+        };
+        let mut inputs = Vec::new();
+        for i in 0..input_count {
+            if mixing {
+                println!("Input {}:", i + 1);
+            }
+            let (kind, input) = prompt_input(&config)?;
+            let gain_db: f32 = if mixing {
+                prompt("Gain in dB: ")?
+            } else {
+                0f32
+            };
+            inputs.push((kind, input, gain_db));
+        }
+
+        let mut outputs = Vec::new();
+        for i in 0..config.outputs.max(1) {
+            if config.outputs > 1 {
+                println!("Please select output device {}:", i + 1);
+            } else {
+                println!("Please select output device:");
+            }
+            outputs.push(prompt_device(Flow::Render, config.device_states)?);
+        }
+        let ac: IAudioClient = outputs[0].Activate(CLSCTX_ALL, None)?;
+        let wfx: WaveFormat = ac.GetMixFormat()?.into();
+        let mut renders: Vec<_> = outputs.into_iter().map(ClientSource::Device).collect();
+
+        if let Some(stimulus) = config.measure_latency {
+            let (kind, input, _) = inputs.remove(0);
+            let capture = open_input(input, &config)?;
+            let mut meter = RoundTripMeter::open(
+                capture,
+                config.input_stream(kind),
+                renders.remove(0),
+                config.output_stream(),
+                wfx,
+            )?;
+            register_mmcss();
+            measure_latency(&mut meter, stimulus, &config)?;
+        } else if let Some(signal) = config.generate {
+            let mut gp = GeneratorPipe::open(
+                signal,
+                config.generate_level_db,
+                renders.remove(0),
+                config.output_stream(),
+                wfx,
+            )?;
+            gp.set_processors(processors(&config));
+            register_mmcss();
+            gp.run()?;
+        } else if !mixing {
+            let (kind, input, _) = inputs.remove(0);
+            let capture = open_input(input, &config)?;
+            let renders = renders
+                .into_iter()
+                .map(|x| (x, config.output_stream()))
+                .collect();
+            let mut ps =
+                PipeStreamInfo::open_fanout(capture, config.input_stream(kind), renders, wfx)?;
+            ps.set_processors(processors(&config))?;
+            if config.detect_jumps {
+                ps.detect_jumps();
+            }
+            register_mmcss();
+            ps.run()?;
+        } else {
+            let mut sources = Vec::new();
+            let sidechain = Sidechain::new();
+            for (i, (kind, input, gain_db)) in inputs.into_iter().enumerate() {
+                sources.push(MixSource {
+                    source: open_input(input, &config)?,
+                    config: config.input_stream(kind),
+                    gain_db,
+                    processors: config.input_processors(i, &sidechain),
+                });
+            }
+            let mut mp = MixPipe::open(
+                sources,
+                renders.remove(0),
+                config.output_stream(),
+                wfx,
+                config.mix_options(),
+            )?;
+            mp.set_processors(processors(&config));
+            register_mmcss();
+            mp.run()?;
+        }
+        println!("Done");
+        Ok(())
+    }
+}
+
+/// The configured chain, with a loudness meter at the end when asked for
+fn processors(config: &Config) -> Chain {
+    let mut chain = config.processors();
+    if config.show_loudness {
+        let meter = LoudnessMeter::new();
+        let readings = meter.readings();
+        thread::spawn(move || report_loudness(&readings));
+        chain.push(meter);
+    }
+    chain
+}
+
+fn report_loudness(readings: &LoudnessReadings) {
+    loop {
+        thread::sleep(Duration::from_secs(1));
+        println!(
+            "M {:.1} LUFS, S {:.1} LUFS, I {:.1} LUFS",
+            readings.momentary(),
+            readings.short_term(),
+            readings.integrated()
+        );
+    }
+}
+
+fn measure_latency(meter: &mut RoundTripMeter, stimulus: Stimulus, config: &Config) -> Result<()> {
+    let rate = meter.sample_rate();
+    let ms = |frames: usize| frames as f64 * 1000f64 / rate as f64;
+    let mut measured = Vec::new();
+    for i in 0..config.measure_count {
+        match meter.measure(stimulus, config.measure_level_db) {
+            Ok(rt) => {
+                println!(
+                    "Round trip {}: {:.2}ms ({} frames, peak {:.1} dB above the correlation)",
+                    i + 1,
+                    rt.millis(),
+                    rt.frames,
+                    rt.clarity_db
+                );
+                measured.push(rt.frames);
+            }
+            Err(e) => println!("Round trip {}: {e}", i + 1),
+        }
+    }
+    if measured.is_empty() {
+        bail!("no round trip could be measured");
+    }
+    measured.sort_unstable();
+    println!(
+        "Measured {:.2}ms median ({:.2}ms to {:.2}ms), theoretical {:.2}ms (input + output min_period)",
+        ms(measured[measured.len() / 2]),
+        ms(measured[0]),
+        ms(measured[measured.len() - 1]),
+        ms(meter.theoretical_frames())
+    );
+    Ok(())
+}
+
+/// `RUST_LOG` wins over `--log-level` when it is set
+fn init_logging(config: &Config) {
+    let filter = EnvFilter::builder()
+        .with_default_directive(LevelFilter::from(config.log_level).into())
+        .from_env_lossy();
+    tracing_subscriber::fmt().with_env_filter(filter).init();
+}
+
+fn register_mmcss() {
+    let mut task_idx = 0;
+    unsafe { AvSetMmThreadCharacteristicsW(w!("Pro Audio"), &mut task_idx).unwrap() };
+    debug!(task_idx, "registered for the MMCSS Pro Audio task");
+}
+
+enum Input {
+    Device(IMMDevice),
+    Process(u32),
+}
+
+fn prompt_input(config: &Config) -> Result<(InputKind, Input)> {
+    let kind = match config.input {
+        Some(kind) => kind,
+        None => {
+            println!("Choose input type: ");
+            for (i, kind) in InputKind::ALL.iter().enumerate() {
+                println!("{}: {kind}", i + 1);
+            }
+            prompt("Choice: ")?
+        }
+    };
+    kind.validate(config.input_share_mode)?;
+
+    let input = match kind {
+        InputKind::Device => {
+            println!("Please select input device:");
+            Input::Device(prompt_device(Flow::Capture, config.device_states)?)
+        }
+        InputKind::Process => Input::Process(prompt_process()?),
+        InputKind::Loopback => {
+            println!("Please select the output device to record:");
+            Input::Device(prompt_device(Flow::Render, config.device_states)?)
+        }
+    };
+    Ok((kind, input))
+}
+
+fn open_input(input: Input, config: &Config) -> Result<ClientSource> {
+    Ok(match input {
+        Input::Device(dev) => ClientSource::Device(dev),
+        Input::Process(pid) => {
+            let ac = capture_process_sync(
+                pid,
+                config.loopback_mode,
+                &ActivationOptions {
+                    timeout: config.activation_timeout(),
+                    cancel: None,
+                },
+            )?;
+            ClientSource::Client(ac)
+        }
+    })
+}
+
+fn prompt_process() -> Result<u32> {
+    println!("Processes currently playing audio:");
+    for proc in audio_processes(&SystemProcesses)? {
+        println!("  {proc}");
+    }
+    let selector: ProcessSelector = prompt("Process to capture (pid, exe name, title: or exe:): ")?;
+    let proc = selector.resolve(&SystemProcesses)?;
+    println!("Capturing {proc}");
+    Ok(proc.pid)
+}
+
+fn list_devices(states: DeviceStateMask) -> Result<()> {
+    for flow in [Flow::Render, Flow::Capture] {
+        println!("{flow:?} endpoints:");
+        for dev in device_infos(flow, states)? {
+            println!("  {:<12} {} ({})", dev.state.to_string(), dev.name, dev.id);
+        }
+    }
+    Ok(())
+}
+
+fn prompt_device(flow: Flow, states: DeviceStateMask) -> Result<IMMDevice> {
+    let devs = device_infos(flow, states)?;
+    for (i, dev) in devs.iter().enumerate() {
+        let default = if dev.default_roles.is_empty() {
+            ""
+        } else {
+            " (default)"
+        };
+        let state = if dev.state == DeviceState::Active {
+            String::new()
+        } else {
+            format!(" [{}]", dev.state)
+        };
+        println!("{i:<2} {}{default}{state}", dev.name);
+    }
+    let selector: DeviceSelector = prompt("Choice (index, name, id:, re: or default): ")?;
+    get_device(&selector.select(&devs)?.id)
+}
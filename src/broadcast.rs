@@ -0,0 +1,266 @@
+use rtrb::{Consumer, Producer, RingBuffer};
+
+use crate::stream::read_frames;
+
+/// One writer feeding several readers, each through its own ring of `frames[i]` frames. A reader
+/// that falls behind only loses frames itself, the writer and the other readers never wait on it
+pub fn broadcast(frames: &[usize], block_align: usize) -> (BroadcastWriter, Vec<BroadcastReader>) {
+    let mut outputs = Vec::with_capacity(frames.len());
+    let mut readers = Vec::with_capacity(frames.len());
+    for &frames in frames {
+        let (producer, consumer) = RingBuffer::new(frames * block_align);
+        outputs.push(BroadcastOutput {
+            producer,
+            dropped: 0,
+        });
+        readers.push(BroadcastReader {
+            consumer,
+            block_align,
+        });
+    }
+    (
+        BroadcastWriter {
+            outputs,
+            block_align,
+        },
+        readers,
+    )
+}
+
+struct BroadcastOutput {
+    producer: Producer<u8>,
+    dropped: usize,
+}
+
+/// Writing end of a [`broadcast`]
+pub struct BroadcastWriter {
+    outputs: Vec<BroadcastOutput>,
+    block_align: usize,
+}
+
+impl BroadcastWriter {
+    /// Never blocks. Every reader gets as many whole frames of `data` as fit in its ring, the rest
+    /// is counted as dropped for that reader. Returns the fewest frames any live reader took
+    pub fn push(&mut self, data: &[u8]) -> usize {
+        let frames = data.len() / self.block_align;
+        let mut fewest = frames;
+        for output in &mut self.outputs {
+            if output.producer.is_abandoned() {
+                continue;
+            }
+            let len = (frames * self.block_align).min(output.producer.slots()) / self.block_align
+                * self.block_align;
+            if len > 0 {
+                let chunk = output.producer.write_chunk_uninit(len).unwrap();
+                chunk.fill_from_iter(data[..len].iter().copied());
+            }
+            let written = len / self.block_align;
+            output.dropped += frames - written;
+            fewest = fewest.min(written);
+        }
+        fewest
+    }
+
+    /// Frames that did not fit in reader `i`'s ring so far
+    pub fn dropped_frames(&self, i: usize) -> usize {
+        self.outputs[i].dropped
+    }
+
+    /// Room left in the fullest live reader's ring
+    pub fn free_frames(&self) -> usize {
+        self.outputs
+            .iter()
+            .filter(|x| !x.producer.is_abandoned())
+            .map(|x| x.producer.slots() / self.block_align)
+            .min()
+            .unwrap_or(0)
+    }
+
+    pub fn readers(&self) -> usize {
+        self.outputs.len()
+    }
+}
+
+/// Reading end of one [`broadcast`] output
+pub struct BroadcastReader {
+    consumer: Consumer<u8>,
+    block_align: usize,
+}
+
+impl BroadcastReader {
+    /// Never blocks, see [`read_frames`]
+    pub fn pull(&mut self, out: &mut [u8]) -> usize {
+        read_frames(&mut self.consumer, self.block_align, out)
+    }
+
+    pub fn available_frames(&self) -> usize {
+        self.consumer.slots() / self.block_align
+    }
+
+    /// Size of this reader's ring
+    pub fn capacity_frames(&self) -> usize {
+        self.consumer.buffer().capacity() / self.block_align
+    }
+
+    /// Drops the oldest frames so at most `max_frames` stay queued, returns how many were dropped.
+    /// Keeps a reader that stalled for a while from carrying the extra latency forever
+    pub fn trim(&mut self, max_frames: usize) -> usize {
+        let excess = self.available_frames().saturating_sub(max_frames);
+        if excess > 0 {
+            self.consumer
+                .read_chunk(excess * self.block_align)
+                .unwrap()
+                .commit_all();
+        }
+        excess
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BLOCK_ALIGN: usize = 4;
+
+    // Frames numbered from `first`, so losses and reordering show up
+    fn frames(first: u32, count: usize) -> Vec<u8> {
+        (first..first + count as u32)
+            .flat_map(|x| x.to_le_bytes())
+            .collect()
+    }
+
+    fn numbers(bytes: &[u8]) -> impl Iterator<Item = u32> + '_ {
+        bytes
+            .chunks_exact(BLOCK_ALIGN)
+            .map(|x| u32::from_le_bytes(x.try_into().unwrap()))
+    }
+
+    // An output rendering `period` frames every `period` ticks of one frame
+    struct SimulatedOutput {
+        reader: BroadcastReader,
+        period: usize,
+        buf: Vec<u8>,
+        received: Vec<u32>,
+    }
+
+    impl SimulatedOutput {
+        fn new(reader: BroadcastReader, period: usize) -> Self {
+            Self {
+                reader,
+                period,
+                buf: vec![0; period * BLOCK_ALIGN],
+                received: Vec::new(),
+            }
+        }
+
+        fn tick(&mut self, frame: usize) {
+            if frame.is_multiple_of(self.period) {
+                let len = self.reader.pull(&mut self.buf);
+                self.received.extend(numbers(&self.buf[..len]));
+            }
+        }
+    }
+
+    #[test]
+    fn outputs_at_different_periods() {
+        // Capture packets of 48 frames, outputs at 48, 128 and 480 frames, each ring sized from
+        // its own period like the pipe does
+        let periods = [48, 128, 480];
+        let sizes: Vec<_> = periods.iter().map(|x| (x + 48) * 4).collect();
+        let (mut writer, readers) = broadcast(&sizes, BLOCK_ALIGN);
+        assert_eq!(writer.readers(), 3);
+        let mut outputs: Vec<_> = readers
+            .into_iter()
+            .zip(periods)
+            .map(|(reader, period)| SimulatedOutput::new(reader, period))
+            .collect();
+        for (output, size) in outputs.iter().zip(&sizes) {
+            assert_eq!(output.reader.capacity_frames(), *size);
+        }
+
+        let total = 48000;
+        for frame in 0..total {
+            if frame % 48 == 47 {
+                let first = frame as u32 - 47;
+                assert_eq!(writer.push(&frames(first, 48)), 48);
+            }
+            for output in &mut outputs {
+                output.tick(frame);
+            }
+        }
+        for (i, output) in outputs.iter_mut().enumerate() {
+            assert_eq!(writer.dropped_frames(i), 0);
+            // Whatever is still queued completes the count
+            let queued = output.reader.available_frames();
+            assert_eq!(output.received.len() + queued, total, "output {i}");
+            assert!(queued <= output.period + 48);
+            assert!(
+                output
+                    .received
+                    .iter()
+                    .copied()
+                    .eq(0..output.received.len() as u32),
+                "output {i}"
+            );
+        }
+    }
+
+    #[test]
+    fn a_stalled_output_only_loses_its_own_frames() {
+        let (mut writer, mut readers) = broadcast(&[960, 960], BLOCK_ALIGN);
+        let mut stalled = readers.pop().unwrap();
+        let mut live = readers.pop().unwrap();
+        let mut buf = vec![0; 480 * BLOCK_ALIGN];
+        let mut received = Vec::new();
+        for i in 0..10 {
+            writer.push(&frames(i * 480, 480));
+            let len = live.pull(&mut buf);
+            received.extend(numbers(&buf[..len]));
+        }
+        assert!(received.iter().copied().eq(0..4800));
+        assert_eq!(writer.dropped_frames(0), 0);
+        // The stalled ring filled after two packets, everything after that was dropped for it
+        assert_eq!(writer.dropped_frames(1), 4800 - 960);
+        assert_eq!(writer.free_frames(), 0);
+        let len = stalled.pull(&mut vec![0; 4800 * BLOCK_ALIGN]);
+        assert_eq!(len, 960 * BLOCK_ALIGN);
+        assert_eq!(writer.free_frames(), 960);
+    }
+
+    #[test]
+    fn push_accounting() {
+        let (mut writer, mut readers) = broadcast(&[100, 30], 2);
+        // Partial frames are never written
+        assert_eq!(writer.push(&[0; 2 * 20 + 1]), 20);
+        assert_eq!(writer.dropped_frames(0), 0);
+        // Returns the fewest any reader took, the rest is counted per reader
+        assert_eq!(writer.push(&[0; 2 * 20]), 10);
+        assert_eq!(writer.dropped_frames(0), 0);
+        assert_eq!(writer.dropped_frames(1), 10);
+        assert_eq!(readers[0].available_frames(), 40);
+        assert_eq!(readers[1].available_frames(), 30);
+        assert_eq!(writer.free_frames(), 0);
+
+        // A dropped reader no longer holds the others back
+        readers.pop();
+        assert_eq!(writer.free_frames(), 60);
+        assert_eq!(writer.push(&[0; 2 * 20]), 20);
+        assert_eq!(writer.dropped_frames(1), 10);
+        drop(readers);
+        assert_eq!(writer.free_frames(), 0);
+        assert_eq!(writer.push(&[0; 4]), 2);
+    }
+
+    #[test]
+    fn trim_drops_the_oldest() {
+        let (mut writer, mut readers) = broadcast(&[100], BLOCK_ALIGN);
+        writer.push(&frames(0, 80));
+        assert_eq!(readers[0].trim(100), 0);
+        assert_eq!(readers[0].trim(30), 50);
+        assert_eq!(readers[0].available_frames(), 30);
+        let mut buf = vec![0; 100 * BLOCK_ALIGN];
+        let len = readers[0].pull(&mut buf);
+        assert!(numbers(&buf[..len]).eq(50..80));
+        assert_eq!(readers[0].trim(0), 0);
+    }
+}
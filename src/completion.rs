@@ -0,0 +1,297 @@
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Condvar, Mutex, Weak},
+    task::{Context, Poll, Waker},
+    time::{Duration, Instant},
+};
+
+use thiserror::Error;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Error)]
+pub enum WaitError {
+    #[error("timed out after {0:?}")]
+    Timeout(Duration),
+
+    #[error("cancelled")]
+    Cancelled,
+
+    #[error("completion source was dropped without completing")]
+    Abandoned,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Pending,
+    Completed,
+    Cancelled,
+    Abandoned,
+}
+
+impl State {
+    fn result(self) -> Option<Result<(), WaitError>> {
+        match self {
+            State::Pending => None,
+            State::Completed => Some(Ok(())),
+            State::Cancelled => Some(Err(WaitError::Cancelled)),
+            State::Abandoned => Some(Err(WaitError::Abandoned)),
+        }
+    }
+}
+
+struct Inner {
+    state: Mutex<(State, Option<Waker>)>,
+    cv: Condvar,
+}
+
+impl Inner {
+    // Only the first transition out of `Pending` counts, later ones lose the race
+    fn settle(&self, to: State) -> bool {
+        let waker = {
+            let mut guard = self.state.lock().unwrap();
+            if guard.0 != State::Pending {
+                return false;
+            }
+            guard.0 = to;
+            guard.1.take()
+        };
+        self.cv.notify_all();
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+        true
+    }
+}
+
+/// Waiting side of a one-shot completion. Can be waited on by blocking or awaited
+pub struct Completion(Arc<Inner>);
+
+/// Signalling side of a [`Completion`]. Dropping it before `complete` abandons the wait
+pub struct Completer(Arc<Inner>);
+
+impl Completion {
+    pub fn new() -> (Completion, Completer) {
+        let inner = Arc::new(Inner {
+            state: Mutex::new((State::Pending, None)),
+            cv: Condvar::new(),
+        });
+        (Completion(inner.clone()), Completer(inner))
+    }
+
+    pub fn with_cancel(token: &CancelToken) -> (Completion, Completer) {
+        let (completion, completer) = Self::new();
+        token.register(&completion.0);
+        (completion, completer)
+    }
+
+    pub fn wait(&self, timeout: Option<Duration>) -> Result<(), WaitError> {
+        let deadline = timeout.map(|x| Instant::now() + x);
+        let mut guard = self.0.state.lock().unwrap();
+        loop {
+            if let Some(rs) = guard.0.result() {
+                return rs;
+            }
+            guard = match (deadline, timeout) {
+                (Some(deadline), Some(timeout)) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return Err(WaitError::Timeout(timeout));
+                    }
+                    self.0.cv.wait_timeout(guard, deadline - now).unwrap().0
+                }
+                _ => self.0.cv.wait(guard).unwrap(),
+            };
+        }
+    }
+
+    pub async fn wait_async(self, timeout: Option<Duration>) -> Result<(), WaitError> {
+        match timeout {
+            Some(timeout) => tokio::time::timeout(timeout, self)
+                .await
+                .map_err(|_| WaitError::Timeout(timeout))?,
+            None => self.await,
+        }
+    }
+}
+
+impl Future for Completion {
+    type Output = Result<(), WaitError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut guard = self.0.state.lock().unwrap();
+        match guard.0.result() {
+            Some(rs) => Poll::Ready(rs),
+            None => {
+                guard.1 = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl Completer {
+    pub fn complete(&self) -> bool {
+        self.0.settle(State::Completed)
+    }
+}
+
+impl Drop for Completer {
+    fn drop(&mut self) {
+        self.0.settle(State::Abandoned);
+    }
+}
+
+/// Cancels every [`Completion`] created with it that is still pending, now or in the future
+#[derive(Clone, Default)]
+pub struct CancelToken(Arc<Mutex<CancelState>>);
+
+#[derive(Default)]
+struct CancelState {
+    cancelled: bool,
+    waiting: Vec<Weak<Inner>>,
+}
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        let mut guard = self.0.lock().unwrap();
+        guard.cancelled = true;
+        for inner in guard.waiting.drain(..) {
+            if let Some(inner) = inner.upgrade() {
+                inner.settle(State::Cancelled);
+            }
+        }
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.lock().unwrap().cancelled
+    }
+
+    fn register(&self, inner: &Arc<Inner>) {
+        let mut guard = self.0.lock().unwrap();
+        if guard.cancelled {
+            inner.settle(State::Cancelled);
+        } else {
+            guard.waiting.retain(|x| x.strong_count() > 0);
+            guard.waiting.push(Arc::downgrade(inner));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn complete_before_wait() {
+        let (completion, completer) = Completion::new();
+        assert!(completer.complete());
+        assert!(!completer.complete());
+        drop(completer);
+        assert_eq!(completion.wait(Some(Duration::ZERO)), Ok(()));
+        assert_eq!(completion.wait(None), Ok(()));
+    }
+
+    #[test]
+    fn complete_from_another_thread() {
+        let (completion, completer) = Completion::new();
+        let handle = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            completer.complete()
+        });
+        assert_eq!(completion.wait(Some(Duration::from_secs(5))), Ok(()));
+        assert!(handle.join().unwrap());
+    }
+
+    #[test]
+    fn timeout() {
+        let (completion, completer) = Completion::new();
+        let timeout = Duration::from_millis(10);
+        let start = Instant::now();
+        assert_eq!(
+            completion.wait(Some(timeout)),
+            Err(WaitError::Timeout(timeout))
+        );
+        assert!(start.elapsed() >= timeout);
+        // Timing out does not settle anything
+        assert!(completer.complete());
+        assert_eq!(completion.wait(Some(timeout)), Ok(()));
+    }
+
+    #[test]
+    fn abandoned() {
+        let (completion, completer) = Completion::new();
+        drop(completer);
+        assert_eq!(completion.wait(None), Err(WaitError::Abandoned));
+    }
+
+    #[test]
+    fn cancel_before_registration() {
+        let token = CancelToken::new();
+        token.cancel();
+        assert!(token.is_cancelled());
+        let (completion, completer) = Completion::with_cancel(&token);
+        assert!(!completer.complete());
+        assert_eq!(completion.wait(None), Err(WaitError::Cancelled));
+    }
+
+    #[test]
+    fn cancel_after_registration() {
+        let token = CancelToken::new();
+        let (first, _first) = Completion::with_cancel(&token);
+        let (second, second_completer) = Completion::with_cancel(&token);
+        assert!(second_completer.complete());
+        token.cancel();
+        assert_eq!(first.wait(None), Err(WaitError::Cancelled));
+        // Already settled before the cancel
+        assert_eq!(second.wait(None), Ok(()));
+    }
+
+    #[test]
+    fn first_settle_wins() {
+        for _ in 0..200 {
+            let token = CancelToken::new();
+            let (completion, completer) = Completion::with_cancel(&token);
+            let cancel = thread::spawn({
+                let token = token.clone();
+                move || token.cancel()
+            });
+            let complete = thread::spawn(move || completer.complete());
+            let result = completion.wait(None);
+            cancel.join().unwrap();
+            let completed = complete.join().unwrap();
+            if completed {
+                assert_eq!(result, Ok(()));
+            } else {
+                assert_eq!(result, Err(WaitError::Cancelled));
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn wait_async() {
+        let (completion, completer) = Completion::new();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            completer.complete();
+        });
+        assert_eq!(completion.wait_async(None).await, Ok(()));
+
+        let (completion, _completer) = Completion::new();
+        let timeout = Duration::from_millis(5);
+        assert_eq!(
+            completion.wait_async(Some(timeout)).await,
+            Err(WaitError::Timeout(timeout))
+        );
+
+        let token = CancelToken::new();
+        let (completion, _completer) = Completion::with_cancel(&token);
+        tokio::spawn(async move { token.cancel() });
+        assert_eq!(completion.await, Err(WaitError::Cancelled));
+    }
+}
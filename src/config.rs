@@ -0,0 +1,347 @@
+use std::time::Duration;
+
+use clap::Parser;
+use tracing::Level;
+
+use wasapi_low_latency::{
+    delay::{Delay, DelayTime},
+    device::DeviceStateMask,
+    duck::{DuckSettings, Ducker, Sidechain},
+    dynamics::{Compressor, CompressorSettings, Gate, GateSettings, Limiter, LimiterSettings},
+    engine::{InputKind, PeriodRequest, ShareMode, StreamConfig, StreamKind},
+    eq::{Band, Equalizer},
+    generator::Signal,
+    loudness::{AutoGain, AutoGainSettings},
+    mixer::MixOptions,
+    process::ProcessLoopbackMode,
+    processor::Chain,
+    roundtrip::Stimulus,
+    stereo::{PanLaw, StereoSettings, StereoTools},
+};
+
+/// Anything left out here is asked interactively (or read from `stdio.txt`)
+#[derive(Debug, Parser)]
+#[command(version, about)]
+pub struct Config {
+    /// Most detailed log level shown: `error`, `warn`, `info`, `debug` or `trace`. `RUST_LOG`
+    /// takes precedence, e.g. `RUST_LOG=wasapi_low_latency=debug`
+    #[arg(long, default_value = "info")]
+    pub log_level: Level,
+
+    /// Print every render and capture endpoint with its state, then exit
+    #[arg(long)]
+    pub list_devices: bool,
+
+    /// `device`, `process` or `loopback` (what a render endpoint plays)
+    #[arg(long)]
+    pub input: Option<InputKind>,
+
+    /// Play a test signal instead of capturing: `sine[:hz]`, `sweep[:from:to:secs]`, `white`,
+    /// `pink`, `impulse[:secs]` or `ident` (each channel in turn, with its own tone)
+    #[arg(long)]
+    pub generate: Option<Signal>,
+
+    /// Peak level of generated tones, noise has the same RMS as a tone at this level
+    #[arg(long, default_value_t = -18.0, allow_negative_numbers = true)]
+    pub generate_level_db: f64,
+
+    /// Measure the round trip from the output back to the input instead of piping, e.g. over a
+    /// loopback cable or a microphone next to the speaker: `mls` or `impulse`
+    #[arg(long)]
+    pub measure_latency: Option<Stimulus>,
+
+    /// Number of round trips to measure
+    #[arg(long, default_value_t = 5)]
+    pub measure_count: usize,
+
+    /// Level the stimulus is played at, in dBFS
+    #[arg(long, default_value_t = -12.0, allow_negative_numbers = true)]
+    pub measure_level_db: f32,
+
+    /// Endpoint states to enumerate, e.g. `active,unplugged` or `all`
+    #[arg(long, default_value = "active")]
+    pub device_states: DeviceStateMask,
+
+    /// Process capture: `include` records only the target process tree, `exclude` records
+    /// everything else
+    #[arg(long, default_value = "include")]
+    pub loopback_mode: ProcessLoopbackMode,
+
+    /// Give up on a process capture activation after this many milliseconds, 0 waits forever
+    #[arg(long, default_value_t = 5000)]
+    pub activation_timeout_ms: u64,
+
+    /// `shared` or `exclusive`. Exclusive is not available for process capture
+    #[arg(long, default_value = "shared")]
+    pub input_share_mode: ShareMode,
+
+    /// `shared` or `exclusive`
+    #[arg(long, default_value = "shared")]
+    pub output_share_mode: ShareMode,
+
+    /// Input engine period: `min`, frames (`480`) or milliseconds (`5ms`), snapped to what the
+    /// endpoint supports
+    #[arg(long, default_value = "min")]
+    pub input_period: PeriodRequest,
+
+    /// Output engine period: `min`, frames (`480`) or milliseconds (`5ms`), snapped to what the
+    /// endpoint supports
+    #[arg(long, default_value = "min")]
+    pub output_period: PeriodRequest,
+
+    /// Also look for clicks in what the outputs play and log them with the other glitches
+    #[arg(long)]
+    pub detect_jumps: bool,
+
+    /// Number of output devices to ask for. Every output gets the same capture, with its own
+    /// buffering
+    #[arg(long, default_value_t = 1)]
+    pub outputs: usize,
+
+    /// Number of inputs to ask for. More than one mixes them into the output, each with its own
+    /// gain
+    #[arg(long, default_value_t = 1)]
+    pub mix_inputs: usize,
+
+    /// Attenuation applied to the sum of mixed inputs, in dB
+    #[arg(long, default_value_t = 6.0)]
+    pub headroom_db: f32,
+
+    /// Hard clip the mix instead of bending peaks above -2.5 dBFS toward full scale
+    #[arg(long)]
+    pub no_soft_clip: bool,
+
+    /// Enable the noise gate, opening above this level in dBFS
+    #[arg(long, allow_negative_numbers = true)]
+    pub gate_threshold_db: Option<f32>,
+
+    /// Expansion ratio below the gate threshold, `inf` closes the gate completely
+    #[arg(long, default_value_t = f32::INFINITY)]
+    pub gate_ratio: f32,
+
+    /// Deepest attenuation of the gate, in dB
+    #[arg(long, default_value_t = 80.0)]
+    pub gate_range_db: f32,
+
+    #[arg(long, default_value_t = 1.0)]
+    pub gate_attack_ms: f32,
+
+    /// How long the gate stays open after the level drops
+    #[arg(long, default_value_t = 50.0)]
+    pub gate_hold_ms: f32,
+
+    #[arg(long, default_value_t = 100.0)]
+    pub gate_release_ms: f32,
+
+    /// Gate each channel on its own level instead of the loudest one
+    #[arg(long)]
+    pub gate_unlinked: bool,
+
+    /// EQ band, repeat for more: `type:freq[:gain_db][:q]` with type `peak`, `lowshelf`,
+    /// `highshelf`, `highpass`, `lowpass` or `notch`, e.g. `--eq highpass:80 --eq peak:3000:-4:2`
+    #[arg(long)]
+    pub eq: Vec<Band>,
+
+    /// With `--mix-inputs`: input number (from 1) whose level ducks every other input, e.g. the
+    /// microphone over a game
+    #[arg(long)]
+    pub duck_key: Option<usize>,
+
+    /// Key level that starts ducking, in dBFS
+    #[arg(long, default_value_t = -40.0, allow_negative_numbers = true)]
+    pub duck_threshold_db: f32,
+
+    /// How far the other inputs are turned down
+    #[arg(long, default_value_t = 15.0)]
+    pub duck_depth_db: f32,
+
+    #[arg(long, default_value_t = 20.0)]
+    pub duck_attack_ms: f32,
+
+    #[arg(long, default_value_t = 500.0)]
+    pub duck_release_ms: f32,
+
+    /// Delay the pipe, in frames (`960`) or milliseconds (`40ms`). A comma separated list sets
+    /// each channel, the last value repeats
+    #[arg(long, value_delimiter = ',')]
+    pub delay: Vec<DelayTime>,
+
+    /// Longest delay the delay line is allocated for
+    #[arg(long, default_value = "1000ms")]
+    pub max_delay: DelayTime,
+
+    /// Steer the short-term loudness toward this many LUFS, e.g. -23 (EBU R128) or -16
+    #[arg(long, allow_negative_numbers = true)]
+    pub target_lufs: Option<f64>,
+
+    /// Largest boost or cut the loudness target may apply
+    #[arg(long, default_value_t = 12.0)]
+    pub auto_gain_max_db: f64,
+
+    /// Print momentary, short-term and integrated loudness of the output every second
+    #[arg(long)]
+    pub show_loudness: bool,
+
+    /// Enable the compressor, reducing gain above this level in dBFS
+    #[arg(long, allow_negative_numbers = true)]
+    pub compressor_threshold_db: Option<f32>,
+
+    #[arg(long, default_value_t = 4.0)]
+    pub compressor_ratio: f32,
+
+    /// Soft knee width around the threshold, 0 for a hard knee
+    #[arg(long, default_value_t = 6.0)]
+    pub compressor_knee_db: f32,
+
+    #[arg(long, default_value_t = 5.0)]
+    pub compressor_attack_ms: f32,
+
+    #[arg(long, default_value_t = 100.0)]
+    pub compressor_release_ms: f32,
+
+    /// Gain added after compression
+    #[arg(long, default_value_t = 0.0, allow_negative_numbers = true)]
+    pub compressor_makeup_db: f32,
+
+    /// Compress each channel on its own level instead of the loudest one
+    #[arg(long)]
+    pub compressor_unlinked: bool,
+
+    /// Enable the true-peak limiter at the end of the chain, nothing above this level in dBTP
+    /// gets through
+    #[arg(long, allow_negative_numbers = true)]
+    pub limiter_ceiling_db: Option<f32>,
+
+    /// How far the limiter looks ahead, adds as much latency
+    #[arg(long, default_value_t = 1.5)]
+    pub limiter_lookahead_ms: f32,
+
+    #[arg(long, default_value_t = 50.0)]
+    pub limiter_release_ms: f32,
+
+    /// Pan or balance position, -1 (left) to 1 (right)
+    #[arg(long, default_value_t = 0.0, allow_negative_numbers = true)]
+    pub pan: f32,
+
+    /// `balance`, `-3db` (constant power), `-4.5db` or `-6db` (linear)
+    #[arg(long, default_value = "balance")]
+    pub pan_law: PanLaw,
+
+    /// Stereo width: 0 is mono, 1 unchanged, 2 twice the side signal
+    #[arg(long, default_value_t = 1.0)]
+    pub width: f32,
+
+    /// Swap left and right
+    #[arg(long)]
+    pub swap_channels: bool,
+
+    /// Fold down to mono, to check how the source holds up on a mono speaker
+    #[arg(long)]
+    pub mono: bool,
+}
+
+impl Config {
+    pub fn activation_timeout(&self) -> Option<Duration> {
+        (self.activation_timeout_ms > 0).then(|| Duration::from_millis(self.activation_timeout_ms))
+    }
+
+    pub fn input_stream(&self, input: InputKind) -> StreamConfig {
+        StreamConfig::new(input.stream_kind())
+            .share_mode(self.input_share_mode)
+            .period(self.input_period)
+    }
+
+    pub fn mix_options(&self) -> MixOptions {
+        MixOptions {
+            headroom_db: self.headroom_db,
+            soft_clip: !self.no_soft_clip,
+        }
+    }
+
+    pub fn stereo(&self) -> StereoSettings {
+        StereoSettings {
+            pan: self.pan,
+            pan_law: self.pan_law,
+            width: self.width,
+            swap: self.swap_channels,
+            mono: self.mono,
+        }
+    }
+
+    /// Processing stage for the pipe, empty when every setting is neutral
+    pub fn processors(&self) -> Chain {
+        let mut chain = Chain::new();
+        if let Some(threshold_db) = self.gate_threshold_db {
+            chain.push(Gate::new(GateSettings {
+                threshold_db,
+                ratio: self.gate_ratio,
+                range_db: self.gate_range_db,
+                attack_ms: self.gate_attack_ms,
+                hold_ms: self.gate_hold_ms,
+                release_ms: self.gate_release_ms,
+                linked: !self.gate_unlinked,
+            }));
+        }
+        if !self.eq.is_empty() {
+            chain.push(Equalizer::new(self.eq.clone()));
+        }
+        let stereo = self.stereo();
+        if !stereo.is_identity() {
+            chain.push(StereoTools::new(stereo));
+        }
+        if !self.delay.is_empty() {
+            chain.push(Delay::new(self.delay.clone(), self.max_delay));
+        }
+        if let Some(target_lufs) = self.target_lufs {
+            chain.push(AutoGain::new(AutoGainSettings {
+                target_lufs,
+                max_gain_db: self.auto_gain_max_db,
+                ..Default::default()
+            }));
+        }
+        if let Some(threshold_db) = self.compressor_threshold_db {
+            chain.push(Compressor::new(CompressorSettings {
+                threshold_db,
+                ratio: self.compressor_ratio,
+                knee_db: self.compressor_knee_db,
+                attack_ms: self.compressor_attack_ms,
+                release_ms: self.compressor_release_ms,
+                makeup_db: self.compressor_makeup_db,
+                linked: !self.compressor_unlinked,
+            }));
+        }
+        if let Some(ceiling_db) = self.limiter_ceiling_db {
+            chain.push(Limiter::new(LimiterSettings {
+                ceiling_db,
+                lookahead_ms: self.limiter_lookahead_ms,
+                release_ms: self.limiter_release_ms,
+            }));
+        }
+        chain
+    }
+
+    /// Processing of mixer input `i` (from 0): the ducking key, a ducker, or nothing
+    pub fn input_processors(&self, i: usize, sidechain: &Sidechain) -> Chain {
+        let mut chain = Chain::new();
+        match self.duck_key {
+            Some(key) if key == i + 1 => chain.push(sidechain.key()),
+            Some(_) => chain.push(Ducker::new(
+                sidechain.clone(),
+                DuckSettings {
+                    threshold_db: self.duck_threshold_db,
+                    depth_db: self.duck_depth_db,
+                    attack_ms: self.duck_attack_ms,
+                    release_ms: self.duck_release_ms,
+                },
+            )),
+            None => {}
+        }
+        chain
+    }
+
+    pub fn output_stream(&self) -> StreamConfig {
+        StreamConfig::new(StreamKind::Render)
+            .share_mode(self.output_share_mode)
+            .period(self.output_period)
+    }
+}
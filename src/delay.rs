@@ -0,0 +1,471 @@
+use std::{
+    fmt::Display,
+    str::FromStr,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+
+use thiserror::Error;
+
+use crate::processor::Processor;
+
+/// A delay, either in frames or in milliseconds of the pipe's rate
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DelayTime {
+    Frames(u32),
+    Millis(f64),
+}
+
+impl Default for DelayTime {
+    fn default() -> Self {
+        DelayTime::Frames(0)
+    }
+}
+
+impl DelayTime {
+    pub fn frames(self, sample_rate: u32) -> usize {
+        match self {
+            DelayTime::Frames(frames) => frames as usize,
+            DelayTime::Millis(ms) => (ms * sample_rate as f64 / 1000f64).round() as usize,
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+#[error("invalid delay, expected frames (`960`) or milliseconds (`20ms`)")]
+pub struct InvalidDelay;
+
+impl FromStr for DelayTime {
+    type Err = InvalidDelay;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim().to_ascii_lowercase();
+        if let Some(ms) = s.strip_suffix("ms") {
+            match ms.trim().parse::<f64>() {
+                Ok(ms) if ms >= 0f64 && ms.is_finite() => Ok(DelayTime::Millis(ms)),
+                _ => Err(InvalidDelay),
+            }
+        } else {
+            s.parse().map(DelayTime::Frames).map_err(|_| InvalidDelay)
+        }
+    }
+}
+
+impl Display for DelayTime {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DelayTime::Frames(frames) => write!(f, "{frames}"),
+            DelayTime::Millis(ms) => write!(f, "{ms}ms"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct DelayChannel {
+    // Frames behind the write position, faded from `current` to `next`
+    current: usize,
+    next: usize,
+    fade: usize,
+    // Set while a fade runs, started once it is done
+    pending: Option<usize>,
+}
+
+// Millis are stored as their bits with the top bit set, a non-negative f64 never has it
+const MILLIS_BIT: u64 = 1 << 63;
+
+fn encode(time: DelayTime) -> u64 {
+    match time {
+        DelayTime::Frames(frames) => frames as u64,
+        DelayTime::Millis(ms) => ms.max(0f64).to_bits() | MILLIS_BIT,
+    }
+}
+
+fn decode(bits: u64) -> DelayTime {
+    if bits & MILLIS_BIT == 0 {
+        DelayTime::Frames(bits as u32)
+    } else {
+        DelayTime::Millis(f64::from_bits(bits & !MILLIS_BIT))
+    }
+}
+
+#[derive(Debug)]
+struct DelayTargets {
+    slots: Box<[AtomicU64]>,
+    // Bumped after every change, so `process` only looks at the slots when one happened
+    version: AtomicU64,
+}
+
+/// Changes a [`Delay`]'s channels from another thread while it streams, lock-free. The delay
+/// picks a change up at its next block and crossfades to it
+#[derive(Debug, Clone)]
+pub struct DelayControl {
+    targets: Arc<DelayTargets>,
+}
+
+impl DelayControl {
+    // Covers every speaker a channel mask can name, channels past it follow the last slot
+    const SLOTS: usize = 32;
+
+    fn new(delays: &[DelayTime]) -> Self {
+        let slots = (0..Self::SLOTS.max(delays.len()))
+            .map(|i| {
+                let time = delays.get(i).or(delays.last()).copied();
+                AtomicU64::new(encode(time.unwrap_or_default()))
+            })
+            .collect();
+        Self {
+            targets: Arc::new(DelayTargets {
+                slots,
+                version: AtomicU64::new(0),
+            }),
+        }
+    }
+
+    fn slot(&self, channel: usize) -> &AtomicU64 {
+        let slots = &self.targets.slots;
+        &slots[channel.min(slots.len() - 1)]
+    }
+
+    /// Requested delay of `channel`, before it is capped to the maximum
+    pub fn delay(&self, channel: usize) -> DelayTime {
+        decode(self.slot(channel).load(Ordering::Relaxed))
+    }
+
+    /// Sets `channel` to `time`. Channels past the last slot share it, as they share the last
+    /// delay given to [`Delay::new`]
+    pub fn set_delay(&self, channel: usize, time: DelayTime) {
+        self.slot(channel).store(encode(time), Ordering::Relaxed);
+        self.targets.version.fetch_add(1, Ordering::Release);
+    }
+
+    pub fn set_all(&self, time: DelayTime) {
+        for slot in &self.targets.slots {
+            slot.store(encode(time), Ordering::Relaxed);
+        }
+        self.targets.version.fetch_add(1, Ordering::Release);
+    }
+
+    fn version(&self) -> u64 {
+        self.targets.version.load(Ordering::Acquire)
+    }
+}
+
+/// Per-channel delay line over one preallocated circular buffer. Changing a delay crossfades from
+/// the old read position to the new one, so alignment can be adjusted while streaming, from the
+/// processing thread or through a [`DelayControl`]
+pub struct Delay {
+    control: DelayControl,
+    max: DelayTime,
+    channels: usize,
+    sample_rate: u32,
+    buffer: Vec<f32>,
+    // Frames in `buffer`, one more than the longest delay
+    len: usize,
+    pos: usize,
+    fade_frames: usize,
+    state: Vec<DelayChannel>,
+    // Control version and slot bits last applied to `state`
+    version: u64,
+    applied: Vec<u64>,
+}
+
+impl Delay {
+    const FADE_MS: f64 = 10f64;
+
+    /// Channel `i` gets `delays[i]`, channels past the end get the last one. `max` is the longest
+    /// delay `set_delay` may ask for later, the buffer is sized for it
+    pub fn new(delays: Vec<DelayTime>, max: DelayTime) -> Self {
+        Self {
+            control: DelayControl::new(&delays),
+            max,
+            channels: 0,
+            sample_rate: 0,
+            buffer: Vec::new(),
+            len: 1,
+            pos: 0,
+            fade_frames: 1,
+            state: Vec::new(),
+            version: 0,
+            applied: Vec::new(),
+        }
+    }
+
+    /// Handle to change the delays from another thread once this is moved into a pipe
+    pub fn control(&self) -> DelayControl {
+        self.control.clone()
+    }
+
+    fn clamp(&self, time: DelayTime) -> usize {
+        time.frames(self.sample_rate).min(self.len - 1)
+    }
+
+    /// Delay of `channel` once any fade is done, in frames
+    pub fn delay_frames(&self, channel: usize) -> usize {
+        self.state
+            .get(channel)
+            .map_or(0, |x| x.pending.unwrap_or(x.next))
+    }
+
+    /// Crossfades `channel` to `time`, capped at the maximum given to `new`. Does not allocate
+    pub fn set_delay(&mut self, channel: usize, time: DelayTime) {
+        self.control.set_delay(channel, time);
+        self.apply();
+    }
+
+    pub fn set_all(&mut self, time: DelayTime) {
+        self.control.set_all(time);
+        self.apply();
+    }
+
+    // Starts a crossfade on every channel whose slot changed since it was last looked at
+    fn apply(&mut self) {
+        self.version = self.control.version();
+        for channel in 0..self.channels {
+            let bits = self.control.slot(channel).load(Ordering::Relaxed);
+            if bits == self.applied[channel] {
+                continue;
+            }
+            self.applied[channel] = bits;
+            let frames = self.clamp(decode(bits));
+            let state = &mut self.state[channel];
+            if state.current == state.next {
+                state.next = frames;
+                state.fade = 0;
+            } else {
+                state.pending = Some(frames);
+            }
+        }
+    }
+}
+
+impl Processor for Delay {
+    fn prepare(&mut self, channels: usize, sample_rate: u32) {
+        self.channels = channels;
+        self.sample_rate = sample_rate;
+        self.len = self.max.frames(sample_rate) + 1;
+        self.buffer = vec![0f32; self.len * channels];
+        self.fade_frames = ((Self::FADE_MS * sample_rate as f64 / 1000f64) as usize).max(1);
+        self.version = self.control.version();
+        self.applied = (0..channels)
+            .map(|channel| self.control.slot(channel).load(Ordering::Relaxed))
+            .collect();
+        self.state = self
+            .applied
+            .iter()
+            .map(|bits| {
+                let frames = self.clamp(decode(*bits));
+                DelayChannel {
+                    current: frames,
+                    next: frames,
+                    ..Default::default()
+                }
+            })
+            .collect();
+        self.pos = 0;
+    }
+
+    fn process(&mut self, buf: &mut [f32]) {
+        if self.channels == 0 {
+            return;
+        }
+        if self.control.version() != self.version {
+            self.apply();
+        }
+        let ch = self.channels;
+        let len = self.len;
+        for frame in buf.chunks_exact_mut(ch) {
+            let base = self.pos * ch;
+            self.buffer[base..base + ch].copy_from_slice(frame);
+            for (c, (x, state)) in frame.iter_mut().zip(&mut self.state).enumerate() {
+                let read = |delay: usize| self.buffer[(self.pos + len - delay) % len * ch + c];
+                if state.current == state.next {
+                    *x = read(state.current);
+                    continue;
+                }
+                let t = state.fade as f32 / self.fade_frames as f32;
+                *x = read(state.current) * (1f32 - t) + read(state.next) * t;
+                state.fade += 1;
+                if state.fade >= self.fade_frames {
+                    state.current = state.next;
+                    state.fade = 0;
+                    if let Some(pending) = state.pending.take() {
+                        state.next = pending;
+                    }
+                }
+            }
+            self.pos = (self.pos + 1) % len;
+        }
+    }
+
+    /// The longest channel delay
+    fn latency(&self) -> usize {
+        (0..self.channels)
+            .map(|x| self.delay_frames(x))
+            .max()
+            .unwrap_or(0)
+    }
+
+    fn reset(&mut self) {
+        self.buffer.fill(0f32);
+        for state in &mut self.state {
+            state.current = state.pending.take().unwrap_or(state.next);
+            state.next = state.current;
+            state.fade = 0;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+    use crate::{
+        format::FormatSpec,
+        processor::{Chain, Pipeline},
+    };
+
+    const FS: u32 = 48000;
+
+    fn sine(n: usize) -> f32 {
+        (2f32 * std::f32::consts::PI * 440f32 * n as f32 / FS as f32).sin()
+    }
+
+    // Frame at which the impulse sent on frame 0 of every channel comes out of `channel`
+    fn impulse_at(delay: &mut Delay, channels: usize, channel: usize) -> usize {
+        let mut buf = vec![0f32; 1000 * channels];
+        buf[..channels].fill(1f32);
+        // Odd blocks, so the read position wraps mid-block
+        for block in buf.chunks_mut(7 * channels) {
+            delay.process(block);
+        }
+        (0..1000)
+            .find(|i| buf[i * channels + channel] == 1f32)
+            .unwrap()
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!("960".parse::<DelayTime>().unwrap(), DelayTime::Frames(960));
+        assert_eq!(
+            "40ms".parse::<DelayTime>().unwrap(),
+            DelayTime::Millis(40f64)
+        );
+        assert_eq!(
+            " 2.5MS".parse::<DelayTime>().unwrap(),
+            DelayTime::Millis(2.5)
+        );
+        assert!("-1ms".parse::<DelayTime>().is_err());
+        assert!("x".parse::<DelayTime>().is_err());
+        assert_eq!(DelayTime::Millis(40f64).to_string(), "40ms");
+        assert_eq!(DelayTime::Millis(40f64).frames(44100), 1764);
+    }
+
+    #[test]
+    fn encoding_round_trips() {
+        for time in [
+            DelayTime::Frames(0),
+            DelayTime::Frames(u32::MAX),
+            DelayTime::Millis(0f64),
+            DelayTime::Millis(57.3),
+        ] {
+            assert_eq!(decode(encode(time)), time);
+        }
+    }
+
+    #[test]
+    fn per_channel_alignment() {
+        let mut delay = Delay::new(
+            vec![DelayTime::Frames(3), DelayTime::Millis(0.125)],
+            DelayTime::Millis(100f64),
+        );
+        delay.prepare(3, FS);
+        // The third channel repeats the last delay
+        let at: Vec<usize> = (0..3)
+            .map(|c| {
+                delay.reset();
+                impulse_at(&mut delay, 3, c)
+            })
+            .collect();
+        assert_eq!(at, [3, 6, 6]);
+        assert_eq!(delay.latency(), 6);
+
+        let pipeline = Pipeline::new(
+            Chain::new().with(Delay::new(
+                vec![DelayTime::Millis(20f64)],
+                DelayTime::Millis(50f64),
+            )),
+            FormatSpec::float(2, FS),
+            480,
+        );
+        assert_eq!(pipeline.latency(), 960);
+    }
+
+    #[test]
+    fn changes_crossfade_without_clicks() {
+        let mut delay = Delay::new(vec![DelayTime::Millis(5f64)], DelayTime::Millis(200f64));
+        delay.prepare(1, FS);
+        let mut out = Vec::new();
+        for block in 0..200 {
+            match block {
+                50 => delay.set_delay(0, DelayTime::Millis(57.3)),
+                // Waits for the running fade
+                51 => delay.set_delay(0, DelayTime::Frames(10)),
+                // Capped to the maximum
+                120 => delay.set_all(DelayTime::Millis(300f64)),
+                _ => {}
+            }
+            let mut buf: Vec<f32> = (0..480).map(|i| sine(block * 480 + i)).collect();
+            delay.process(&mut buf);
+            out.extend(buf);
+        }
+        // A 440 Hz sine moves at most 0.0576 per sample, a click would be far more
+        let step = out[480..]
+            .windows(2)
+            .map(|w| (w[1] - w[0]).abs())
+            .fold(0f32, f32::max);
+        assert!(step < 0.07, "{step}");
+        // Settled on the queued 10 frames before the cap
+        for (i, x) in out.iter().enumerate().take(111 * 480).skip(110 * 480) {
+            assert!((x - sine(i - 10)).abs() < 1e-5);
+        }
+        assert_eq!(delay.delay_frames(0), 9600);
+        let end = out.len() - 1;
+        assert!((out[end] - sine(end - 9600)).abs() < 1e-5);
+    }
+
+    #[test]
+    fn control_from_another_thread() {
+        let mut delay = Delay::new(vec![DelayTime::Frames(0)], DelayTime::Millis(10f64));
+        delay.prepare(2, FS);
+        let control = delay.control();
+        thread::spawn(move || {
+            control.set_delay(1, DelayTime::Frames(40));
+            assert_eq!(control.delay(1), DelayTime::Frames(40));
+        })
+        .join()
+        .unwrap();
+        // Nothing moves until the next block, the first one fades in the change
+        assert_eq!(delay.delay_frames(1), 0);
+        let mut buf = vec![0f32; 2 * 480];
+        delay.process(&mut buf);
+        assert_eq!(delay.delay_frames(0), 0);
+        assert_eq!(delay.delay_frames(1), 40);
+        assert_eq!(delay.latency(), 40);
+        assert_eq!(impulse_at(&mut delay, 2, 1), 40);
+
+        // Past the last slot and past the channels: nothing to grow, nothing changes
+        let control = delay.control();
+        control.set_delay(100, DelayTime::Frames(5));
+        delay.set_delay(100, DelayTime::Frames(5));
+        delay.process(&mut buf);
+        assert_eq!((delay.delay_frames(0), delay.delay_frames(1)), (0, 40));
+
+        control.set_all(DelayTime::Millis(20f64));
+        delay.process(&mut buf);
+        // Capped to 10 ms
+        assert_eq!((delay.delay_frames(0), delay.delay_frames(1)), (480, 480));
+    }
+}
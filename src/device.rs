@@ -0,0 +1,405 @@
+use std::{fmt::Display, str::FromStr};
+
+use regex::Regex;
+use thiserror::Error;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Flow {
+    Render,
+    Capture,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Console,
+    Multimedia,
+    Communications,
+}
+
+impl Role {
+    pub const ALL: [Role; 3] = [Role::Console, Role::Multimedia, Role::Communications];
+}
+
+/// Same bit values as `DEVICE_STATE_*`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceState {
+    Active = 0x1,
+    Disabled = 0x2,
+    NotPresent = 0x4,
+    Unplugged = 0x8,
+}
+
+impl DeviceState {
+    pub const ALL: [DeviceState; 4] = [
+        DeviceState::Active,
+        DeviceState::Disabled,
+        DeviceState::NotPresent,
+        DeviceState::Unplugged,
+    ];
+
+    pub fn from_bits(bits: u32) -> Option<Self> {
+        Self::ALL.into_iter().find(|x| *x as u32 == bits)
+    }
+}
+
+impl Display for DeviceState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            DeviceState::Active => "active",
+            DeviceState::Disabled => "disabled",
+            DeviceState::NotPresent => "not present",
+            DeviceState::Unplugged => "unplugged",
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeviceStateMask(pub u32);
+
+impl DeviceStateMask {
+    pub const ACTIVE: Self = Self(DeviceState::Active as u32);
+    pub const ALL: Self = Self(0xF);
+
+    pub fn contains(&self, state: DeviceState) -> bool {
+        self.0 & state as u32 != 0
+    }
+}
+
+impl Default for DeviceStateMask {
+    fn default() -> Self {
+        Self::ACTIVE
+    }
+}
+
+/// Comma separated states, e.g. `active,unplugged`, or `all`
+impl FromStr for DeviceStateMask {
+    type Err = DeviceSelectError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split(',')
+            .map(|x| x.trim().to_ascii_lowercase())
+            .try_fold(Self(0), |mask, x| {
+                let bits = match x.as_str() {
+                    "all" => Self::ALL.0,
+                    "active" => DeviceState::Active as u32,
+                    "disabled" => DeviceState::Disabled as u32,
+                    "notpresent" | "not_present" | "not-present" => DeviceState::NotPresent as u32,
+                    "unplugged" => DeviceState::Unplugged as u32,
+                    _ => return Err(DeviceSelectError::InvalidState(x)),
+                };
+                Ok(Self(mask.0 | bits))
+            })
+    }
+}
+
+/// Plain description of an endpoint, decoupled from `IMMDevice` so selection can be done on any platform
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeviceInfo {
+    pub id: String,
+    pub name: String,
+    pub flow: Flow,
+    pub state: DeviceState,
+    pub default_roles: Vec<Role>,
+}
+
+impl Display for DeviceInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} ({})", self.name, self.id)?;
+        if self.state != DeviceState::Active {
+            write!(f, " [{}]", self.state)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum DeviceSelector {
+    Index(usize),
+    Id(String),
+    Name(String),
+    Regex(Regex),
+    Default { flow: Option<Flow>, role: Role },
+}
+
+#[derive(Debug, Error)]
+pub enum DeviceSelectError {
+    #[error("invalid device regex: {0}")]
+    InvalidRegex(#[from] regex::Error),
+
+    #[error("invalid device role: `{0}`")]
+    InvalidRole(String),
+
+    #[error("invalid device state: `{0}`")]
+    InvalidState(String),
+
+    #[error("no device matches `{0}`")]
+    NotFound(String),
+
+    #[error("`{selector}` matches multiple devices:{}", candidates.iter().map(|x| format!("\n  {x}")).collect::<String>())]
+    Ambiguous {
+        selector: String,
+        candidates: Vec<String>,
+    },
+}
+
+impl Display for DeviceSelector {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DeviceSelector::Index(i) => write!(f, "{i}"),
+            DeviceSelector::Id(id) => write!(f, "id:{id}"),
+            DeviceSelector::Name(name) => write!(f, "name:{name}"),
+            DeviceSelector::Regex(re) => write!(f, "re:{re}"),
+            DeviceSelector::Default { flow, role } => {
+                write!(f, "default")?;
+                match flow {
+                    Some(Flow::Render) => write!(f, " render")?,
+                    Some(Flow::Capture) => write!(f, " capture")?,
+                    None => {}
+                }
+                match role {
+                    Role::Console => Ok(()),
+                    Role::Multimedia => write!(f, " multimedia"),
+                    Role::Communications => write!(f, " communications"),
+                }
+            }
+        }
+    }
+}
+
+/// Accepted forms:
+/// - `3`: index in the enumeration order
+/// - `id:{0.0.1.00000000}...`: exact endpoint id
+/// - `re:^Speakers`: regex on the friendly name
+/// - `default [render|capture] [console|multimedia|communications]`: default endpoint for a role
+/// - `name:Speakers` or anything else: case-insensitive substring of the friendly name
+impl FromStr for DeviceSelector {
+    type Err = DeviceSelectError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if let Ok(i) = s.parse() {
+            return Ok(DeviceSelector::Index(i));
+        }
+        if let Some(id) = s.strip_prefix("id:") {
+            return Ok(DeviceSelector::Id(id.trim().into()));
+        }
+        if let Some(re) = s.strip_prefix("re:") {
+            return Ok(DeviceSelector::Regex(Regex::new(re.trim())?));
+        }
+        if let Some(name) = s.strip_prefix("name:") {
+            return Ok(DeviceSelector::Name(name.trim().into()));
+        }
+
+        let mut words = s.split_whitespace();
+        if words
+            .next()
+            .is_some_and(|x| x.eq_ignore_ascii_case("default"))
+        {
+            let mut flow = None;
+            let mut role = Role::Console;
+            for word in words {
+                match word.to_ascii_lowercase().as_str() {
+                    "render" | "output" => flow = Some(Flow::Render),
+                    "capture" | "input" => flow = Some(Flow::Capture),
+                    "console" => role = Role::Console,
+                    "multimedia" => role = Role::Multimedia,
+                    "communications" | "comms" => role = Role::Communications,
+                    _ => return Err(DeviceSelectError::InvalidRole(s.into())),
+                }
+            }
+            return Ok(DeviceSelector::Default { flow, role });
+        }
+
+        Ok(DeviceSelector::Name(s.into()))
+    }
+}
+
+impl DeviceSelector {
+    pub fn matches(&self, dev: &DeviceInfo) -> bool {
+        match self {
+            DeviceSelector::Index(_) => false,
+            DeviceSelector::Id(id) => dev.id == *id,
+            DeviceSelector::Name(name) => dev.name.to_lowercase().contains(&name.to_lowercase()),
+            DeviceSelector::Regex(re) => re.is_match(&dev.name),
+            DeviceSelector::Default { flow, role } => {
+                flow.is_none_or(|x| x == dev.flow) && dev.default_roles.contains(role)
+            }
+        }
+    }
+
+    /// Pick exactly one device out of `devs`, listing the candidates if the selector is ambiguous
+    pub fn select<'a>(&self, devs: &'a [DeviceInfo]) -> Result<&'a DeviceInfo, DeviceSelectError> {
+        if let DeviceSelector::Index(i) = self {
+            return devs
+                .get(*i)
+                .ok_or_else(|| DeviceSelectError::NotFound(self.to_string()));
+        }
+
+        let found: Vec<_> = devs.iter().filter(|x| self.matches(x)).collect();
+        match found[..] {
+            [] => Err(DeviceSelectError::NotFound(self.to_string())),
+            [dev] => Ok(dev),
+            _ => {
+                // "Speakers" should still pick "Speakers" over "Speakers (2)"
+                if let DeviceSelector::Name(name) = self {
+                    let exact: Vec<_> = found
+                        .iter()
+                        .filter(|x| x.name.eq_ignore_ascii_case(name))
+                        .collect();
+                    if let [dev] = exact[..] {
+                        return Ok(dev);
+                    }
+                }
+
+                Err(DeviceSelectError::Ambiguous {
+                    selector: self.to_string(),
+                    candidates: found.iter().map(|x| x.to_string()).collect(),
+                })
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn device(id: &str, name: &str, flow: Flow, default_roles: Vec<Role>) -> DeviceInfo {
+        DeviceInfo {
+            id: id.into(),
+            name: name.into(),
+            flow,
+            state: DeviceState::Active,
+            default_roles,
+        }
+    }
+
+    fn devices() -> Vec<DeviceInfo> {
+        vec![
+            device(
+                "a",
+                "Speakers (Realtek)",
+                Flow::Render,
+                vec![Role::Console, Role::Multimedia],
+            ),
+            device("b", "Speakers", Flow::Render, vec![Role::Communications]),
+            device("c", "Headphones", Flow::Render, vec![]),
+            device("d", "Microphone", Flow::Capture, Role::ALL.to_vec()),
+        ]
+    }
+
+    fn select(selector: &str) -> Result<String, DeviceSelectError> {
+        let devs = devices();
+        let selector: DeviceSelector = selector.parse()?;
+        selector.select(&devs).map(|x| x.id.clone())
+    }
+
+    #[test]
+    fn parse() {
+        assert!(matches!("3".parse(), Ok(DeviceSelector::Index(3))));
+        assert!(matches!("id: x ".parse(), Ok(DeviceSelector::Id(x)) if x == "x"));
+        assert!(matches!("name:Speakers".parse(), Ok(DeviceSelector::Name(x)) if x == "Speakers"));
+        assert!(matches!("Speakers".parse(), Ok(DeviceSelector::Name(x)) if x == "Speakers"));
+        assert!(matches!("re:^Head".parse(), Ok(DeviceSelector::Regex(_))));
+        assert!(matches!(
+            "re:(".parse::<DeviceSelector>(),
+            Err(DeviceSelectError::InvalidRegex(_))
+        ));
+        assert!(matches!(
+            "default foo".parse::<DeviceSelector>(),
+            Err(DeviceSelectError::InvalidRole(_))
+        ));
+    }
+
+    #[test]
+    fn default_roles() {
+        assert!(matches!(
+            "default".parse(),
+            Ok(DeviceSelector::Default {
+                flow: None,
+                role: Role::Console
+            })
+        ));
+        assert!(matches!(
+            "Default Input Comms".parse(),
+            Ok(DeviceSelector::Default {
+                flow: Some(Flow::Capture),
+                role: Role::Communications
+            })
+        ));
+        assert_eq!(
+            "default render communications"
+                .parse::<DeviceSelector>()
+                .unwrap()
+                .to_string(),
+            "default render communications"
+        );
+
+        assert_eq!(select("default render").unwrap(), "a");
+        assert_eq!(select("default render multimedia").unwrap(), "a");
+        assert_eq!(select("default render comms").unwrap(), "b");
+        assert_eq!(select("default capture").unwrap(), "d");
+        // Without a flow the role matches on both sides
+        assert!(matches!(
+            select("default"),
+            Err(DeviceSelectError::Ambiguous { .. })
+        ));
+    }
+
+    #[test]
+    fn exact_name_wins_a_tie() {
+        assert_eq!(select("speakers").unwrap(), "b");
+        assert_eq!(select("name:SPEAKERS (realtek)").unwrap(), "a");
+    }
+
+    #[test]
+    fn ambiguity_lists_candidates() {
+        let err = select("speak").unwrap_err();
+        let DeviceSelectError::Ambiguous {
+            selector,
+            candidates,
+        } = &err
+        else {
+            panic!("{err}");
+        };
+        assert_eq!(selector, "name:speak");
+        assert_eq!(
+            candidates,
+            &["Speakers (Realtek) (a)", "Speakers (b)"].map(String::from)
+        );
+        assert_eq!(
+            err.to_string(),
+            "`name:speak` matches multiple devices:\n  Speakers (Realtek) (a)\n  Speakers (b)"
+        );
+        // A regex has no exact name to fall back on
+        assert!(matches!(
+            select("re:^Speakers"),
+            Err(DeviceSelectError::Ambiguous { .. })
+        ));
+    }
+
+    #[test]
+    fn other_selectors() {
+        assert_eq!(select("re:^Head").unwrap(), "c");
+        assert_eq!(select("id:c").unwrap(), "c");
+        assert_eq!(select("2").unwrap(), "c");
+        assert!(matches!(select("4"), Err(DeviceSelectError::NotFound(x)) if x == "4"));
+        assert!(matches!(
+            select("hdmi"),
+            Err(DeviceSelectError::NotFound(_))
+        ));
+    }
+
+    #[test]
+    fn state_mask() {
+        let mask: DeviceStateMask = "Active, unplugged".parse().unwrap();
+        assert_eq!(mask.0, 0x9);
+        assert!(mask.contains(DeviceState::Unplugged));
+        assert!(!mask.contains(DeviceState::Disabled));
+        assert_eq!(
+            "all".parse::<DeviceStateMask>().unwrap(),
+            DeviceStateMask::ALL
+        );
+        assert!("foo".parse::<DeviceStateMask>().is_err());
+    }
+}
@@ -0,0 +1,283 @@
+use std::sync::{
+    Arc,
+    atomic::{AtomicU32, Ordering},
+};
+
+use crate::{dynamics::time_coeff, processor::Processor};
+
+/// Level of a key pipe, shared lock-free with the pipes it ducks
+#[derive(Debug, Clone, Default)]
+pub struct Sidechain {
+    level: Arc<AtomicU32>,
+}
+
+impl Sidechain {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Peak envelope of the key pipe, linear. Updated once per processed block
+    pub fn level(&self) -> f32 {
+        f32::from_bits(self.level.load(Ordering::Relaxed))
+    }
+
+    fn set_level(&self, level: f32) {
+        self.level.store(level.to_bits(), Ordering::Relaxed);
+    }
+
+    /// Goes in the key pipe's chain
+    pub fn key(&self) -> SidechainKey {
+        SidechainKey {
+            sidechain: self.clone(),
+            channels: 0,
+            envelope: 0f32,
+            decay: 0f32,
+        }
+    }
+}
+
+/// Publishes its pipe's level to a [`Sidechain`], leaving the audio untouched
+pub struct SidechainKey {
+    sidechain: Sidechain,
+    channels: usize,
+    envelope: f32,
+    decay: f32,
+}
+
+impl SidechainKey {
+    // Fast enough to follow speech, slow enough not to flicker between syllables
+    const DETECTOR_MS: f32 = 10f32;
+}
+
+impl Processor for SidechainKey {
+    fn prepare(&mut self, channels: usize, sample_rate: u32) {
+        self.channels = channels;
+        self.decay = 1f32 - time_coeff(Self::DETECTOR_MS, sample_rate);
+    }
+
+    fn process(&mut self, buf: &mut [f32]) {
+        if self.channels == 0 {
+            return;
+        }
+        for frame in buf.chunks_exact(self.channels) {
+            let peak = frame.iter().fold(0f32, |a, x| a.max(x.abs()));
+            self.envelope = if peak > self.envelope {
+                peak
+            } else {
+                self.envelope * self.decay
+            };
+        }
+        self.sidechain.set_level(self.envelope);
+    }
+
+    fn reset(&mut self) {
+        self.envelope = 0f32;
+        self.sidechain.set_level(0f32);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DuckSettings {
+    /// Key level that starts ducking, in dBFS
+    pub threshold_db: f32,
+    /// Attenuation while the key is above the threshold, in dB
+    pub depth_db: f32,
+    pub attack_ms: f32,
+    pub release_ms: f32,
+}
+
+impl Default for DuckSettings {
+    fn default() -> Self {
+        Self {
+            threshold_db: -40f32,
+            depth_db: 15f32,
+            attack_ms: 20f32,
+            release_ms: 500f32,
+        }
+    }
+}
+
+/// Turns its pipe down by `depth_db` while another pipe's [`Sidechain`] is above the threshold
+pub struct Ducker {
+    settings: DuckSettings,
+    sidechain: Sidechain,
+    channels: usize,
+    sample_rate: u32,
+    threshold: f32,
+    depth: f32,
+    attack: f32,
+    release: f32,
+    gain: f32,
+}
+
+impl Ducker {
+    pub fn new(sidechain: Sidechain, settings: DuckSettings) -> Self {
+        Self {
+            settings,
+            sidechain,
+            channels: 0,
+            sample_rate: 0,
+            threshold: 0f32,
+            depth: 1f32,
+            attack: 1f32,
+            release: 1f32,
+            gain: 1f32,
+        }
+    }
+
+    pub fn settings(&self) -> DuckSettings {
+        self.settings
+    }
+
+    pub fn set(&mut self, settings: DuckSettings) {
+        self.settings = settings;
+        self.threshold = 10f32.powf(settings.threshold_db / 20f32);
+        self.depth = 10f32.powf(-settings.depth_db.abs() / 20f32);
+        if self.sample_rate > 0 {
+            self.attack = time_coeff(settings.attack_ms, self.sample_rate);
+            self.release = time_coeff(settings.release_ms, self.sample_rate);
+        }
+    }
+
+    /// Gain applied to the last frame, linear
+    pub fn gain(&self) -> f32 {
+        self.gain
+    }
+}
+
+impl Processor for Ducker {
+    fn prepare(&mut self, channels: usize, sample_rate: u32) {
+        self.channels = channels;
+        self.sample_rate = sample_rate;
+        self.set(self.settings);
+    }
+
+    fn process(&mut self, buf: &mut [f32]) {
+        if self.channels == 0 {
+            return;
+        }
+        // The key publishes once per block. On a `Mixer` both run inside `Mixer::process` on the
+        // render thread, a key added before this input is read for the same block, one added
+        // after it a block late. Either way one decision per block is as fine grained as it gets
+        let (target, coeff) = if self.sidechain.level() >= self.threshold {
+            (self.depth, self.attack)
+        } else {
+            (1f32, self.release)
+        };
+        for frame in buf.chunks_exact_mut(self.channels) {
+            self.gain += (target - self.gain) * coeff;
+            for x in frame.iter_mut() {
+                *x *= self.gain;
+            }
+        }
+    }
+
+    fn reset(&mut self) {
+        self.gain = 1f32;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        format::FormatSpec,
+        mixer::{MixOptions, Mixer, MixerInput},
+        processor::Chain,
+        stream::frame_channel,
+    };
+
+    const FS: u32 = 48000;
+    const PERIOD: usize = 480;
+
+    fn ms(x: usize) -> usize {
+        x * FS as usize / 1000
+    }
+
+    // Silent, talking from 300 ms to 800 ms, silent again
+    fn mic(i: usize) -> f32 {
+        if (ms(300)..ms(800)).contains(&i) {
+            0.3 * (i as f32 * 0.07).sin()
+        } else {
+            0f32
+        }
+    }
+
+    #[test]
+    fn key_and_ducker_on_two_mixer_inputs() {
+        let out = FormatSpec::float(1, FS);
+        let frames = ms(1500);
+        let sidechain = Sidechain::new();
+        let settings = DuckSettings {
+            threshold_db: -40f32,
+            depth_db: 12f32,
+            attack_ms: 20f32,
+            release_ms: 200f32,
+        };
+        let mut mixer = Mixer::new(
+            out,
+            PERIOD,
+            MixOptions {
+                headroom_db: 0f32,
+                soft_clip: false,
+            },
+        );
+        let mut add = |chain: Chain, samples: Vec<f32>| {
+            let (mut bridge, consumer) = frame_channel(frames, out.block_align());
+            let mut bytes = vec![0; samples.len() * out.sample_format.bytes()];
+            out.sample_format.write_samples(&samples, &mut bytes);
+            assert_eq!(bridge.push(&bytes), bytes.len());
+            mixer.add_input(MixerInput::new(consumer, out, out, PERIOD).processors(chain, out));
+        };
+        // The key goes first, so the ducker reads its level for the same block
+        add(
+            Chain::new().with(sidechain.key()),
+            (0..frames).map(mic).collect(),
+        );
+        add(
+            Chain::new().with(Ducker::new(sidechain.clone(), settings)),
+            vec![0.5; frames],
+        );
+
+        let mut mixed = vec![0f32; frames];
+        for block in mixed.chunks_mut(PERIOD) {
+            mixer.process(block);
+        }
+        // Whatever the mic did not add is the ducked game
+        let gain: Vec<f32> = mixed
+            .iter()
+            .enumerate()
+            .map(|(i, x)| (x - mic(i)) / 0.5)
+            .collect();
+        let depth = 10f32.powf(-12f32 / 20f32);
+
+        assert!(gain[..ms(300)].iter().all(|x| *x == 1f32));
+        // Ducking starts in the block the mic starts talking in
+        assert!(
+            gain[ms(300) + PERIOD - 1] < 0.8,
+            "{}",
+            gain[ms(300) + PERIOD - 1]
+        );
+        assert!((gain[ms(400)] - depth).abs() < 0.01, "{}", gain[ms(400)]);
+        assert!((gain[ms(799)] - depth).abs() < 0.01, "{}", gain[ms(799)]);
+        // The key falls under the threshold in about 35 ms, then the 200 ms release
+        assert!(gain[ms(850)] < 0.5, "{}", gain[ms(850)]);
+        assert!((gain[ms(1499)] - 0.97).abs() < 0.01, "{}", gain[ms(1499)]);
+    }
+
+    #[test]
+    fn key_below_the_threshold_leaves_the_pipe_alone() {
+        let sidechain = Sidechain::new();
+        let mut key = sidechain.key();
+        key.prepare(1, FS);
+        key.process(&mut [0.001; PERIOD]);
+        assert!(sidechain.level() <= 0.001);
+
+        let mut ducker = Ducker::new(sidechain, DuckSettings::default());
+        ducker.prepare(1, FS);
+        let mut buf = vec![1f32; ms(100)];
+        ducker.process(&mut buf);
+        assert!(buf.iter().all(|x| *x == 1f32));
+        assert_eq!(ducker.gain(), 1f32);
+    }
+}
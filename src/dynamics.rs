@@ -0,0 +1,987 @@
+use crate::processor::Processor;
+
+/// Per-sample one-pole coefficient reaching about 63% of a step after `ms`
+pub(crate) fn time_coeff(ms: f32, sample_rate: u32) -> f32 {
+    if ms <= 0f32 {
+        return 1f32;
+    }
+    1f32 - (-1000f32 / (ms * sample_rate as f32)).exp()
+}
+
+fn to_db(x: f32) -> f32 {
+    20f32 * x.max(1e-10).log10()
+}
+
+fn from_db(db: f32) -> f32 {
+    10f32.powf(db / 20f32)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GateSettings {
+    /// Level above which the gate is fully open, in dBFS
+    pub threshold_db: f32,
+    /// Expansion below the threshold: each dB under it comes out `ratio` dB under.
+    /// `f32::INFINITY` makes a gate, anything below 1 counts as 1
+    pub ratio: f32,
+    /// Deepest attenuation, in dB
+    pub range_db: f32,
+    pub attack_ms: f32,
+    /// How long the gate stays open after the level drops below the threshold
+    pub hold_ms: f32,
+    pub release_ms: f32,
+    /// One gain for every channel, driven by the loudest
+    pub linked: bool,
+}
+
+impl Default for GateSettings {
+    fn default() -> Self {
+        Self {
+            threshold_db: -50f32,
+            ratio: f32::INFINITY,
+            range_db: 80f32,
+            attack_ms: 1f32,
+            hold_ms: 50f32,
+            release_ms: 100f32,
+            linked: true,
+        }
+    }
+}
+
+impl GateSettings {
+    /// Gain in dB for a detector level, before timing
+    pub fn static_gain_db(&self, level_db: f32) -> f32 {
+        if level_db >= self.threshold_db {
+            return 0f32;
+        }
+        let reduction = (self.threshold_db - level_db) * (self.ratio.max(1f32) - 1f32);
+        -reduction.min(self.range_db)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct GateChannel {
+    envelope: f32,
+    hold_left: u32,
+    gain: f32,
+}
+
+impl Default for GateChannel {
+    fn default() -> Self {
+        Self {
+            envelope: 0f32,
+            hold_left: 0,
+            gain: 1f32,
+        }
+    }
+}
+
+/// Noise gate and downward expander. A peak detector drives the gain computer, the gain then
+/// opens with the attack time, stays open for the hold time and closes with the release time
+pub struct Gate {
+    settings: GateSettings,
+    channels: usize,
+    state: Vec<GateChannel>,
+    detector_decay: f32,
+    attack: f32,
+    release: f32,
+    hold: u32,
+    sample_rate: u32,
+}
+
+impl Gate {
+    // The detector falls this fast, quicker than any sensible release so it does not add to it
+    const DETECTOR_MS: f32 = 5f32;
+
+    pub fn new(settings: GateSettings) -> Self {
+        Self {
+            settings,
+            channels: 0,
+            state: Vec::new(),
+            detector_decay: 0f32,
+            attack: 1f32,
+            release: 1f32,
+            hold: 0,
+            sample_rate: 0,
+        }
+    }
+
+    pub fn settings(&self) -> GateSettings {
+        self.settings
+    }
+
+    /// Takes effect from the next sample, the gain itself never jumps
+    pub fn set(&mut self, settings: GateSettings) {
+        self.settings = settings;
+        if self.sample_rate > 0 {
+            self.update_timing();
+        }
+    }
+
+    /// Current gain of channel `i` (or of every channel when linked), linear
+    pub fn gain(&self, i: usize) -> f32 {
+        self.state.get(i).map_or(1f32, |x| x.gain)
+    }
+
+    fn update_timing(&mut self) {
+        let rate = self.sample_rate;
+        self.detector_decay = 1f32 - time_coeff(Self::DETECTOR_MS, rate);
+        self.attack = time_coeff(self.settings.attack_ms, rate);
+        self.release = time_coeff(self.settings.release_ms, rate);
+        self.hold = (self.settings.hold_ms.max(0f32) * rate as f32 / 1000f32) as u32;
+    }
+
+    fn step(&self, state: &mut GateChannel, peak: f32) -> f32 {
+        state.envelope = if peak > state.envelope {
+            peak
+        } else {
+            state.envelope * self.detector_decay
+        };
+        let level_db = to_db(state.envelope);
+        let target = if level_db >= self.settings.threshold_db {
+            state.hold_left = self.hold;
+            1f32
+        } else if state.hold_left > 0 {
+            state.hold_left -= 1;
+            1f32
+        } else {
+            from_db(self.settings.static_gain_db(level_db))
+        };
+        let coeff = if target > state.gain {
+            self.attack
+        } else {
+            self.release
+        };
+        state.gain += (target - state.gain) * coeff;
+        state.gain
+    }
+}
+
+impl Processor for Gate {
+    fn prepare(&mut self, channels: usize, sample_rate: u32) {
+        self.channels = channels;
+        self.sample_rate = sample_rate;
+        self.state = vec![GateChannel::default(); channels];
+        self.update_timing();
+    }
+
+    fn process(&mut self, buf: &mut [f32]) {
+        if self.channels == 0 {
+            return;
+        }
+        let mut state = std::mem::take(&mut self.state);
+        for frame in buf.chunks_exact_mut(self.channels) {
+            if self.settings.linked {
+                let peak = frame.iter().fold(0f32, |a, x| a.max(x.abs()));
+                let gain = self.step(&mut state[0], peak);
+                for x in frame.iter_mut() {
+                    *x *= gain;
+                }
+            } else {
+                for (x, state) in frame.iter_mut().zip(state.iter_mut()) {
+                    *x *= self.step(state, x.abs());
+                }
+            }
+        }
+        if self.settings.linked {
+            let first = state[0];
+            state[1..].fill(first);
+        }
+        self.state = state;
+    }
+
+    fn reset(&mut self) {
+        self.state.fill(GateChannel::default());
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CompressorSettings {
+    /// Level above which the gain is reduced, in dBFS
+    pub threshold_db: f32,
+    /// Each dB above the threshold comes out `1 / ratio` dB above it
+    pub ratio: f32,
+    /// Width of the soft knee around the threshold, 0 is a hard knee
+    pub knee_db: f32,
+    pub attack_ms: f32,
+    pub release_ms: f32,
+    /// Gain added after compression
+    pub makeup_db: f32,
+    /// One gain for every channel, driven by the loudest
+    pub linked: bool,
+}
+
+impl Default for CompressorSettings {
+    fn default() -> Self {
+        Self {
+            threshold_db: -18f32,
+            ratio: 4f32,
+            knee_db: 6f32,
+            attack_ms: 5f32,
+            release_ms: 100f32,
+            makeup_db: 0f32,
+            linked: true,
+        }
+    }
+}
+
+impl CompressorSettings {
+    /// Gain reduction in dB (zero or positive) for a detector level, before timing
+    pub fn static_reduction_db(&self, level_db: f32) -> f32 {
+        let over = level_db - self.threshold_db;
+        let slope = 1f32 - 1f32 / self.ratio.max(1f32);
+        let half_knee = self.knee_db.max(0f32) / 2f32;
+        if over <= -half_knee {
+            0f32
+        } else if over < half_knee {
+            slope * (over + half_knee).powi(2) / (2f32 * self.knee_db)
+        } else {
+            slope * over
+        }
+    }
+}
+
+/// Feed-forward compressor, linked across channels or one per channel. The peak level drives the
+/// gain computer, the gain reduction is smoothed in dB with separate attack and release
+pub struct Compressor {
+    settings: CompressorSettings,
+    channels: usize,
+    sample_rate: u32,
+    attack: f32,
+    release: f32,
+    makeup: f32,
+    reduction_db: Vec<f32>,
+}
+
+impl Compressor {
+    pub fn new(settings: CompressorSettings) -> Self {
+        Self {
+            settings,
+            channels: 0,
+            sample_rate: 0,
+            attack: 1f32,
+            release: 1f32,
+            makeup: from_db(settings.makeup_db),
+            reduction_db: Vec::new(),
+        }
+    }
+
+    pub fn settings(&self) -> CompressorSettings {
+        self.settings
+    }
+
+    pub fn set(&mut self, settings: CompressorSettings) {
+        self.settings = settings;
+        self.makeup = from_db(settings.makeup_db);
+        if self.sample_rate > 0 {
+            self.attack = time_coeff(settings.attack_ms, self.sample_rate);
+            self.release = time_coeff(settings.release_ms, self.sample_rate);
+        }
+    }
+
+    /// Current gain reduction of channel `i` (or of every channel when linked) in dB, without
+    /// makeup gain
+    pub fn reduction_db(&self, i: usize) -> f32 {
+        self.reduction_db.get(i).copied().unwrap_or(0f32)
+    }
+
+    // Moves `reduction_db` toward what `peak` asks for, returns the gain to apply
+    fn step(&self, reduction_db: &mut f32, peak: f32) -> f32 {
+        let target = self.settings.static_reduction_db(to_db(peak));
+        let coeff = if target > *reduction_db {
+            self.attack
+        } else {
+            self.release
+        };
+        *reduction_db += (target - *reduction_db) * coeff;
+        from_db(-*reduction_db) * self.makeup
+    }
+}
+
+impl Processor for Compressor {
+    fn prepare(&mut self, channels: usize, sample_rate: u32) {
+        self.channels = channels;
+        self.sample_rate = sample_rate;
+        self.reduction_db = vec![0f32; channels];
+        self.set(self.settings);
+    }
+
+    fn process(&mut self, buf: &mut [f32]) {
+        if self.channels == 0 {
+            return;
+        }
+        let mut reduction_db = std::mem::take(&mut self.reduction_db);
+        for frame in buf.chunks_exact_mut(self.channels) {
+            if self.settings.linked {
+                let peak = frame.iter().fold(0f32, |a, x| a.max(x.abs()));
+                let gain = self.step(&mut reduction_db[0], peak);
+                for x in frame.iter_mut() {
+                    *x *= gain;
+                }
+            } else {
+                for (x, reduction_db) in frame.iter_mut().zip(reduction_db.iter_mut()) {
+                    *x *= self.step(reduction_db, x.abs());
+                }
+            }
+        }
+        if self.settings.linked {
+            let first = reduction_db[0];
+            reduction_db[1..].fill(first);
+        }
+        self.reduction_db = reduction_db;
+    }
+
+    fn reset(&mut self) {
+        self.reduction_db.fill(0f32);
+    }
+}
+
+/// 4x oversampling true-peak estimate after ITU-R BS.1770: the sample itself plus three
+/// interpolated points up to the next sample, from a windowed sinc over `TruePeak::TAPS` samples.
+/// Reports on the sample `TruePeak::DELAY` frames behind the newest one
+#[derive(Debug, Clone)]
+pub struct TruePeak {
+    phases: [[f32; Self::TAPS]; 3],
+    history: Vec<[f32; Self::TAPS]>,
+    pos: usize,
+}
+
+impl TruePeak {
+    pub const TAPS: usize = 12;
+    pub const DELAY: usize = Self::TAPS / 2;
+
+    pub fn new(channels: usize) -> Self {
+        let mut phases = [[0f32; Self::TAPS]; 3];
+        let half = (Self::TAPS / 2) as f64;
+        for (k, phase) in phases.iter_mut().enumerate() {
+            let frac = (k + 1) as f64 / 4f64;
+            for (i, c) in phase.iter_mut().enumerate() {
+                // Tap i holds x[m + i - (TAPS / 2 - 1)], the point sits at m + frac
+                let t = frac - (i as f64 - (half - 1f64));
+                let sinc = if t == 0f64 {
+                    1f64
+                } else {
+                    (std::f64::consts::PI * t).sin() / (std::f64::consts::PI * t)
+                };
+                let window = 0.5f64 + 0.5f64 * (std::f64::consts::PI * t / half).cos();
+                *c = (sinc * window) as f32;
+            }
+        }
+        Self {
+            phases,
+            history: vec![[0f32; Self::TAPS]; channels],
+            pos: 0,
+        }
+    }
+
+    /// Pushes one frame, returns the largest absolute true-peak estimate over every channel
+    pub fn push(&mut self, frame: &[f32]) -> f32 {
+        self.pos = (self.pos + 1) % Self::TAPS;
+        let mut peak = 0f32;
+        for (x, history) in frame.iter().zip(&mut self.history) {
+            history[self.pos] = *x;
+            let tap = |i: usize| history[(self.pos + 1 + i) % Self::TAPS];
+            peak = peak.max(tap(Self::TAPS / 2 - 1).abs());
+            for phase in &self.phases {
+                let y: f32 = phase.iter().enumerate().map(|(i, c)| c * tap(i)).sum();
+                peak = peak.max(y.abs());
+            }
+        }
+        peak
+    }
+
+    pub fn reset(&mut self) {
+        self.history.fill([0f32; Self::TAPS]);
+    }
+}
+
+/// Minimum over the last `len` values pushed, in constant time per value on average. A monotonic
+/// queue in a ring allocated once: every entry is smaller than the ones pushed after it, so the
+/// oldest is the minimum and anything it outlives is dropped
+#[derive(Debug, Clone)]
+struct RunningMin {
+    // Push number and value, from `head` for `count` entries
+    entries: Vec<(u64, f32)>,
+    head: usize,
+    count: usize,
+    pushed: u64,
+}
+
+impl RunningMin {
+    fn new(len: usize) -> Self {
+        Self {
+            entries: vec![(0, 0f32); len.max(1)],
+            head: 0,
+            count: 0,
+            pushed: 0,
+        }
+    }
+
+    /// Adds `x`, returns the minimum of the window that ends with it
+    fn push(&mut self, x: f32) -> f32 {
+        let len = self.entries.len();
+        // Entries at least as large as `x` leave the window before it and are never the minimum
+        while self.count > 0 && self.entries[(self.head + self.count - 1) % len].1 >= x {
+            self.count -= 1;
+        }
+        // The window moves by one, so at most the oldest entry falls out of it
+        if self.count > 0 && self.entries[self.head].0 + len as u64 <= self.pushed {
+            self.head = (self.head + 1) % len;
+            self.count -= 1;
+        }
+        self.entries[(self.head + self.count) % len] = (self.pushed, x);
+        self.count += 1;
+        self.pushed += 1;
+        self.entries[self.head].1
+    }
+
+    fn clear(&mut self) {
+        self.head = 0;
+        self.count = 0;
+        self.pushed = 0;
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LimiterSettings {
+    /// Highest true peak let through, in dBTP as a BS.1770 4x meter reads it
+    pub ceiling_db: f32,
+    /// How far ahead peaks are seen, the gain ramps down over this time
+    pub lookahead_ms: f32,
+    pub release_ms: f32,
+}
+
+impl Default for LimiterSettings {
+    fn default() -> Self {
+        Self {
+            ceiling_db: -1f32,
+            lookahead_ms: 1.5f32,
+            release_ms: 50f32,
+        }
+    }
+}
+
+/// Look-ahead true-peak brick-wall limiter, linked across channels. The gain each true peak
+/// needs is held and averaged over the look-ahead window, so the gain is already down when the
+/// peak leaves the delay line and stays down for every sample the peak was interpolated from.
+/// A final clamp keeps every sample below the ceiling
+pub struct Limiter {
+    settings: LimiterSettings,
+    ceiling: f32,
+    channels: usize,
+    detector: TruePeak,
+    window: usize,
+    // Smallest gain any detected peak needs, over the last `window + 2 * TruePeak::DELAY` frames
+    required: RunningMin,
+    // Held and released gain over the last `window` frames, averaged into the applied gain
+    held: Vec<f32>,
+    held_sum: f64,
+    envelope: f32,
+    release: f32,
+    pos: usize,
+    delay: Vec<f32>,
+    delay_pos: usize,
+    gain: f32,
+}
+
+impl Limiter {
+    pub fn new(settings: LimiterSettings) -> Self {
+        Self {
+            settings,
+            ceiling: from_db(settings.ceiling_db),
+            channels: 0,
+            detector: TruePeak::new(0),
+            window: 1,
+            required: RunningMin::new(1),
+            held: Vec::new(),
+            held_sum: 0f64,
+            envelope: 1f32,
+            release: 1f32,
+            pos: 0,
+            delay: Vec::new(),
+            delay_pos: 0,
+            gain: 1f32,
+        }
+    }
+
+    pub fn settings(&self) -> LimiterSettings {
+        self.settings
+    }
+
+    /// Gain applied to the frame that left last, linear
+    pub fn gain(&self) -> f32 {
+        self.gain
+    }
+
+    fn delay_frames(&self) -> usize {
+        self.window - 1 + 2 * TruePeak::DELAY
+    }
+}
+
+impl Processor for Limiter {
+    fn prepare(&mut self, channels: usize, sample_rate: u32) {
+        self.channels = channels;
+        self.ceiling = from_db(self.settings.ceiling_db);
+        self.release = time_coeff(self.settings.release_ms, sample_rate);
+        self.window = ((self.settings.lookahead_ms * sample_rate as f32 / 1000f32) as usize).max(1);
+        self.detector = TruePeak::new(channels);
+        self.required = RunningMin::new(self.delay_frames() + 1);
+        self.held = vec![1f32; self.window];
+        self.delay = vec![0f32; self.delay_frames() * channels];
+        self.reset();
+    }
+
+    fn process(&mut self, buf: &mut [f32]) {
+        if self.channels == 0 {
+            return;
+        }
+        let ch = self.channels;
+        let delay_len = self.delay.len() / ch;
+        for frame in buf.chunks_exact_mut(ch) {
+            let peak = self.detector.push(frame);
+            self.pos = (self.pos + 1) % self.window;
+            let min = self.required.push(if peak > self.ceiling {
+                self.ceiling / peak
+            } else {
+                1f32
+            });
+            self.envelope = if min < self.envelope {
+                min
+            } else {
+                self.envelope + (min - self.envelope) * self.release
+            };
+            self.held_sum += self.envelope as f64 - self.held[self.pos] as f64;
+            self.held[self.pos] = self.envelope;
+            self.gain = (self.held_sum / self.window as f64) as f32;
+
+            let slot = &mut self.delay[self.delay_pos * ch..(self.delay_pos + 1) * ch];
+            for (x, d) in frame.iter_mut().zip(slot.iter_mut()) {
+                let out = *d * self.gain;
+                *d = *x;
+                *x = out.clamp(-self.ceiling, self.ceiling);
+            }
+            self.delay_pos = (self.delay_pos + 1) % delay_len;
+        }
+    }
+
+    fn latency(&self) -> usize {
+        self.delay_frames()
+    }
+
+    fn reset(&mut self) {
+        self.detector.reset();
+        self.required.clear();
+        self.held.fill(1f32);
+        self.held_sum = self.window as f64;
+        self.envelope = 1f32;
+        self.delay.fill(0f32);
+        self.delay_pos = 0;
+        self.gain = 1f32;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FS: u32 = 48000;
+
+    fn ms(x: f32) -> usize {
+        (x * FS as f32 / 1000f32) as usize
+    }
+
+    // Alternating full-scale signs at `level`, so the peak detector sees the level on every sample
+    fn square(level: f32, frames: usize) -> impl Iterator<Item = f32> {
+        (0..frames).map(move |i| if i % 2 == 0 { level } else { -level })
+    }
+
+    // -60 dBFS with a -6 dBFS burst from 100ms to 300ms
+    fn burst(len_ms: f32) -> Vec<f32> {
+        square(1f32, ms(len_ms))
+            .enumerate()
+            .map(|(i, x)| {
+                let loud = (ms(100f32)..ms(300f32)).contains(&i);
+                x * if loud { 0.5 } else { 0.001 }
+            })
+            .collect()
+    }
+
+    fn gate_gains(settings: GateSettings, input: &[f32]) -> Vec<f32> {
+        let mut gate = Gate::new(settings);
+        gate.prepare(1, FS);
+        let mut buf = input.to_vec();
+        gate.process(&mut buf);
+        buf.iter().zip(input).map(|(o, i)| o / i).collect()
+    }
+
+    fn gate(threshold_db: f32) -> GateSettings {
+        GateSettings {
+            threshold_db,
+            attack_ms: 1f32,
+            hold_ms: 50f32,
+            release_ms: 20f32,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn gate_attack() {
+        let gains = gate_gains(gate(-40f32), &burst(600f32));
+        assert!(gains[ms(90f32)] < 0.02, "{}", gains[ms(90f32)]);
+        // One attack time constant in, 63% of the way open
+        let one = gains[ms(101f32)];
+        assert!(one > 0.6 && one < 0.7, "{one}");
+        assert!(gains[ms(105f32)] > 0.99);
+    }
+
+    #[test]
+    fn gate_hold_and_release() {
+        let gains = gate_gains(gate(-40f32), &burst(600f32));
+        // The detector needs 5ms * ln(0.5 / 0.01) = 19.6ms to fall under the threshold, then the
+        // hold keeps the gate fully open for 50ms
+        assert!(gains[ms(300f32 + 19f32 + 49f32)] > 0.999);
+        let closing = (ms(300f32)..gains.len())
+            .find(|&i| gains[i] < 0.999)
+            .unwrap();
+        let held = (closing - ms(300f32)) as f32 * 1000f32 / FS as f32;
+        assert!((held - 69.6).abs() < 1f32, "{held}");
+        // Then halfway closed after 20ms * ln 2
+        let half = (closing..gains.len()).find(|&i| gains[i] < 0.5).unwrap();
+        let release = (half - closing) as f32 * 1000f32 / FS as f32;
+        assert!((release - 13.9).abs() < 1f32, "{release}");
+        // And down to the range in the end
+        let floor = from_db(-80f32);
+        assert!(gains[ms(590f32)] < 2e-3, "{}", gains[ms(590f32)]);
+        assert!(gains[ms(590f32)] >= floor * 0.99);
+    }
+
+    #[test]
+    fn gate_retriggers_within_the_hold() {
+        // A second burst before the hold ran out keeps the gate open throughout
+        let input: Vec<f32> = square(1f32, ms(400f32))
+            .enumerate()
+            .map(|(i, x)| {
+                let loud =
+                    (ms(100f32)..ms(200f32)).contains(&i) || (ms(230f32)..ms(300f32)).contains(&i);
+                x * if loud { 0.5 } else { 0.001 }
+            })
+            .collect();
+        let gains = gate_gains(gate(-40f32), &input);
+        assert!(gains[ms(110f32)..ms(300f32)].iter().all(|x| *x > 0.999));
+    }
+
+    #[test]
+    fn expander_ratio_and_range() {
+        let expander = GateSettings {
+            threshold_db: -40f32,
+            ratio: 2f32,
+            ..Default::default()
+        };
+        assert_eq!(expander.static_gain_db(-30f32), 0f32);
+        assert_eq!(expander.static_gain_db(-50f32), -10f32);
+        let gate = GateSettings {
+            threshold_db: -40f32,
+            range_db: 30f32,
+            ..Default::default()
+        };
+        assert_eq!(gate.static_gain_db(-41f32), -30f32);
+
+        // A steady -60 dBFS, 20 dB under a 2:1 expander, comes out 20 dB down
+        let input: Vec<f32> = square(0.001, ms(2000f32)).collect();
+        let gains = gate_gains(expander, &input);
+        let db = to_db(gains[ms(1990f32)]);
+        assert!((db + 20f32).abs() < 0.1, "{db}");
+    }
+
+    #[test]
+    fn ratio_below_one_never_boosts() {
+        for ratio in [0f32, 0.5, -2f32, f32::NAN] {
+            let settings = GateSettings {
+                threshold_db: -40f32,
+                ratio,
+                ..Default::default()
+            };
+            assert_eq!(settings.static_gain_db(-60f32), 0f32, "{ratio}");
+            let input: Vec<f32> = square(0.001, ms(200f32)).collect();
+            let gains = gate_gains(settings, &input);
+            assert!(gains.iter().all(|x| *x <= 1f32), "{ratio}");
+        }
+    }
+
+    #[test]
+    fn linked_vs_unlinked() {
+        // Left loud, right quiet
+        let input: Vec<f32> = square(1f32, ms(200f32))
+            .flat_map(|x| [0.5 * x, 0.001 * x])
+            .collect();
+        let run = |linked| {
+            let mut gate = Gate::new(GateSettings {
+                threshold_db: -40f32,
+                release_ms: 10f32,
+                linked,
+                ..Default::default()
+            });
+            gate.prepare(2, FS);
+            let mut buf = input.clone();
+            gate.process(&mut buf);
+            (gate.gain(0), gate.gain(1))
+        };
+        // The loud channel holds both open
+        let (left, right) = run(true);
+        assert!(left > 0.999 && right > 0.999, "{left} {right}");
+        assert_eq!(left, right);
+        let (left, right) = run(false);
+        assert!(left > 0.999 && right < 1e-3, "{left} {right}");
+    }
+
+    fn compressor(knee_db: f32) -> CompressorSettings {
+        CompressorSettings {
+            threshold_db: -20f32,
+            ratio: 4f32,
+            knee_db,
+            attack_ms: 10f32,
+            release_ms: 100f32,
+            ..Default::default()
+        }
+    }
+
+    // Reduction of every channel after each frame
+    fn reductions(settings: CompressorSettings, channels: usize, input: &[f32]) -> Vec<Vec<f32>> {
+        let mut compressor = Compressor::new(settings);
+        compressor.prepare(channels, FS);
+        let mut buf = input.to_vec();
+        buf.chunks_exact_mut(channels)
+            .map(|frame| {
+                compressor.process(frame);
+                (0..channels).map(|c| compressor.reduction_db(c)).collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn compressor_hard_knee() {
+        let settings = compressor(0f32);
+        for level_db in [-60f32, -30f32, -20f32] {
+            assert_eq!(settings.static_reduction_db(level_db), 0f32, "{level_db}");
+        }
+        // 12 dB over comes out 12 / ratio over
+        for (ratio, reduction) in [(1f32, 0f32), (2f32, 6f32), (4f32, 9f32), (0.5, 0f32)] {
+            let settings = CompressorSettings { ratio, ..settings };
+            assert_eq!(settings.static_reduction_db(-8f32), reduction, "{ratio}");
+        }
+        let limiting = CompressorSettings {
+            ratio: f32::INFINITY,
+            ..settings
+        };
+        assert_eq!(limiting.static_reduction_db(-8f32), 12f32);
+    }
+
+    #[test]
+    fn compressor_soft_knee() {
+        let settings = compressor(6f32);
+        // Nothing below the knee, the hard knee curve above it
+        assert_eq!(settings.static_reduction_db(-23f32), 0f32);
+        assert_eq!(settings.static_reduction_db(-30f32), 0f32);
+        assert!((settings.static_reduction_db(-17f32) - 0.75 * 3f32).abs() < 1e-5);
+        assert!((settings.static_reduction_db(-8f32) - 9f32).abs() < 1e-5);
+        // Halfway into the knee, a quarter of the hard knee reduction at its end
+        assert!((settings.static_reduction_db(-20f32) - 0.5625).abs() < 1e-5);
+        // Rising through the knee, never steeper than the slope above it
+        let curve: Vec<f32> = (0..=60)
+            .map(|i| settings.static_reduction_db(-23f32 + i as f32 * 0.1))
+            .collect();
+        assert!(
+            curve
+                .windows(2)
+                .all(|w| w[1] >= w[0] && w[1] - w[0] <= 0.075 + 1e-5)
+        );
+    }
+
+    #[test]
+    fn compressor_attack_and_release() {
+        let settings = compressor(0f32);
+        let input = burst(600f32);
+        let reduction: Vec<f32> = reductions(settings, 1, &input)
+            .into_iter()
+            .map(|x| x[0])
+            .collect();
+        let full = settings.static_reduction_db(to_db(0.5));
+        assert_eq!(reduction[ms(100f32) - 1], 0f32);
+        // 63% of the way after the attack time, all of it well before the burst ends
+        let attacked = reduction[ms(100f32) + ms(10f32) - 1];
+        assert!((attacked - full * 0.632).abs() < 0.05, "{attacked}");
+        assert!((reduction[ms(300f32) - 1] - full).abs() < 1e-3);
+        // Back to 37% after the release time
+        let released = reduction[ms(300f32) + ms(100f32) - 1];
+        assert!((released - full * 0.368).abs() < 0.05, "{released}");
+
+        // Makeup is applied on top of the reduction
+        let mut compressor = Compressor::new(CompressorSettings {
+            makeup_db: 3f32,
+            ..settings
+        });
+        compressor.prepare(1, FS);
+        let mut buf: Vec<f32> = square(0.5, ms(300f32)).collect();
+        compressor.process(&mut buf);
+        let out_db = to_db(buf[buf.len() - 1].abs());
+        assert!(
+            (out_db - (to_db(0.5) - full + 3f32)).abs() < 0.01,
+            "{out_db}"
+        );
+        assert_eq!(compressor.latency(), 0);
+    }
+
+    #[test]
+    fn compressor_linked_vs_unlinked() {
+        // Left 14 dB over the threshold, right well under it
+        let input: Vec<f32> = square(1f32, ms(200f32))
+            .flat_map(|x| [0.5 * x, 0.01 * x])
+            .collect();
+        let settings = compressor(0f32);
+        let full = settings.static_reduction_db(to_db(0.5));
+        let last = |linked| {
+            let settings = CompressorSettings { linked, ..settings };
+            reductions(settings, 2, &input).pop().unwrap()
+        };
+        // The loud channel turns both down
+        let linked = last(true);
+        assert!((linked[0] - full).abs() < 1e-3, "{linked:?}");
+        assert_eq!(linked[0], linked[1]);
+        let unlinked = last(false);
+        assert!((unlinked[0] - full).abs() < 1e-3, "{unlinked:?}");
+        assert_eq!(unlinked[1], 0f32);
+    }
+
+    #[test]
+    fn running_min_matches_the_window() {
+        let mut x = 0x2545_f491_4f6c_dd1du64;
+        let values: Vec<f32> = (0..2000)
+            .map(|_| {
+                x ^= x << 13;
+                x ^= x >> 7;
+                x ^= x << 17;
+                // Few distinct values, so ties come up
+                (x % 16) as f32 / 16f32
+            })
+            .collect();
+        for len in [1, 2, 5, 64] {
+            let mut min = RunningMin::new(len);
+            for (i, v) in values.iter().enumerate() {
+                let expected = values[(i + 1).saturating_sub(len)..=i]
+                    .iter()
+                    .fold(f32::INFINITY, |a, x| a.min(*x));
+                assert_eq!(min.push(*v), expected, "{len} {i}");
+            }
+            min.clear();
+            assert_eq!(min.push(0.5), 0.5);
+        }
+    }
+
+    fn limit(settings: LimiterSettings, channels: usize, input: &[f32]) -> (Vec<f32>, usize) {
+        let mut limiter = Limiter::new(settings);
+        limiter.prepare(channels, FS);
+        let mut buf = input.to_vec();
+        // Odd block sizes, so the delay line wraps mid-block
+        for block in buf.chunks_mut(37 * channels) {
+            limiter.process(block);
+        }
+        (buf, limiter.latency())
+    }
+
+    // Sample peak and true peak of `output` as a BS.1770 meter reads it
+    fn peaks(output: &[f32], channels: usize) -> (f32, f32) {
+        let mut meter = TruePeak::new(channels);
+        let true_peak = output
+            .chunks_exact(channels)
+            .map(|f| meter.push(f))
+            .fold(0f32, f32::max);
+        let sample_peak = output.iter().fold(0f32, |a, x| a.max(x.abs()));
+        (sample_peak, true_peak)
+    }
+
+    // 50ms bursts every 100ms, built from one frame function
+    fn bursts(channels: usize, frame: impl Fn(usize) -> f32) -> Vec<f32> {
+        (0..ms(1000f32))
+            .flat_map(|i| {
+                let x = if i % ms(100f32) < ms(50f32) {
+                    frame(i)
+                } else {
+                    0f32
+                };
+                std::iter::repeat_n(x, channels)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn limiter_latency_matches_the_delay() {
+        let settings = LimiterSettings::default();
+        let mut input = vec![0f32; ms(50f32)];
+        input[100] = 0.25;
+        let (output, latency) = limit(settings, 1, &input);
+        assert_eq!(latency, ms(1.5f32) - 1 + 2 * TruePeak::DELAY);
+        // Below the ceiling nothing changes but the position
+        let (at, peak) =
+            output.iter().enumerate().fold(
+                (0, 0f32),
+                |a, (i, x)| if x.abs() > a.1 { (i, x.abs()) } else { a },
+            );
+        assert_eq!(at, 100 + latency);
+        assert_eq!(peak, 0.25);
+
+        let input: Vec<f32> = (0..ms(50f32))
+            .map(|i| 0.5 * (i as f32 * 0.05).sin())
+            .collect();
+        let (output, latency) = limit(settings, 1, &input);
+        assert!(output[..latency].iter().all(|x| *x == 0f32));
+        for (o, i) in output[latency..].iter().zip(&input) {
+            assert!((o - i).abs() < 1e-6, "{o} {i}");
+        }
+    }
+
+    #[test]
+    fn limiter_holds_full_scale_bursts() {
+        for ceiling_db in [0f32, -1f32, -6f32] {
+            let ceiling = from_db(ceiling_db);
+            let settings = LimiterSettings {
+                ceiling_db,
+                ..Default::default()
+            };
+            let input = bursts(2, |i| if i % 2 == 0 { 1f32 } else { -1f32 });
+            let (output, latency) = limit(settings, 2, &input);
+            let (sample_peak, true_peak) = peaks(&output[latency * 2..], 2);
+            assert!(sample_peak <= ceiling, "{ceiling_db}: {sample_peak}");
+            assert!(true_peak <= ceiling * 1.0001, "{ceiling_db}: {true_peak}");
+            // The gain did the work, not the final clamp
+            assert!(sample_peak > ceiling * 0.5, "{ceiling_db}: {sample_peak}");
+        }
+    }
+
+    #[test]
+    fn limiter_holds_intersample_peaks() {
+        // fs/4 at 45 degrees: every sample is at 0.707, the waveform between them reaches 1
+        let quarter = |i: usize| {
+            let phase = std::f32::consts::FRAC_PI_2 * (i % 4) as f32 + std::f32::consts::FRAC_PI_4;
+            phase.sin()
+        };
+        let input = bursts(1, quarter);
+        let (sample_peak, true_peak) = peaks(&input, 1);
+        assert!(
+            sample_peak < 0.71 && true_peak > 0.99,
+            "{sample_peak} {true_peak}"
+        );
+
+        let ceiling_db = -1f32;
+        let ceiling = from_db(ceiling_db);
+        let settings = LimiterSettings {
+            ceiling_db,
+            ..Default::default()
+        };
+        // Every sample is below the ceiling, only the true peak is over it
+        assert!(sample_peak < ceiling);
+        let (output, latency) = limit(settings, 1, &input);
+        let (sample_peak, true_peak) = peaks(&output[latency..], 1);
+        assert!(sample_peak <= ceiling, "{sample_peak}");
+        assert!(true_peak <= ceiling * 1.0001, "{true_peak}");
+        assert!(true_peak > ceiling * 0.9, "{true_peak}");
+    }
+}
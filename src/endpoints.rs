@@ -0,0 +1,128 @@
+use anyhow::Result;
+use tracing::warn;
+use windows::Win32::{
+    Media::Audio::{
+        DEVICE_STATE, EDataFlow, ERole, IMMDevice, IMMDeviceEnumerator, MMDeviceEnumerator,
+        eCapture, eCommunications, eConsole, eMultimedia, eRender,
+    },
+    System::Com::{CLSCTX_ALL, CoCreateInstance},
+};
+use windows_strings::HSTRING;
+
+use crate::{
+    device::{DeviceInfo, DeviceSelector, DeviceState, DeviceStateMask, Flow, Role},
+    utils::IMMDeviceEx,
+};
+
+pub fn get_devices(flow: EDataFlow, states: DeviceStateMask) -> Result<Vec<IMMDevice>> {
+    unsafe {
+        let dev_enum: IMMDeviceEnumerator =
+            CoCreateInstance(&MMDeviceEnumerator, None, CLSCTX_ALL)?;
+        let devs = dev_enum.EnumAudioEndpoints(flow, DEVICE_STATE(states.0))?;
+        let count = devs.GetCount()?;
+        let s: Result<Vec<_>, _> = (0..count).map(|x| devs.Item(x)).collect();
+        Ok(s?)
+    }
+}
+
+/// Endpoints of `flow` whose state is in `states`, as plain data for [`DeviceSelector`]
+pub fn device_infos(flow: Flow, states: DeviceStateMask) -> Result<Vec<DeviceInfo>> {
+    let data_flow = to_edataflow(flow);
+
+    unsafe {
+        let dev_enum: IMMDeviceEnumerator =
+            CoCreateInstance(&MMDeviceEnumerator, None, CLSCTX_ALL)?;
+        // No default endpoint is not an error, there is simply nothing to mark
+        let defaults: Vec<(Role, String)> = Role::ALL
+            .into_iter()
+            .filter_map(|role| {
+                let dev = dev_enum
+                    .GetDefaultAudioEndpoint(data_flow, to_erole(role))
+                    .ok()?;
+                Some((role, dev.GetId().ok()?.to_string().ok()?))
+            })
+            .collect();
+
+        // A device whose properties can't be read (disabled and not-present ones are the usual
+        // suspects) must not hide all the others, only enumeration failures are errors
+        Ok(get_devices(data_flow, states)?
+            .into_iter()
+            .filter_map(|dev| {
+                let id = match dev
+                    .GetId()
+                    .map_err(anyhow::Error::from)
+                    .and_then(|x| Ok(x.to_string()?))
+                {
+                    Ok(id) => id,
+                    Err(e) => {
+                        warn!("skipping a {flow:?} device without an id: {e}");
+                        return None;
+                    }
+                };
+                let state = match dev.GetState() {
+                    Ok(x) => DeviceState::from_bits(x.0),
+                    Err(e) => {
+                        warn!("skipping {id}, state unavailable: {e}");
+                        return None;
+                    }
+                };
+                let Some(state) = state else {
+                    warn!("skipping {id}, unknown device state");
+                    return None;
+                };
+                let name = match dev.display_name() {
+                    Ok(x) => x.to_string(),
+                    Err(e) => {
+                        warn!("no name for {id}: {e}");
+                        "<unknown>".to_string()
+                    }
+                };
+                let default_roles = defaults
+                    .iter()
+                    .filter(|(_, x)| *x == id)
+                    .map(|(role, _)| *role)
+                    .collect();
+                Some(DeviceInfo {
+                    name,
+                    id,
+                    flow,
+                    state,
+                    default_roles,
+                })
+            })
+            .collect())
+    }
+}
+
+pub fn to_erole(role: Role) -> ERole {
+    match role {
+        Role::Console => eConsole,
+        Role::Multimedia => eMultimedia,
+        Role::Communications => eCommunications,
+    }
+}
+
+pub fn to_edataflow(flow: Flow) -> EDataFlow {
+    match flow {
+        Flow::Render => eRender,
+        Flow::Capture => eCapture,
+    }
+}
+
+pub fn get_device(id: &str) -> Result<IMMDevice> {
+    unsafe {
+        let dev_enum: IMMDeviceEnumerator =
+            CoCreateInstance(&MMDeviceEnumerator, None, CLSCTX_ALL)?;
+        Ok(dev_enum.GetDevice(&HSTRING::from(id))?)
+    }
+}
+
+/// Resolve `selector` against the endpoints of `flow` whose state is in `states`
+pub fn find_device(
+    flow: Flow,
+    states: DeviceStateMask,
+    selector: &DeviceSelector,
+) -> Result<IMMDevice> {
+    let devs = device_infos(flow, states)?;
+    get_device(&selector.select(&devs)?.id)
+}
@@ -0,0 +1,497 @@
+use std::{fmt::Display, str::FromStr, time::Duration};
+
+use thiserror::Error;
+
+use crate::{frames_to_reference_time, reference_time_to_frames, to_reference_time};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ShareMode {
+    /// Goes through the audio engine, format is the engine mix format
+    #[default]
+    Shared,
+    /// Owns the endpoint, format negotiated with the driver
+    Exclusive,
+}
+
+impl Display for ShareMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ShareMode::Shared => write!(f, "shared"),
+            ShareMode::Exclusive => write!(f, "exclusive"),
+        }
+    }
+}
+
+impl FromStr for ShareMode {
+    type Err = EngineConfigError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "shared" => Ok(ShareMode::Shared),
+            "exclusive" => Ok(ShareMode::Exclusive),
+            _ => Err(EngineConfigError::InvalidShareMode(s.into())),
+        }
+    }
+}
+
+/// Engine period asked for by the user, snapped to what the endpoint supports with [`snap_period`]
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum PeriodRequest {
+    /// Smallest period the endpoint allows
+    #[default]
+    Min,
+    Frames(u32),
+    Millis(f64),
+}
+
+impl PeriodRequest {
+    /// `None` when the minimum is asked for
+    pub fn frames(&self, sample_rate: u32) -> Option<u32> {
+        match *self {
+            PeriodRequest::Min => None,
+            PeriodRequest::Frames(frames) => Some(frames),
+            PeriodRequest::Millis(ms) => Some((ms * sample_rate as f64 / 1000f64).round() as u32),
+        }
+    }
+}
+
+impl Display for PeriodRequest {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PeriodRequest::Min => write!(f, "min"),
+            PeriodRequest::Frames(frames) => write!(f, "{frames}"),
+            PeriodRequest::Millis(ms) => write!(f, "{ms}ms"),
+        }
+    }
+}
+
+/// `min`, a frame count (`480`) or milliseconds (`2.5ms`)
+impl FromStr for PeriodRequest {
+    type Err = EngineConfigError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let invalid = || EngineConfigError::InvalidPeriod(s.into());
+        if s.eq_ignore_ascii_case("min") {
+            return Ok(PeriodRequest::Min);
+        }
+        if let Some(ms) = s.strip_suffix("ms") {
+            let ms: f64 = ms.trim().parse().map_err(|_| invalid())?;
+            if !ms.is_finite() || ms <= 0f64 {
+                return Err(invalid());
+            }
+            return Ok(PeriodRequest::Millis(ms));
+        }
+        match s.parse() {
+            Ok(0) | Err(_) => Err(invalid()),
+            Ok(frames) => Ok(PeriodRequest::Frames(frames)),
+        }
+    }
+}
+
+/// Periods `GetSharedModeEnginePeriod` reports for a format, in frames
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EnginePeriods {
+    pub default: u32,
+    pub fundamental: u32,
+    pub min: u32,
+    pub max: u32,
+}
+
+/// Nearest multiple of `fundamental` to `requested` (ties go up), clamped to `[min, max]`.
+/// `InitializeSharedAudioStream` rejects anything else
+pub fn snap_period(requested: u32, periods: &EnginePeriods) -> u32 {
+    let fundamental = periods.fundamental.max(1);
+    let snapped =
+        (requested as u64 + fundamental as u64 / 2) / fundamental as u64 * fundamental as u64;
+    (snapped.min(u32::MAX as u64) as u32).clamp(periods.min, periods.max.max(periods.min))
+}
+
+/// Which way audio flows through a client. Loopback clients capture what a render endpoint (or a
+/// process, see `activate_audio_async`) plays
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StreamKind {
+    Capture,
+    Render,
+    Loopback,
+}
+
+impl Display for StreamKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StreamKind::Capture => write!(f, "capture"),
+            StreamKind::Render => write!(f, "render"),
+            StreamKind::Loopback => write!(f, "loopback"),
+        }
+    }
+}
+
+/// What the pipe records from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InputKind {
+    /// A capture endpoint (microphone, line in)
+    Device,
+    /// A single process tree, see `activate_audio_async`
+    Process,
+    /// Everything a render endpoint plays
+    Loopback,
+}
+
+impl InputKind {
+    pub const ALL: [InputKind; 3] = [InputKind::Device, InputKind::Process, InputKind::Loopback];
+
+    pub fn stream_kind(self) -> StreamKind {
+        match self {
+            InputKind::Device => StreamKind::Capture,
+            InputKind::Process | InputKind::Loopback => StreamKind::Loopback,
+        }
+    }
+
+    /// Loopback clients can only be opened in shared mode
+    pub fn validate(self, share_mode: ShareMode) -> Result<(), EngineConfigError> {
+        match (self.stream_kind(), share_mode) {
+            (StreamKind::Loopback, ShareMode::Exclusive) => {
+                Err(EngineConfigError::SharedOnly(self))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Display for InputKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            InputKind::Device => write!(f, "device"),
+            InputKind::Process => write!(f, "process"),
+            InputKind::Loopback => write!(f, "loopback"),
+        }
+    }
+}
+
+/// The name or the 1-based menu number
+impl FromStr for InputKind {
+    type Err = EngineConfigError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "1" | "device" | "capture" => Ok(InputKind::Device),
+            "2" | "process" => Ok(InputKind::Process),
+            "3" | "loopback" => Ok(InputKind::Loopback),
+            _ => Err(EngineConfigError::InvalidInputKind(s.into())),
+        }
+    }
+}
+
+/// Engine period assumed when the client cannot report one, process loopback clients for example
+pub const LEGACY_FALLBACK_PERIOD: Duration = Duration::from_millis(10);
+
+/// `IAudioClient::Initialize` arguments for clients without `IAudioClient3`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LegacyInit {
+    /// Add `AUDCLNT_STREAMFLAGS_LOOPBACK`
+    pub loopback: bool,
+    /// `hnsBufferDuration`, shared mode takes 0 for `hnsPeriodicity`
+    pub buffer_duration: i64,
+    /// Period the engine signals at, in frames
+    pub period: u32,
+}
+
+/// `default_period` is what `GetDevicePeriod` reports in 100ns units, if anything. Shared mode
+/// always runs at the engine period, so a requested period can only make the buffer larger
+pub fn legacy_init(
+    kind: StreamKind,
+    default_period: Option<i64>,
+    request: PeriodRequest,
+    sample_rate: u32,
+) -> LegacyInit {
+    let period = default_period
+        .filter(|x| *x > 0)
+        .unwrap_or_else(|| to_reference_time(LEGACY_FALLBACK_PERIOD));
+    let requested = request
+        .frames(sample_rate)
+        .map(|x| frames_to_reference_time(x, sample_rate))
+        .unwrap_or(0);
+
+    LegacyInit {
+        loopback: kind == StreamKind::Loopback,
+        buffer_duration: period.max(requested),
+        period: reference_time_to_frames(period, sample_rate),
+    }
+}
+
+/// How a single endpoint gets initialised
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct StreamConfig {
+    pub kind: StreamKind,
+    pub share_mode: ShareMode,
+    pub period: PeriodRequest,
+}
+
+impl StreamConfig {
+    /// Shared mode at the minimum period
+    pub fn new(kind: StreamKind) -> Self {
+        Self {
+            kind,
+            share_mode: ShareMode::default(),
+            period: PeriodRequest::default(),
+        }
+    }
+
+    pub fn share_mode(mut self, share_mode: ShareMode) -> Self {
+        self.share_mode = share_mode;
+        self
+    }
+
+    pub fn period(mut self, period: PeriodRequest) -> Self {
+        self.period = period;
+        self
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum EngineConfigError {
+    #[error("invalid share mode `{0}`, expected shared or exclusive")]
+    InvalidShareMode(String),
+
+    #[error("invalid period `{0}`, expected min, a frame count or milliseconds like 2.5ms")]
+    InvalidPeriod(String),
+
+    #[error("invalid input `{0}`, expected device, process or loopback")]
+    InvalidInputKind(String),
+
+    #[error("{0} input only works in shared mode")]
+    SharedOnly(InputKind),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PERIODS: EnginePeriods = EnginePeriods {
+        default: 480,
+        fundamental: 48,
+        min: 144,
+        max: 480,
+    };
+
+    #[test]
+    fn snaps_to_nearest_fundamental() {
+        assert_eq!(snap_period(144, &PERIODS), 144);
+        assert_eq!(snap_period(200, &PERIODS), 192);
+        assert_eq!(snap_period(230, &PERIODS), 240);
+    }
+
+    #[test]
+    fn ties_round_up() {
+        // 216 is as far from 192 as from 240
+        assert_eq!(snap_period(215, &PERIODS), 192);
+        assert_eq!(snap_period(216, &PERIODS), 240);
+    }
+
+    #[test]
+    fn odd_fundamental() {
+        // 10ms at 44.1kHz
+        let periods = EnginePeriods {
+            default: 441,
+            fundamental: 441,
+            min: 441,
+            max: 441 * 4,
+        };
+        assert_eq!(snap_period(661, &periods), 441);
+        assert_eq!(snap_period(662, &periods), 882);
+        assert_eq!(snap_period(1000, &periods), 882);
+        assert_eq!(snap_period(1200, &periods), 1323);
+
+        let periods = EnginePeriods {
+            default: 441,
+            fundamental: 7,
+            min: 21,
+            max: 441,
+        };
+        assert_eq!(snap_period(24, &periods), 21);
+        assert_eq!(snap_period(25, &periods), 28);
+    }
+
+    #[test]
+    fn clamps_to_min_and_max() {
+        assert_eq!(snap_period(1, &PERIODS), 144);
+        assert_eq!(snap_period(100, &PERIODS), 144);
+        assert_eq!(snap_period(500, &PERIODS), 480);
+        assert_eq!(snap_period(u32::MAX, &PERIODS), 480);
+        // A bogus max below min still leaves min
+        let inverted = EnginePeriods { max: 96, ..PERIODS };
+        assert_eq!(snap_period(1000, &inverted), 144);
+    }
+
+    #[test]
+    fn zero_fundamental() {
+        let periods = EnginePeriods {
+            fundamental: 0,
+            ..PERIODS
+        };
+        assert_eq!(snap_period(201, &periods), 201);
+        assert_eq!(snap_period(10, &periods), 144);
+        assert_eq!(snap_period(1000, &periods), 480);
+    }
+
+    #[test]
+    fn millis_round_to_nearest_frame() {
+        assert_eq!(PeriodRequest::Millis(2.5).frames(48000), Some(120));
+        assert_eq!(PeriodRequest::Millis(2.5).frames(44100), Some(110));
+        assert_eq!(PeriodRequest::Millis(1.0).frames(44100), Some(44));
+        // 220.5 frames, halves round away from zero
+        assert_eq!(PeriodRequest::Millis(5.0).frames(44100), Some(221));
+        assert_eq!(PeriodRequest::Millis(0.01).frames(44100), Some(0));
+        assert_eq!(PeriodRequest::Frames(96).frames(44100), Some(96));
+        assert_eq!(PeriodRequest::Min.frames(44100), None);
+
+        let periods = EnginePeriods {
+            default: 441,
+            fundamental: 147,
+            min: 147,
+            max: 441,
+        };
+        let frames = PeriodRequest::Millis(5.0).frames(44100).unwrap();
+        assert_eq!(snap_period(frames, &periods), 294);
+    }
+
+    #[test]
+    fn legacy_loopback_flag() {
+        for (kind, loopback) in [
+            (StreamKind::Capture, false),
+            (StreamKind::Render, false),
+            (StreamKind::Loopback, true),
+        ] {
+            let init = legacy_init(kind, Some(100_000), PeriodRequest::Min, 48000);
+            assert_eq!(init.loopback, loopback, "{kind}");
+            assert_eq!(init.buffer_duration, 100_000);
+            assert_eq!(init.period, 480);
+        }
+    }
+
+    #[test]
+    fn legacy_falls_back_to_10ms() {
+        for default_period in [None, Some(0), Some(-1)] {
+            let init = legacy_init(
+                StreamKind::Loopback,
+                default_period,
+                PeriodRequest::Min,
+                48000,
+            );
+            assert_eq!(
+                init,
+                LegacyInit {
+                    loopback: true,
+                    buffer_duration: 100_000,
+                    period: 480,
+                },
+                "{default_period:?}"
+            );
+        }
+        let init = legacy_init(StreamKind::Capture, None, PeriodRequest::Min, 44100);
+        assert_eq!(init.period, 441);
+    }
+
+    #[test]
+    fn legacy_request_only_grows_the_buffer() {
+        // 3ms device period, the engine keeps signalling at it
+        let init = legacy_init(StreamKind::Render, Some(30_000), PeriodRequest::Min, 48000);
+        assert_eq!(init.buffer_duration, 30_000);
+        assert_eq!(init.period, 144);
+
+        let init = legacy_init(
+            StreamKind::Render,
+            Some(30_000),
+            PeriodRequest::Millis(20.0),
+            48000,
+        );
+        assert_eq!(init.buffer_duration, 200_000);
+        assert_eq!(init.period, 144);
+
+        let init = legacy_init(
+            StreamKind::Render,
+            Some(30_000),
+            PeriodRequest::Frames(960),
+            48000,
+        );
+        assert_eq!(init.buffer_duration, 200_000);
+
+        // Smaller than the device period is not possible in shared mode
+        let init = legacy_init(
+            StreamKind::Capture,
+            Some(100_000),
+            PeriodRequest::Frames(10),
+            44100,
+        );
+        assert_eq!(init.buffer_duration, 100_000);
+        assert_eq!(init.period, 441);
+    }
+
+    #[test]
+    fn parse_input_kind() {
+        assert_eq!("1".parse::<InputKind>().unwrap(), InputKind::Device);
+        assert_eq!("capture".parse::<InputKind>().unwrap(), InputKind::Device);
+        assert_eq!(
+            " Process ".parse::<InputKind>().unwrap(),
+            InputKind::Process
+        );
+        assert_eq!("3".parse::<InputKind>().unwrap(), InputKind::Loopback);
+        for kind in InputKind::ALL {
+            assert_eq!(kind.to_string().parse::<InputKind>().unwrap(), kind);
+        }
+        for invalid in ["0", "4", "render", ""] {
+            assert!(matches!(
+                invalid.parse::<InputKind>(),
+                Err(EngineConfigError::InvalidInputKind(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn loopback_inputs_are_shared_only() {
+        for kind in InputKind::ALL {
+            assert!(kind.validate(ShareMode::Shared).is_ok());
+        }
+        assert!(InputKind::Device.validate(ShareMode::Exclusive).is_ok());
+        for kind in [InputKind::Process, InputKind::Loopback] {
+            assert_eq!(kind.stream_kind(), StreamKind::Loopback);
+            assert!(matches!(
+                kind.validate(ShareMode::Exclusive),
+                Err(EngineConfigError::SharedOnly(x)) if x == kind
+            ));
+        }
+        assert_eq!(
+            InputKind::Loopback
+                .validate(ShareMode::Exclusive)
+                .unwrap_err()
+                .to_string(),
+            "loopback input only works in shared mode"
+        );
+    }
+
+    #[test]
+    fn parse_period() {
+        assert_eq!(
+            "2.5ms".parse::<PeriodRequest>().unwrap(),
+            PeriodRequest::Millis(2.5)
+        );
+        assert_eq!(
+            " 480 ".parse::<PeriodRequest>().unwrap(),
+            PeriodRequest::Frames(480)
+        );
+        assert_eq!("MIN".parse::<PeriodRequest>().unwrap(), PeriodRequest::Min);
+        for invalid in ["0", "-1ms", "0ms", "infms", "NaNms", "fast", ""] {
+            assert!(invalid.parse::<PeriodRequest>().is_err(), "{invalid}");
+        }
+        for request in [
+            PeriodRequest::Min,
+            PeriodRequest::Frames(128),
+            PeriodRequest::Millis(2.5),
+        ] {
+            assert_eq!(
+                request.to_string().parse::<PeriodRequest>().unwrap(),
+                request
+            );
+        }
+    }
+}
@@ -0,0 +1,575 @@
+use std::{
+    f64::consts::{PI, SQRT_2},
+    fmt::Display,
+    str::FromStr,
+};
+
+use thiserror::Error;
+
+use crate::processor::Processor;
+
+/// Shape of one EQ band, after the RBJ audio EQ cookbook
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FilterKind {
+    Peaking,
+    LowShelf,
+    HighShelf,
+    HighPass,
+    LowPass,
+    Notch,
+}
+
+impl FilterKind {
+    pub const ALL: [FilterKind; 6] = [
+        FilterKind::Peaking,
+        FilterKind::LowShelf,
+        FilterKind::HighShelf,
+        FilterKind::HighPass,
+        FilterKind::LowPass,
+        FilterKind::Notch,
+    ];
+
+    /// Whether `gain_db` means anything for this shape
+    pub fn has_gain(self) -> bool {
+        matches!(
+            self,
+            FilterKind::Peaking | FilterKind::LowShelf | FilterKind::HighShelf
+        )
+    }
+}
+
+impl FromStr for FilterKind {
+    type Err = InvalidBand;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "peak" | "peaking" | "bell" => Ok(FilterKind::Peaking),
+            "lowshelf" | "ls" => Ok(FilterKind::LowShelf),
+            "highshelf" | "hs" => Ok(FilterKind::HighShelf),
+            "highpass" | "hp" => Ok(FilterKind::HighPass),
+            "lowpass" | "lp" => Ok(FilterKind::LowPass),
+            "notch" => Ok(FilterKind::Notch),
+            _ => Err(InvalidBand::Kind),
+        }
+    }
+}
+
+impl Display for FilterKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            FilterKind::Peaking => "peak",
+            FilterKind::LowShelf => "lowshelf",
+            FilterKind::HighShelf => "highshelf",
+            FilterKind::HighPass => "highpass",
+            FilterKind::LowPass => "lowpass",
+            FilterKind::Notch => "notch",
+        })
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum InvalidBand {
+    #[error("invalid filter type, expected peak, lowshelf, highshelf, highpass, lowpass or notch")]
+    Kind,
+    #[error(
+        "invalid band, expected `type:freq[:gain_db][:q]` (no gain for pass and notch filters)"
+    )]
+    Syntax,
+    #[error("frequency and q must be positive")]
+    Range,
+}
+
+/// One EQ band. `q` sets the bandwidth of peaks and notches, the slope of shelves and the
+/// resonance of pass filters (0.707 is Butterworth)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Band {
+    pub kind: FilterKind,
+    pub freq: f64,
+    pub gain_db: f64,
+    pub q: f64,
+}
+
+impl Band {
+    pub fn new(kind: FilterKind, freq: f64) -> Self {
+        Self {
+            kind,
+            freq,
+            gain_db: 0f64,
+            q: 1f64 / SQRT_2,
+        }
+    }
+
+    pub fn gain_db(mut self, gain_db: f64) -> Self {
+        self.gain_db = gain_db;
+        self
+    }
+
+    pub fn q(mut self, q: f64) -> Self {
+        self.q = q;
+        self
+    }
+
+    /// Biquad coefficients at `sample_rate`. The frequency is kept below Nyquist
+    pub fn coeffs(&self, sample_rate: u32) -> Coeffs {
+        let fs = sample_rate as f64;
+        let w0 = 2f64 * PI * self.freq.clamp(1f64, fs * 0.499) / fs;
+        let (sin, cos) = w0.sin_cos();
+        let alpha = sin / (2f64 * self.q.max(1e-3));
+        let a = 10f64.powf(self.gain_db / 40f64);
+
+        let (b0, b1, b2, a0, a1, a2) = match self.kind {
+            FilterKind::Peaking => (
+                1f64 + alpha * a,
+                -2f64 * cos,
+                1f64 - alpha * a,
+                1f64 + alpha / a,
+                -2f64 * cos,
+                1f64 - alpha / a,
+            ),
+            FilterKind::LowShelf => {
+                let s = 2f64 * a.sqrt() * alpha;
+                (
+                    a * ((a + 1f64) - (a - 1f64) * cos + s),
+                    2f64 * a * ((a - 1f64) - (a + 1f64) * cos),
+                    a * ((a + 1f64) - (a - 1f64) * cos - s),
+                    (a + 1f64) + (a - 1f64) * cos + s,
+                    -2f64 * ((a - 1f64) + (a + 1f64) * cos),
+                    (a + 1f64) + (a - 1f64) * cos - s,
+                )
+            }
+            FilterKind::HighShelf => {
+                let s = 2f64 * a.sqrt() * alpha;
+                (
+                    a * ((a + 1f64) + (a - 1f64) * cos + s),
+                    -2f64 * a * ((a - 1f64) + (a + 1f64) * cos),
+                    a * ((a + 1f64) + (a - 1f64) * cos - s),
+                    (a + 1f64) - (a - 1f64) * cos + s,
+                    2f64 * ((a - 1f64) - (a + 1f64) * cos),
+                    (a + 1f64) - (a - 1f64) * cos - s,
+                )
+            }
+            FilterKind::HighPass => (
+                (1f64 + cos) / 2f64,
+                -(1f64 + cos),
+                (1f64 + cos) / 2f64,
+                1f64 + alpha,
+                -2f64 * cos,
+                1f64 - alpha,
+            ),
+            FilterKind::LowPass => (
+                (1f64 - cos) / 2f64,
+                1f64 - cos,
+                (1f64 - cos) / 2f64,
+                1f64 + alpha,
+                -2f64 * cos,
+                1f64 - alpha,
+            ),
+            FilterKind::Notch => (
+                1f64,
+                -2f64 * cos,
+                1f64,
+                1f64 + alpha,
+                -2f64 * cos,
+                1f64 - alpha,
+            ),
+        };
+        Coeffs {
+            b0: b0 / a0,
+            b1: b1 / a0,
+            b2: b2 / a0,
+            a1: a1 / a0,
+            a2: a2 / a0,
+        }
+    }
+}
+
+/// `type:freq[:gain_db][:q]`, e.g. `peak:1000:-3:1.4`, `lowshelf:200:4` or `highpass:80:0.707`
+impl FromStr for Band {
+    type Err = InvalidBand;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.to_ascii_lowercase();
+        let mut parts = s.split(':');
+        let kind: FilterKind = parts.next().ok_or(InvalidBand::Syntax)?.parse()?;
+        let mut num = |required| match parts.next() {
+            Some(x) => x
+                .trim_end_matches("hz")
+                .trim_end_matches("db")
+                .parse::<f64>()
+                .map(Some)
+                .map_err(|_| InvalidBand::Syntax),
+            None if required => Err(InvalidBand::Syntax),
+            None => Ok(None),
+        };
+        let mut band = Band::new(kind, num(true)?.unwrap());
+        if kind.has_gain()
+            && let Some(gain_db) = num(false)?
+        {
+            band.gain_db = gain_db;
+        }
+        if let Some(q) = num(false)? {
+            band.q = q;
+        }
+        if num(false)?.is_some() {
+            return Err(InvalidBand::Syntax);
+        }
+        if !(band.freq > 0f64 && band.q > 0f64) {
+            return Err(InvalidBand::Range);
+        }
+        Ok(band)
+    }
+}
+
+impl Display for Band {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.kind, self.freq)?;
+        if self.kind.has_gain() {
+            write!(f, ":{}", self.gain_db)?;
+        }
+        write!(f, ":{}", self.q)
+    }
+}
+
+/// Normalised biquad coefficients, `a0` is 1
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Coeffs {
+    pub b0: f64,
+    pub b1: f64,
+    pub b2: f64,
+    pub a1: f64,
+    pub a2: f64,
+}
+
+impl Coeffs {
+    pub const IDENTITY: Coeffs = Coeffs {
+        b0: 1f64,
+        b1: 0f64,
+        b2: 0f64,
+        a1: 0f64,
+        a2: 0f64,
+    };
+
+    /// Gain at `freq` in dB, from the transfer function evaluated on the unit circle
+    pub fn magnitude_db(&self, freq: f64, sample_rate: u32) -> f64 {
+        let w = 2f64 * PI * freq / sample_rate as f64;
+        let (s1, c1) = w.sin_cos();
+        let (s2, c2) = (2f64 * w).sin_cos();
+        let num_re = self.b0 + self.b1 * c1 + self.b2 * c2;
+        let num_im = -(self.b1 * s1 + self.b2 * s2);
+        let den_re = 1f64 + self.a1 * c1 + self.a2 * c2;
+        let den_im = -(self.a1 * s1 + self.a2 * s2);
+        10f64 * ((num_re * num_re + num_im * num_im) / (den_re * den_re + den_im * den_im)).log10()
+    }
+
+    fn approach(&mut self, target: &Coeffs, k: f64) -> bool {
+        let mut settled = true;
+        for (c, t) in [
+            (&mut self.b0, target.b0),
+            (&mut self.b1, target.b1),
+            (&mut self.b2, target.b2),
+            (&mut self.a1, target.a1),
+            (&mut self.a2, target.a2),
+        ] {
+            *c += (t - *c) * k;
+            if (t - *c).abs() < 1e-9 {
+                *c = t;
+            } else {
+                settled = false;
+            }
+        }
+        settled
+    }
+}
+
+/// One band with its target and current coefficients and a transposed direct form II state per
+/// channel
+struct Section {
+    band: Band,
+    target: Coeffs,
+    current: Coeffs,
+    settled: bool,
+    state: Vec<[f64; 2]>,
+}
+
+/// Multi-band parametric EQ, one biquad per band, applied to every channel. Band changes glide
+/// to the new coefficients over a few milliseconds instead of jumping
+pub struct Equalizer {
+    sections: Vec<Section>,
+    channels: usize,
+    sample_rate: u32,
+    // Per-frame smoothing coefficient
+    coeff: f64,
+}
+
+impl Equalizer {
+    const SMOOTHING_SECS: f64 = 0.005;
+
+    pub fn new(bands: Vec<Band>) -> Self {
+        Self {
+            sections: bands
+                .into_iter()
+                .map(|band| Section {
+                    band,
+                    target: Coeffs::IDENTITY,
+                    current: Coeffs::IDENTITY,
+                    settled: true,
+                    state: Vec::new(),
+                })
+                .collect(),
+            channels: 0,
+            sample_rate: 0,
+            coeff: 1f64,
+        }
+    }
+
+    pub fn bands(&self) -> impl Iterator<Item = &Band> {
+        self.sections.iter().map(|x| &x.band)
+    }
+
+    /// Replaces band `i`, the change is smoothed. Does not allocate
+    pub fn set_band(&mut self, i: usize, band: Band) {
+        let section = &mut self.sections[i];
+        section.band = band;
+        if self.sample_rate > 0 {
+            section.target = band.coeffs(self.sample_rate);
+            section.settled = false;
+        }
+    }
+
+    /// Combined gain of every band at `freq` in dB, for the settings the EQ is heading to
+    pub fn magnitude_db(&self, freq: f64, sample_rate: u32) -> f64 {
+        self.sections
+            .iter()
+            .map(|x| x.band.coeffs(sample_rate).magnitude_db(freq, sample_rate))
+            .sum()
+    }
+}
+
+impl Processor for Equalizer {
+    fn prepare(&mut self, channels: usize, sample_rate: u32) {
+        self.channels = channels;
+        self.sample_rate = sample_rate;
+        self.coeff = 1f64 - (-1f64 / (Self::SMOOTHING_SECS * sample_rate as f64)).exp();
+        for section in &mut self.sections {
+            section.target = section.band.coeffs(sample_rate);
+            section.current = section.target;
+            section.settled = true;
+            section.state = vec![[0f64; 2]; channels];
+        }
+    }
+
+    fn process(&mut self, buf: &mut [f32]) {
+        if self.channels == 0 {
+            return;
+        }
+        for section in &mut self.sections {
+            for frame in buf.chunks_exact_mut(self.channels) {
+                if !section.settled {
+                    section.settled = section.current.approach(&section.target, self.coeff);
+                }
+                let c = section.current;
+                for (x, s) in frame.iter_mut().zip(&mut section.state) {
+                    let input = *x as f64;
+                    let y = c.b0 * input + s[0];
+                    s[0] = c.b1 * input - c.a1 * y + s[1];
+                    s[1] = c.b2 * input - c.a2 * y;
+                    *x = y as f32;
+                }
+            }
+        }
+    }
+
+    fn reset(&mut self) {
+        for section in &mut self.sections {
+            section.current = section.target;
+            section.settled = true;
+            section.state.fill([0f64; 2]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f64::consts::PI;
+
+    use super::*;
+
+    const FS: u32 = 48000;
+
+    fn assert_close(a: f64, b: f64, tolerance: f64) {
+        assert!((a - b).abs() < tolerance, "{a} vs {b}");
+    }
+
+    fn magnitude(band: Band, freq: f64) -> f64 {
+        band.coeffs(FS).magnitude_db(freq, FS)
+    }
+
+    fn sine(freq: f64, amplitude: f64, frames: usize) -> Vec<f32> {
+        (0..frames)
+            .map(|i| ((2f64 * PI * freq * i as f64 / FS as f64).sin() * amplitude) as f32)
+            .collect()
+    }
+
+    #[test]
+    fn peak_gain_at_f0() {
+        for gain in [-12f64, -6f64, 3f64, 6f64, 15f64] {
+            let peak = Band::new(FilterKind::Peaking, 1000f64)
+                .gain_db(gain)
+                .q(1f64);
+            assert_close(magnitude(peak, 1000f64), gain, 1e-6);
+            assert_close(magnitude(peak, 20f64), 0f64, 0.02);
+            // Symmetric on a log frequency axis
+            assert_close(magnitude(peak, 500f64), magnitude(peak, 2000f64), 0.05);
+        }
+    }
+
+    #[test]
+    fn shelf_gain() {
+        let low = Band::new(FilterKind::LowShelf, 200f64).gain_db(6f64);
+        assert_close(magnitude(low, 10f64), 6f64, 0.05);
+        // Half the gain at f0
+        assert_close(magnitude(low, 200f64), 3f64, 0.01);
+        assert_close(magnitude(low, 10000f64), 0f64, 0.02);
+
+        let high = Band::new(FilterKind::HighShelf, 4000f64).gain_db(-8f64);
+        assert_close(magnitude(high, 23000f64), -8f64, 0.1);
+        assert_close(magnitude(high, 4000f64), -4f64, 0.01);
+        assert_close(magnitude(high, 50f64), 0f64, 0.01);
+    }
+
+    #[test]
+    fn pass_filters_are_3db_down_at_the_corner() {
+        let high = Band::new(FilterKind::HighPass, 100f64).q(0.707);
+        assert_close(magnitude(high, 100f64), -3.0103, 0.01);
+        // 12 dB per octave
+        assert_close(magnitude(high, 10f64), -40f64, 0.2);
+        assert_close(magnitude(high, 5000f64), 0f64, 0.01);
+
+        let low = Band::new(FilterKind::LowPass, 2000f64).q(0.707);
+        assert_close(magnitude(low, 2000f64), -3.0103, 0.01);
+        assert_close(magnitude(low, 100f64), 0f64, 0.01);
+        assert!(magnitude(low, 8000f64) < -23f64);
+    }
+
+    #[test]
+    fn notch_depth() {
+        let notch = Band::new(FilterKind::Notch, 60f64).q(10f64);
+        assert!(magnitude(notch, 60f64) < -100f64);
+        assert!(magnitude(notch, 58f64) < -3f64);
+        assert_close(magnitude(notch, 1000f64), 0f64, 0.01);
+    }
+
+    #[test]
+    fn processing_matches_the_response() {
+        let mut eq = Equalizer::new(vec![
+            Band::new(FilterKind::HighPass, 80f64),
+            "peak:1000:4:2".parse().unwrap(),
+            "highshelf:8000:-3".parse().unwrap(),
+        ]);
+        eq.prepare(1, FS);
+        for freq in [50f64, 200f64, 1000f64, 3000f64, 12000f64] {
+            eq.reset();
+            let mut buf = sine(freq, 1f64, FS as usize);
+            eq.process(&mut buf);
+            // Past the settling of the filters
+            let tail = &buf[FS as usize / 2..];
+            let rms =
+                (tail.iter().map(|x| (*x as f64).powi(2)).sum::<f64>() / tail.len() as f64).sqrt();
+            let gain = 20f64 * (rms * 2f64.sqrt()).log10();
+            assert_close(gain, eq.magnitude_db(freq, FS), 0.05);
+        }
+    }
+
+    #[test]
+    fn set_band_glides() {
+        let mut eq = Equalizer::new(vec![Band::new(FilterKind::Peaking, 1000f64)]);
+        eq.prepare(1, FS);
+        let input = sine(1000f64, 0.5, 4800);
+        let mut first = input[..2400].to_vec();
+        eq.process(&mut first);
+        eq.set_band(0, Band::new(FilterKind::Peaking, 1000f64).gain_db(12f64));
+        let mut second = input[2400..].to_vec();
+        eq.process(&mut second);
+        let out = [first, second].concat();
+        // A 1kHz sine at amplitude 2 moves at most 0.26 per sample, a jump would be far more
+        let max_step = out
+            .windows(2)
+            .map(|x| (x[1] - x[0]).abs())
+            .fold(0f32, f32::max);
+        assert!(max_step < 0.3, "{max_step}");
+        let peak = out[4000..].iter().fold(0f32, |a, x| a.max(x.abs()));
+        assert!(
+            (peak - 0.5 * 10f32.powf(12f32 / 20f32)).abs() < 0.02,
+            "{peak}"
+        );
+    }
+
+    #[test]
+    fn set_band_converges_without_nan() {
+        // Big jumps across the whole range, every period, before the last one settles
+        let mut eq = Equalizer::new(vec![
+            Band::new(FilterKind::HighPass, 20f64),
+            Band::new(FilterKind::Peaking, 1000f64),
+        ]);
+        eq.prepare(2, FS);
+        let input: Vec<f32> = sine(440f64, 0.5, 48 * 200)
+            .into_iter()
+            .flat_map(|x| [x, -x])
+            .collect();
+        let freqs = [20f64, 23000f64, 100f64, 15000f64];
+        for (i, block) in input.chunks(96).enumerate() {
+            let freq = freqs[i % freqs.len()];
+            eq.set_band(
+                0,
+                Band::new(FilterKind::HighPass, freq).q(0.5 + i as f64 % 10f64),
+            );
+            eq.set_band(
+                1,
+                Band::new(FilterKind::Peaking, freq)
+                    .gain_db(if i % 2 == 0 { 24f64 } else { -24f64 })
+                    .q(0.1 + i as f64 % 7f64),
+            );
+            let mut block = block.to_vec();
+            eq.process(&mut block);
+            assert!(block.iter().all(|x| x.is_finite()), "block {i}");
+        }
+
+        // Then the last settings are reached exactly
+        let target = [
+            Band::new(FilterKind::HighPass, 100f64),
+            Band::new(FilterKind::Peaking, 2000f64).gain_db(6f64),
+        ];
+        for (i, band) in target.into_iter().enumerate() {
+            eq.set_band(i, band);
+        }
+        // 40 time constants
+        let mut buf = vec![0f32; 2 * 9600];
+        eq.process(&mut buf);
+        assert!(buf.iter().all(|x| x.is_finite()));
+        for section in &eq.sections {
+            assert!(section.settled);
+            assert_eq!(section.current, section.target);
+        }
+    }
+
+    #[test]
+    fn parse_band() {
+        let band: Band = "peak:1000:-3:1.4".parse().unwrap();
+        assert_eq!(
+            band,
+            Band::new(FilterKind::Peaking, 1000f64)
+                .gain_db(-3f64)
+                .q(1.4)
+        );
+        assert_eq!(
+            "HP:80Hz".parse::<Band>().unwrap().kind,
+            FilterKind::HighPass
+        );
+        let band: Band = "highpass:80:0.5".parse().unwrap();
+        assert_eq!(band.q, 0.5);
+        assert_eq!(band.to_string().parse::<Band>().unwrap(), band);
+        for invalid in ["highpass:80:0.5:1", "peak", "peak:-5", "x:5"] {
+            assert!(invalid.parse::<Band>().is_err(), "{invalid}");
+        }
+    }
+}
@@ -0,0 +1,386 @@
+use std::fmt::Display;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SampleFormat {
+    F32,
+    I32,
+    /// 24 valid bits in a 32 bit container
+    I24In32,
+    /// Packed 24 bit
+    I24,
+    I16,
+}
+
+impl SampleFormat {
+    /// Best first
+    pub const ALL: [SampleFormat; 5] = [
+        SampleFormat::F32,
+        SampleFormat::I32,
+        SampleFormat::I24In32,
+        SampleFormat::I24,
+        SampleFormat::I16,
+    ];
+
+    /// Container size of one sample
+    pub fn bytes(self) -> usize {
+        match self {
+            SampleFormat::F32 | SampleFormat::I32 | SampleFormat::I24In32 => 4,
+            SampleFormat::I24 => 3,
+            SampleFormat::I16 => 2,
+        }
+    }
+
+    pub fn valid_bits(self) -> u16 {
+        match self {
+            SampleFormat::F32 | SampleFormat::I32 => 32,
+            SampleFormat::I24In32 | SampleFormat::I24 => 24,
+            SampleFormat::I16 => 16,
+        }
+    }
+
+    pub fn is_float(self) -> bool {
+        self == SampleFormat::F32
+    }
+
+    /// Decodes little endian samples from `bytes` into `out`, scaled to `[-1, 1]`. Stops at
+    /// whichever runs out first, returns the number of samples
+    pub fn read_samples(self, bytes: &[u8], out: &mut [f32]) -> usize {
+        let size = self.bytes();
+        let mut n = 0;
+        for (chunk, out) in bytes.chunks_exact(size).zip(out.iter_mut()) {
+            *out = match self {
+                SampleFormat::F32 => f32::from_le_bytes(chunk.try_into().unwrap()),
+                SampleFormat::I32 | SampleFormat::I24In32 => {
+                    i32::from_le_bytes(chunk.try_into().unwrap()) as f32 / I32_SCALE
+                }
+                SampleFormat::I24 => {
+                    i32::from_le_bytes([0, chunk[0], chunk[1], chunk[2]]) as f32 / I32_SCALE
+                }
+                SampleFormat::I16 => {
+                    i16::from_le_bytes(chunk.try_into().unwrap()) as f32 / I16_SCALE
+                }
+            };
+            n += 1;
+        }
+        n
+    }
+
+    /// Encodes `samples` into `out` as little endian, clipping to `[-1, 1]`. Stops at whichever
+    /// runs out first, returns the number of samples
+    pub fn write_samples(self, samples: &[f32], out: &mut [u8]) -> usize {
+        let size = self.bytes();
+        let mut n = 0;
+        for (x, chunk) in samples.iter().zip(out.chunks_exact_mut(size)) {
+            let x = x.clamp(-1f32, 1f32);
+            match self {
+                SampleFormat::F32 => chunk.copy_from_slice(&x.to_le_bytes()),
+                SampleFormat::I32 => chunk
+                    .copy_from_slice(&((x as f64 * i32::MAX as f64).round() as i32).to_le_bytes()),
+                SampleFormat::I24In32 => {
+                    let v = (x * I24_MAX).round() as i32;
+                    chunk.copy_from_slice(&(v << 8).to_le_bytes());
+                }
+                SampleFormat::I24 => {
+                    let v = (x * I24_MAX).round() as i32;
+                    chunk.copy_from_slice(&v.to_le_bytes()[..3]);
+                }
+                SampleFormat::I16 => {
+                    chunk.copy_from_slice(&((x * i16::MAX as f32).round() as i16).to_le_bytes())
+                }
+            }
+            n += 1;
+        }
+        n
+    }
+}
+
+const I32_SCALE: f32 = 2147483648f32;
+const I16_SCALE: f32 = 32768f32;
+const I24_MAX: f32 = 8388607f32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct FormatSpec {
+    pub sample_format: SampleFormat,
+    pub channels: u16,
+    pub sample_rate: u32,
+}
+
+impl FormatSpec {
+    pub fn block_align(&self) -> usize {
+        self.sample_format.bytes() * self.channels as usize
+    }
+
+    /// Interleaved 32 bit float, the format processing happens in
+    pub fn float(channels: u16, sample_rate: u32) -> Self {
+        Self {
+            sample_format: SampleFormat::F32,
+            channels,
+            sample_rate,
+        }
+    }
+}
+
+impl Display for FormatSpec {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{:?} {}ch {}Hz",
+            self.sample_format, self.channels, self.sample_rate
+        )
+    }
+}
+
+/// Re-encodes interleaved frames from one sample format into another of the same channel count,
+/// e.g. a shared mode float capture for a 16 bit exclusive output. Allocates once, up front
+pub struct SampleConverter {
+    from: FormatSpec,
+    to: FormatSpec,
+    samples: Vec<f32>,
+    bytes: Vec<u8>,
+}
+
+impl SampleConverter {
+    /// `None` unless only the sample format differs
+    pub fn new(from: FormatSpec, to: FormatSpec, max_frames: usize) -> Option<Self> {
+        if from.channels != to.channels || from.sample_rate != to.sample_rate {
+            return None;
+        }
+        Some(Self {
+            from,
+            to,
+            samples: vec![0f32; max_frames * from.channels as usize],
+            bytes: vec![0; max_frames * to.block_align()],
+        })
+    }
+
+    pub fn from(&self) -> FormatSpec {
+        self.from
+    }
+
+    pub fn to(&self) -> FormatSpec {
+        self.to
+    }
+
+    /// Frames converted per call
+    pub fn max_frames(&self) -> usize {
+        self.bytes.len() / self.to.block_align()
+    }
+
+    /// Converts up to [`SampleConverter::max_frames`] whole frames of `data`
+    pub fn convert(&mut self, data: &[u8]) -> &[u8] {
+        let frames = (data.len() / self.from.block_align()).min(self.max_frames());
+        let samples = &mut self.samples[..frames * self.from.channels as usize];
+        self.from.sample_format.read_samples(data, samples);
+        let bytes = &mut self.bytes[..frames * self.to.block_align()];
+        self.to.sample_format.write_samples(samples, bytes);
+        bytes
+    }
+}
+
+/// Makes interleaved float frames in one format, e.g. a [`crate::mixer::Mixer`] or a
+/// [`crate::generator::Generator`]
+pub trait FrameSource {
+    fn spec(&self) -> FormatSpec;
+
+    /// Fills `out` with whole frames
+    fn fill(&mut self, out: &mut [f32]);
+}
+
+/// Encodes what a [`FrameSource`] makes into an endpoint buffer in the same format, through float
+/// samples allocated once
+pub struct SampleWriter {
+    spec: FormatSpec,
+    samples: Vec<f32>,
+}
+
+impl SampleWriter {
+    pub fn new(spec: FormatSpec, max_frames: usize) -> Self {
+        Self {
+            spec,
+            samples: vec![0f32; max_frames * spec.channels as usize],
+        }
+    }
+
+    /// Frames written per call
+    pub fn max_frames(&self) -> usize {
+        self.samples.len() / self.spec.channels as usize
+    }
+
+    /// Fills `out` with whole frames of `source`, up to [`SampleWriter::max_frames`], returns the
+    /// number of frames
+    pub fn render(&mut self, source: &mut impl FrameSource, out: &mut [u8]) -> usize {
+        let frames = (out.len() / self.spec.block_align()).min(self.max_frames());
+        let samples = &mut self.samples[..frames * self.spec.channels as usize];
+        source.fill(samples);
+        self.spec.sample_format.write_samples(samples, out);
+        frames
+    }
+}
+
+/// Rates tried after the preferred one, most common first
+pub const COMMON_RATES: [u32; 5] = [48000, 44100, 96000, 88200, 192000];
+
+/// Exclusive mode formats to try with `IsFormatSupported`, best first. Keeping the channel count
+/// matters most, then the rate, then the sample format, so the preferred format comes first,
+/// followed by other sample formats at the same rate
+pub fn rank_candidates(preferred: FormatSpec) -> Vec<FormatSpec> {
+    let mut channels = vec![preferred.channels];
+    channels.extend([2, 1].into_iter().filter(|x| *x != preferred.channels));
+
+    let mut rates = vec![preferred.sample_rate];
+    rates.extend(
+        COMMON_RATES
+            .into_iter()
+            .filter(|x| *x != preferred.sample_rate),
+    );
+
+    let mut formats = vec![preferred.sample_format];
+    formats.extend(
+        SampleFormat::ALL
+            .into_iter()
+            .filter(|x| *x != preferred.sample_format),
+    );
+
+    let mut candidates = Vec::with_capacity(channels.len() * rates.len() * formats.len());
+    for &channels in &channels {
+        for &sample_rate in &rates {
+            for &sample_format in &formats {
+                candidates.push(FormatSpec {
+                    sample_format,
+                    channels,
+                    sample_rate,
+                });
+            }
+        }
+    }
+    candidates
+}
+
+/// `dwChannelMask` for the usual layouts of a channel count
+pub fn default_channel_mask(channels: u16) -> u32 {
+    match channels {
+        1 => 0x4,
+        2 => 0x3,
+        4 => 0x33,
+        6 => 0x3F,
+        8 => 0x63F,
+        n => (1u32 << n.min(31)) - 1,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PREFERRED: FormatSpec = FormatSpec {
+        sample_format: SampleFormat::I16,
+        channels: 2,
+        sample_rate: 44100,
+    };
+
+    #[test]
+    fn preferred_format_ranks_first() {
+        let candidates = rank_candidates(PREFERRED);
+        assert_eq!(candidates[0], PREFERRED);
+        // Other sample formats at the same rate and channel count follow, best first
+        let formats: Vec<_> = candidates[1..5].iter().map(|x| x.sample_format).collect();
+        assert_eq!(
+            formats,
+            [
+                SampleFormat::F32,
+                SampleFormat::I32,
+                SampleFormat::I24In32,
+                SampleFormat::I24
+            ]
+        );
+        assert!(
+            candidates[..5]
+                .iter()
+                .all(|x| x.channels == 2 && x.sample_rate == 44100)
+        );
+        // Then the most common rate, still in the preferred sample format
+        assert_eq!(
+            candidates[5],
+            FormatSpec {
+                sample_rate: 48000,
+                ..PREFERRED
+            }
+        );
+    }
+
+    #[test]
+    fn channels_change_last() {
+        let candidates = rank_candidates(PREFERRED);
+        assert_eq!(candidates.len(), 2 * 5 * 5);
+        let first_mono = candidates.iter().position(|x| x.channels == 1).unwrap();
+        assert_eq!(first_mono, 25);
+        assert!(candidates[..25].iter().all(|x| x.channels == 2));
+
+        let mut unique = candidates.clone();
+        unique.sort_by_key(|x| (x.channels, x.sample_rate, x.sample_format as u8));
+        unique.dedup();
+        assert_eq!(unique.len(), candidates.len());
+
+        let surround = rank_candidates(FormatSpec {
+            channels: 6,
+            ..PREFERRED
+        });
+        assert_eq!(surround.len(), 3 * 5 * 5);
+        let channels: Vec<_> = surround.iter().step_by(25).map(|x| x.channels).collect();
+        assert_eq!(channels, [6, 2, 1]);
+    }
+
+    #[test]
+    fn uncommon_rate_is_kept() {
+        let candidates = rank_candidates(FormatSpec {
+            sample_rate: 32000,
+            ..PREFERRED
+        });
+        assert_eq!(candidates.len(), 2 * 6 * 5);
+        assert_eq!(candidates[5].sample_rate, 48000);
+    }
+
+    #[test]
+    fn round_trip_every_format() {
+        let samples = [0f32, 0.5, -0.5, 0.999, -1f32];
+        for format in SampleFormat::ALL {
+            let mut bytes = vec![0; samples.len() * format.bytes()];
+            assert_eq!(format.write_samples(&samples, &mut bytes), samples.len());
+            let mut decoded = [0f32; 5];
+            assert_eq!(format.read_samples(&bytes, &mut decoded), samples.len());
+            for (a, b) in samples.iter().zip(decoded) {
+                assert!((a - b).abs() < 1e-4, "{format:?}: {a} != {b}");
+            }
+        }
+    }
+
+    #[test]
+    fn converter() {
+        let from = FormatSpec::float(2, 48000);
+        let to = FormatSpec {
+            sample_format: SampleFormat::I16,
+            ..from
+        };
+        assert!(SampleConverter::new(from, FormatSpec::float(1, 48000), 4).is_none());
+        assert!(SampleConverter::new(from, FormatSpec::float(2, 44100), 4).is_none());
+
+        let mut converter = SampleConverter::new(from, to, 4).unwrap();
+        assert_eq!(converter.max_frames(), 4);
+        let samples = [0.5f32, -0.5, 1f32, -1f32, 0f32, 0.25];
+        let mut input = vec![0; samples.len() * 4];
+        from.sample_format.write_samples(&samples, &mut input);
+        // A trailing partial frame is ignored
+        input.push(0);
+        let output = converter.convert(&input);
+        assert_eq!(output.len(), 3 * to.block_align());
+        let expected: Vec<u8> = [16384i16, -16384, 32767, -32767, 0, 8192]
+            .iter()
+            .flat_map(|x| x.to_le_bytes())
+            .collect();
+        assert_eq!(output, expected);
+
+        // Never more than `max_frames` at once
+        let long = vec![0; 10 * from.block_align()];
+        assert_eq!(converter.convert(&long).len(), 4 * to.block_align());
+    }
+}
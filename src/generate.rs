@@ -0,0 +1,89 @@
+use anyhow::{Result, bail};
+use tracing::info;
+use windows::Win32::{
+    Foundation::HANDLE, Media::Audio::IAudioClient, System::Threading::CreateEventW,
+};
+
+use crate::{
+    engine::StreamConfig,
+    generator::{Generator, Signal},
+    pipe::{ClientSource, InitInfo, init_client},
+    processor::{Chain, Pipeline},
+    utils::WaveFormat,
+    wasapi_stream::source_loop,
+};
+
+/// A [`Generator`] in place of a capture, rendered in whatever format the render client settled
+/// on and through the same processing stage as a captured pipe
+pub struct GeneratorPipe {
+    generator: Generator,
+    pipeline: Option<Pipeline>,
+    render_client: IAudioClient,
+    render_info: InitInfo,
+    ev: HANDLE,
+}
+
+impl GeneratorPipe {
+    pub fn open(
+        signal: Signal,
+        level_db: f64,
+        render: ClientSource,
+        render_config: StreamConfig,
+        wfx: WaveFormat,
+    ) -> Result<Self> {
+        unsafe {
+            let ev = CreateEventW(None, false, false, None)?;
+            info!(
+                share_mode = %render_config.share_mode,
+                kind = %render_config.kind,
+                "initialising output"
+            );
+            let (render_client, render_info) = init_client(&render, Some(wfx), ev, &render_config)?;
+            let Some(spec) = render_info.wfx.spec() else {
+                bail!("cannot generate {:?}", render_info.wfx);
+            };
+            info!(%signal, level_db, %spec, "generating");
+            let generator = Generator::new(signal, level_db, spec);
+            Ok(Self {
+                generator,
+                pipeline: None,
+                render_client,
+                render_info,
+                ev,
+            })
+        }
+    }
+
+    pub fn render_info(&self) -> &InitInfo {
+        &self.render_info
+    }
+
+    /// Runs on the generated signal before it is rendered
+    pub fn set_processors(&mut self, processors: Chain) {
+        if processors.is_empty() {
+            self.pipeline = None;
+            return;
+        }
+        let spec = self.generator.spec();
+        let pipeline = Pipeline::new(processors, spec, self.render_info.buf_size as usize);
+        info!(
+            latency_ms = pipeline.latency() as f64 * 1000f64 / spec.sample_rate as f64,
+            "processing stage ready"
+        );
+        self.pipeline = Some(pipeline);
+    }
+
+    pub fn run(&mut self) -> Result<()> {
+        source_loop(
+            &self.render_client,
+            self.ev,
+            &self.render_info,
+            &mut self.generator,
+            |block| {
+                if let Some(pipeline) = &mut self.pipeline {
+                    pipeline.process(block);
+                }
+            },
+        )
+    }
+}
@@ -0,0 +1,451 @@
+use std::{f64::consts::TAU, fmt::Display, str::FromStr};
+
+use thiserror::Error;
+
+use crate::format::{FormatSpec, FrameSource};
+
+/// What a [`Generator`] plays
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Signal {
+    Sine {
+        freq: f64,
+    },
+    /// Exponential sweep from `from` to `to` Hz over `secs`, then again
+    Sweep {
+        from: f64,
+        to: f64,
+        secs: f64,
+    },
+    WhiteNoise,
+    /// -3 dB per octave
+    PinkNoise,
+    /// One full-level sample every `secs`
+    Impulse {
+        secs: f64,
+    },
+    /// Each channel in turn plays its own tone, `IDENT_BASE_HZ` times its number (from 1)
+    Ident,
+}
+
+/// Tone of channel 1 in [`Signal::Ident`], channel `n` plays `n` times this
+pub const IDENT_BASE_HZ: f64 = 500f64;
+const IDENT_SLOT_SECS: f64 = 1f64;
+const IDENT_TONE_SECS: f64 = 0.8f64;
+
+#[derive(Debug, Error)]
+#[error(
+    "invalid signal, expected sine[:hz], sweep[:from:to:secs], white, pink, impulse[:secs] or ident"
+)]
+pub struct InvalidSignal;
+
+impl FromStr for Signal {
+    type Err = InvalidSignal;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.to_ascii_lowercase();
+        let mut parts = s.split(':');
+        let name = parts.next().unwrap_or_default();
+        let args = parts
+            .map(|x| x.parse::<f64>().ok().filter(|x| *x > 0f64 && x.is_finite()))
+            .collect::<Option<Vec<_>>>()
+            .ok_or(InvalidSignal)?;
+        let signal = match (name, args.as_slice()) {
+            ("sine", []) => Signal::Sine { freq: 1000f64 },
+            ("sine", &[freq]) => Signal::Sine { freq },
+            ("sweep", []) => Signal::Sweep {
+                from: 20f64,
+                to: 20000f64,
+                secs: 10f64,
+            },
+            ("sweep", &[from, to, secs]) => Signal::Sweep { from, to, secs },
+            ("white", []) => Signal::WhiteNoise,
+            ("pink", []) => Signal::PinkNoise,
+            ("impulse", []) => Signal::Impulse { secs: 1f64 },
+            ("impulse", &[secs]) => Signal::Impulse { secs },
+            ("ident", []) => Signal::Ident,
+            _ => return Err(InvalidSignal),
+        };
+        Ok(signal)
+    }
+}
+
+impl Display for Signal {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Signal::Sine { freq } => write!(f, "sine:{freq}"),
+            Signal::Sweep { from, to, secs } => write!(f, "sweep:{from}:{to}:{secs}"),
+            Signal::WhiteNoise => write!(f, "white"),
+            Signal::PinkNoise => write!(f, "pink"),
+            Signal::Impulse { secs } => write!(f, "impulse:{secs}"),
+            Signal::Ident => write!(f, "ident"),
+        }
+    }
+}
+
+/// xorshift64*, plenty for test noise and no allocation
+#[derive(Debug, Clone, Copy)]
+struct Noise(u64);
+
+impl Noise {
+    /// Uniform in `[-1, 1)`
+    fn next(&mut self) -> f64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        let x = self.0.wrapping_mul(0x2545_f491_4f6c_dd1d);
+        (x >> 11) as f64 / (1u64 << 52) as f64 - 1f64
+    }
+}
+
+/// Paul Kellet's refined pink filter, within 0.05 dB of -3 dB/octave above 10 Hz
+#[derive(Debug, Clone, Copy, Default)]
+struct Pink([f64; 7]);
+
+impl Pink {
+    // Brings the filter's output back to the RMS of its white input
+    const GAIN: f64 = 0.3307;
+
+    fn next(&mut self, white: f64) -> f64 {
+        let b = &mut self.0;
+        b[0] = 0.99886 * b[0] + white * 0.0555179;
+        b[1] = 0.99332 * b[1] + white * 0.0750759;
+        b[2] = 0.96900 * b[2] + white * 0.1538520;
+        b[3] = 0.86650 * b[3] + white * 0.3104856;
+        b[4] = 0.55000 * b[4] + white * 0.5329522;
+        b[5] = -0.7616 * b[5] - white * 0.0168980;
+        let pink = b[0] + b[1] + b[2] + b[3] + b[4] + b[5] + b[6] + white * 0.5362;
+        b[6] = white * 0.115926;
+        pink * Self::GAIN
+    }
+}
+
+/// Test signal source in any output format. `level_db` is the peak of tones and impulses; noise
+/// has the RMS of a tone at that level, so both read the same on a meter
+pub struct Generator {
+    signal: Signal,
+    spec: FormatSpec,
+    amplitude: f64,
+    // Frames generated so far
+    frame: u64,
+    phase: f64,
+    noise: Vec<Noise>,
+    pink: Vec<Pink>,
+}
+
+impl Generator {
+    pub fn new(signal: Signal, level_db: f64, spec: FormatSpec) -> Self {
+        let channels = spec.channels as usize;
+        Self {
+            signal,
+            spec,
+            amplitude: 10f64.powf(level_db / 20f64),
+            frame: 0,
+            phase: 0f64,
+            noise: (0..channels)
+                .map(|i| Noise(0x9e37_79b9_7f4a_7c15 ^ (i as u64 + 1).wrapping_mul(0xbf58_476d)))
+                .collect(),
+            pink: vec![Pink::default(); channels],
+        }
+    }
+
+    pub fn signal(&self) -> Signal {
+        self.signal
+    }
+
+    pub fn spec(&self) -> FormatSpec {
+        self.spec
+    }
+}
+
+impl FrameSource for Generator {
+    fn spec(&self) -> FormatSpec {
+        self.spec
+    }
+
+    fn fill(&mut self, out: &mut [f32]) {
+        let channels = self.spec.channels as usize;
+        let rate = self.spec.sample_rate as f64;
+        let a = self.amplitude;
+        // Uniform noise has an RMS of 1/sqrt(3), a tone 1/sqrt(2)
+        let noise_gain = a * (3f64 / 2f64).sqrt();
+
+        for frame in out.chunks_exact_mut(channels) {
+            let t = self.frame as f64 / rate;
+            match self.signal {
+                Signal::Sine { freq } => {
+                    frame.fill((a * self.phase.sin()) as f32);
+                    self.phase = (self.phase + TAU * freq / rate) % TAU;
+                }
+                Signal::Sweep { from, to, secs } => {
+                    let period = ((secs * rate).round() as u64).max(1);
+                    let n = self.frame % period;
+                    if n == 0 {
+                        self.phase = 0f64;
+                    }
+                    frame.fill((a * self.phase.sin()) as f32);
+                    let freq = from * (to / from).powf(n as f64 / period as f64);
+                    self.phase = (self.phase + TAU * freq / rate) % TAU;
+                }
+                Signal::WhiteNoise => {
+                    for (x, noise) in frame.iter_mut().zip(&mut self.noise) {
+                        *x = (noise_gain * noise.next()) as f32;
+                    }
+                }
+                Signal::PinkNoise => {
+                    for ((x, noise), pink) in
+                        frame.iter_mut().zip(&mut self.noise).zip(&mut self.pink)
+                    {
+                        *x = (noise_gain * pink.next(noise.next())) as f32;
+                    }
+                }
+                Signal::Impulse { secs } => {
+                    let period = ((secs * rate).round() as u64).max(1);
+                    let x = if self.frame.is_multiple_of(period) {
+                        a
+                    } else {
+                        0f64
+                    };
+                    frame.fill(x as f32);
+                }
+                Signal::Ident => {
+                    let slot = (t / IDENT_SLOT_SECS) as usize % channels;
+                    let in_slot = t % IDENT_SLOT_SECS;
+                    frame.fill(0f32);
+                    if in_slot < IDENT_TONE_SECS {
+                        let freq = IDENT_BASE_HZ * (slot + 1) as f64;
+                        frame[slot] = (a * (TAU * freq * in_slot).sin()) as f32;
+                    }
+                }
+            }
+            self.frame += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::{SampleFormat, SampleWriter};
+
+    const FS: u32 = 48000;
+
+    fn generate(signal: Signal, level_db: f64, channels: u16, frames: usize) -> Vec<f32> {
+        let mut generator = Generator::new(signal, level_db, FormatSpec::float(channels, FS));
+        let mut out = vec![0f32; frames * channels as usize];
+        for block in out.chunks_mut(480 * channels as usize) {
+            generator.fill(block);
+        }
+        out
+    }
+
+    fn channel(x: &[f32], channels: usize, c: usize) -> Vec<f32> {
+        x.iter().skip(c).step_by(channels).copied().collect()
+    }
+
+    fn rms(x: &[f32]) -> f64 {
+        (x.iter().map(|x| (*x as f64).powi(2)).sum::<f64>() / x.len() as f64).sqrt()
+    }
+
+    fn peak(x: &[f32]) -> f64 {
+        x.iter().fold(0f32, |a, x| a.max(x.abs())) as f64
+    }
+
+    // From the rising zero crossings, interpolated between samples
+    fn frequency(x: &[f32]) -> f64 {
+        let crossings: Vec<f64> = x
+            .windows(2)
+            .enumerate()
+            .filter(|(_, w)| w[0] < 0f32 && w[1] >= 0f32)
+            .map(|(i, w)| i as f64 + w[0] as f64 / (w[0] - w[1]) as f64)
+            .collect();
+        let span = crossings[crossings.len() - 1] - crossings[0];
+        (crossings.len() - 1) as f64 / span * FS as f64
+    }
+
+    // Power spectral density around `freq`, averaged over Hann windowed segments and a few bins
+    fn density(x: &[f32], freq: f64) -> f64 {
+        const N: usize = 4096;
+        let window: Vec<f64> = (0..N)
+            .map(|n| 0.5 - 0.5 * (TAU * n as f64 / N as f64).cos())
+            .collect();
+        let center = (freq * N as f64 / FS as f64).round() as usize;
+        let mut sum = 0f64;
+        let mut count = 0;
+        for segment in x.chunks_exact(N) {
+            for k in center - 2..=center + 2 {
+                // Goertzel
+                let coeff = 2f64 * (TAU * k as f64 / N as f64).cos();
+                let (mut s1, mut s2) = (0f64, 0f64);
+                for (x, w) in segment.iter().zip(&window) {
+                    let s0 = *x as f64 * w + coeff * s1 - s2;
+                    s2 = s1;
+                    s1 = s0;
+                }
+                sum += s1 * s1 + s2 * s2 - coeff * s1 * s2;
+                count += 1;
+            }
+        }
+        sum / count as f64
+    }
+
+    #[test]
+    fn parse() {
+        for s in [
+            "sine:997",
+            "sweep:20:20000:10",
+            "white",
+            "pink",
+            "impulse:0.5",
+            "ident",
+        ] {
+            assert_eq!(s.parse::<Signal>().unwrap().to_string(), s);
+        }
+        assert_eq!(
+            "sine".parse::<Signal>().unwrap(),
+            Signal::Sine { freq: 1000f64 }
+        );
+        assert!("sine:-1".parse::<Signal>().is_err());
+        assert!("sweep:20".parse::<Signal>().is_err());
+        assert!("square".parse::<Signal>().is_err());
+    }
+
+    #[test]
+    fn sine_frequency_and_level() {
+        let x = generate(Signal::Sine { freq: 997f64 }, -6f64, 2, FS as usize);
+        let left = channel(&x, 2, 0);
+        assert_eq!(left, channel(&x, 2, 1));
+        assert!(
+            (frequency(&left) - 997f64).abs() < 0.01,
+            "{}",
+            frequency(&left)
+        );
+        assert!((20f64 * peak(&left).log10() + 6f64).abs() < 0.01);
+        let tone_rms = 10f64.powf(-6f64 / 20f64) / 2f64.sqrt();
+        assert!((rms(&left) - tone_rms).abs() < 1e-3, "{}", rms(&left));
+    }
+
+    #[test]
+    fn noise_reads_as_loud_as_a_tone() {
+        let tone = rms(&generate(
+            Signal::Sine { freq: 1000f64 },
+            -18f64,
+            1,
+            FS as usize,
+        ));
+        for signal in [Signal::WhiteNoise, Signal::PinkNoise] {
+            let x = generate(signal, -18f64, 2, 10 * FS as usize);
+            for c in 0..2 {
+                let db = 20f64 * (rms(&channel(&x, 2, c)) / tone).log10();
+                assert!(db.abs() < 0.5, "{signal} {c}: {db}");
+            }
+            // Every channel has its own noise
+            assert_ne!(channel(&x, 2, 0), channel(&x, 2, 1));
+        }
+    }
+
+    #[test]
+    fn sweep_start_end_and_restart() {
+        let (from, to) = (200f64, 2000f64);
+        let period = FS as usize;
+        let x = generate(
+            Signal::Sweep {
+                from,
+                to,
+                secs: 1f64,
+            },
+            -6f64,
+            1,
+            2 * period,
+        );
+        // The mean of the exponential sweep's frequency over frames `n0..n1`
+        let mean = |n0: usize, n1: usize| {
+            let k = (to / from).ln();
+            let (t0, t1) = (n0 as f64 / period as f64, n1 as f64 / period as f64);
+            from * ((k * t1).exp() - (k * t0).exp()) / (k * (t1 - t0))
+        };
+        let edge = period / 20;
+        for (n0, n1) in [(0, edge), (period - edge, period)] {
+            let f = frequency(&x[n0..n1]);
+            assert!((f / mean(n0, n1) - 1f64).abs() < 0.02, "{n0}: {f}");
+        }
+        assert!(frequency(&x[..edge]) < 1.1 * from);
+        assert!(frequency(&x[period - edge..period]) > 0.9 * to);
+        // Every period starts over from `from` at phase zero
+        assert_eq!(x[0], 0f32);
+        assert_eq!(x[period..], x[..period]);
+    }
+
+    #[test]
+    fn pink_noise_falls_3db_per_octave() {
+        let octaves = [125f64, 250f64, 500f64, 1000f64, 2000f64, 4000f64, 8000f64];
+        for (signal, slope) in [(Signal::WhiteNoise, 0f64), (Signal::PinkNoise, -3.01)] {
+            let x = generate(signal, -18f64, 1, 10 * FS as usize);
+            let db: Vec<f64> = octaves
+                .iter()
+                .map(|f| 10f64 * density(&x, *f).log10())
+                .collect();
+            for (f, w) in octaves.iter().zip(db.windows(2)) {
+                let step = w[1] - w[0];
+                assert!((step - slope).abs() < 0.75, "{signal} {f}: {step}");
+            }
+            let total = db[db.len() - 1] - db[0];
+            let expected = slope * (octaves.len() - 1) as f64;
+            assert!((total - expected).abs() < 1f64, "{signal}: {total}");
+        }
+    }
+
+    #[test]
+    fn impulse_spacing() {
+        let x = generate(Signal::Impulse { secs: 0.25 }, 0f64, 1, FS as usize);
+        let at: Vec<usize> = (0..x.len()).filter(|i| x[*i] != 0f32).collect();
+        assert_eq!(at, [0, 12000, 24000, 36000]);
+        assert!(at.iter().all(|i| x[*i] == 1f32));
+    }
+
+    #[test]
+    fn ident_channel_and_slot() {
+        let channels = 3;
+        // Two rounds over every channel
+        let frames = 6 * FS as usize;
+        let x = generate(Signal::Ident, -6f64, channels as u16, frames);
+        let slot_frames = (IDENT_SLOT_SECS * FS as f64) as usize;
+        let tone_frames = (IDENT_TONE_SECS * FS as f64) as usize;
+        for slot in 0..6 {
+            let playing = slot % channels;
+            for c in 0..channels {
+                let ch = channel(&x, channels, c);
+                let slot = &ch[slot * slot_frames..(slot + 1) * slot_frames];
+                if c != playing {
+                    assert!(slot.iter().all(|x| *x == 0f32));
+                    continue;
+                }
+                let freq = IDENT_BASE_HZ * (c + 1) as f64;
+                let tone = &slot[..tone_frames];
+                assert!(
+                    (frequency(tone) - freq).abs() < 0.1,
+                    "{c}: {}",
+                    frequency(tone)
+                );
+                assert!((20f64 * peak(tone).log10() + 6f64).abs() < 0.01);
+                // A gap before the next channel, the edge frame may round either way
+                assert!(slot[tone_frames + 1..].iter().all(|x| *x == 0f32));
+            }
+        }
+    }
+
+    #[test]
+    fn writer_encodes_the_output_format() {
+        let spec = FormatSpec {
+            sample_format: SampleFormat::I16,
+            channels: 2,
+            sample_rate: FS,
+        };
+        let mut generator = Generator::new(Signal::Impulse { secs: 1f64 }, -6.0206, spec);
+        let mut writer = SampleWriter::new(spec, 480);
+        // More room than the writer takes
+        let mut bytes = vec![0; 960 * spec.block_align()];
+        assert_eq!(writer.render(&mut generator, &mut bytes), 480);
+        assert_eq!(i16::from_le_bytes([bytes[0], bytes[1]]), 16384);
+        assert_eq!(i16::from_le_bytes([bytes[4], bytes[5]]), 0);
+    }
+}
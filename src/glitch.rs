@@ -0,0 +1,514 @@
+use std::{fmt::Display, time::Duration};
+
+use crate::broadcast::BroadcastReader;
+
+/// What went wrong in a pipe
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GlitchKind {
+    /// The ring ran dry with less than a period left in the render buffer, the engine is about
+    /// to fill in silence
+    Underrun,
+    /// Frames thrown away because a ring was full or held too much
+    Overrun { frames: usize },
+    /// The capture engine flagged a gap in its data
+    Discontinuity,
+    /// A sample jumped away from where its neighbours were heading
+    Jump(Jump),
+}
+
+impl Display for GlitchKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GlitchKind::Underrun => write!(f, "underrun"),
+            GlitchKind::Overrun { frames } => write!(f, "overrun, {frames} frames lost"),
+            GlitchKind::Discontinuity => write!(f, "discontinuity"),
+            GlitchKind::Jump(jump) => write!(
+                f,
+                "jump of {:.3} on channel {}, frame {} of the packet",
+                jump.size, jump.channel, jump.frame
+            ),
+        }
+    }
+}
+
+/// A glitch with what the pipe looked like when it happened
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Glitch {
+    pub kind: GlitchKind,
+    /// Since the pipe started
+    pub at: Duration,
+    /// Output the glitch was seen on, `None` for the capture side
+    pub output: Option<usize>,
+    /// Frames queued in the ring, and how many it holds
+    pub fill: usize,
+    pub capacity: usize,
+    /// Engine period of the side it was seen on, in frames
+    pub period: u32,
+}
+
+impl Display for Glitch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:.3}s ", self.at.as_secs_f64())?;
+        match self.output {
+            Some(i) => write!(f, "output {i}")?,
+            None => write!(f, "capture")?,
+        }
+        write!(
+            f,
+            ": {}, ring {}/{} frames, period {}",
+            self.kind, self.fill, self.capacity, self.period
+        )
+    }
+}
+
+/// Largest jump found in a block
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Jump {
+    pub frame: usize,
+    pub channel: usize,
+    /// Distance from the straight line through the two samples before
+    pub size: f32,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct JumpChannel {
+    prev: [f32; 2],
+    // Average distance from the prediction
+    level: f32,
+    // Frames left before anything is reported again
+    hold: usize,
+}
+
+/// Finds clicks: a sample far from the linear prediction of the two before it, far beyond what
+/// the signal usually does. Tones and noise follow their own average, a splice in the middle of
+/// either does not
+pub struct JumpDetector {
+    channels: usize,
+    state: Vec<JumpChannel>,
+    coeff: f32,
+    hold_frames: usize,
+}
+
+impl JumpDetector {
+    /// Times the recent average a sample has to be off to count
+    const RATIO: f32 = 8f32;
+    /// Smallest jump reported, quiet signals can be far off their average without being heard
+    const MIN_JUMP: f32 = 0.1;
+    const AVERAGE_MS: f32 = 10f32;
+    /// Quiet time after a jump, and after the start
+    const HOLD_MS: f32 = 20f32;
+
+    pub fn new(channels: usize, sample_rate: u32) -> Self {
+        let hold_frames = (Self::HOLD_MS * sample_rate as f32 / 1000f32) as usize;
+        Self {
+            channels,
+            state: vec![
+                JumpChannel {
+                    hold: hold_frames,
+                    ..Default::default()
+                };
+                channels
+            ],
+            coeff: 1f32 - (-1f32 / (Self::AVERAGE_MS * sample_rate as f32 / 1000f32)).exp(),
+            hold_frames,
+        }
+    }
+
+    /// Scans interleaved `samples`, returns the largest jump if there was any
+    pub fn scan(&mut self, samples: &[f32]) -> Option<Jump> {
+        let mut largest: Option<Jump> = None;
+        for (frame, x) in samples.chunks_exact(self.channels).enumerate() {
+            for (channel, (&x, state)) in x.iter().zip(&mut self.state).enumerate() {
+                let [a, b] = state.prev;
+                let size = (x - (2f32 * b - a)).abs();
+                if state.hold > 0 {
+                    state.hold -= 1;
+                } else if size > Self::MIN_JUMP && size > Self::RATIO * state.level {
+                    state.hold = self.hold_frames;
+                    if largest.is_none_or(|x| size > x.size) {
+                        largest = Some(Jump {
+                            frame,
+                            channel,
+                            size,
+                        });
+                    }
+                }
+                state.level += (size - state.level) * self.coeff;
+                state.prev = [b, x];
+            }
+        }
+        largest
+    }
+
+    pub fn reset(&mut self) {
+        self.state.fill(JumpChannel {
+            hold: self.hold_frames,
+            ..Default::default()
+        });
+    }
+}
+
+/// Overrun and underrun decisions for one output of a pipe, kept apart from the endpoint so they
+/// can be driven by anything that feeds a [`BroadcastReader`]
+#[derive(Debug, Clone)]
+pub struct OutputMonitor {
+    // Anything queued beyond this is dropped, so a stall does not add latency for good
+    max_latency: usize,
+    // Endpoint period, an endpoint holding less than this is about to run dry
+    period: usize,
+    // Frames the broadcast dropped for this output, as of the last capture
+    dropped: usize,
+    // Set once audio was written, an empty buffer before that is no underrun
+    started: bool,
+}
+
+impl OutputMonitor {
+    pub fn new(max_latency: usize, period: usize) -> Self {
+        Self {
+            max_latency,
+            period,
+            dropped: 0,
+            started: false,
+        }
+    }
+
+    pub fn max_latency(&self) -> usize {
+        self.max_latency
+    }
+
+    /// After a capture, with the writer's [`crate::broadcast::BroadcastWriter::dropped_frames`]
+    /// for this output. Frames that did not fit since the last call are an overrun
+    pub fn captured(&mut self, dropped: usize) -> Option<GlitchKind> {
+        let frames = dropped - self.dropped;
+        self.dropped = dropped;
+        (frames > 0).then_some(GlitchKind::Overrun { frames })
+    }
+
+    /// Before a render: drops what queued up beyond `max_latency`, an overrun as well
+    pub fn trim(&mut self, reader: &mut BroadcastReader) -> Option<GlitchKind> {
+        let frames = reader.trim(self.max_latency);
+        (frames > 0).then_some(GlitchKind::Overrun { frames })
+    }
+
+    /// Before a render into an endpoint holding `padding` frames: an underrun when the ring is dry
+    /// and less than a period is left. Counts once, until [`OutputMonitor::rendered`] writes
+    /// audio again
+    pub fn underrun(&mut self, reader: &BroadcastReader, padding: usize) -> Option<GlitchKind> {
+        if !self.started || padding >= self.period || reader.available_frames() > 0 {
+            return None;
+        }
+        self.started = false;
+        Some(GlitchKind::Underrun)
+    }
+
+    /// After a render that wrote `frames`
+    pub fn rendered(&mut self, frames: usize) {
+        self.started |= frames > 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        broadcast::{BroadcastWriter, broadcast},
+        format::{FormatSpec, FrameSource},
+        generator::{Generator, Signal},
+    };
+
+    const FS: u32 = 48000;
+
+    fn sine(freq: f32, amplitude: f32, frames: usize) -> Vec<f32> {
+        (0..frames)
+            .map(|i| amplitude * (2f32 * std::f32::consts::PI * freq * i as f32 / FS as f32).sin())
+            .collect()
+    }
+
+    // Every jump as (frame from the start, channel), scanned in blocks of `block` frames
+    fn jumps(x: &[f32], channels: usize, block: usize) -> Vec<(usize, usize)> {
+        let mut detector = JumpDetector::new(channels, FS);
+        x.chunks(block * channels)
+            .enumerate()
+            .filter_map(|(i, chunk)| {
+                let jump = detector.scan(chunk)?;
+                Some((i * block + jump.frame, jump.channel))
+            })
+            .collect()
+    }
+
+    // `x` with `len` frames dropped at each of `at`
+    fn splice(x: &[f32], at: &[usize], len: usize) -> Vec<f32> {
+        let mut out = Vec::new();
+        let mut from = 0;
+        for &at in at {
+            out.extend_from_slice(&x[from..at]);
+            from = at + len;
+        }
+        out.extend_from_slice(&x[from..]);
+        out
+    }
+
+    #[test]
+    fn clean_signals_pass() {
+        for freq in [50f32, 440f32, 1000f32, 5000f32, 12000f32, 18000f32] {
+            assert_eq!(jumps(&sine(freq, 1f32, FS as usize), 1, 480), [], "{freq}");
+        }
+        let mut noise = vec![0f32; FS as usize];
+        Generator::new(Signal::WhiteNoise, 0f64, FormatSpec::float(1, FS)).fill(&mut noise);
+        assert_eq!(jumps(&noise, 1, 480), []);
+        // A tone burst with a 2 ms attack and a slow decay
+        let burst: Vec<f32> = sine(200f32, 0.9, FS as usize)
+            .iter()
+            .enumerate()
+            .map(|(i, x)| x * (1f32 - (-(i as f32) / 100f32).exp()) * (-(i as f32) / 9600f32).exp())
+            .collect();
+        assert_eq!(jumps(&burst, 1, 480), []);
+    }
+
+    #[test]
+    fn a_spliced_sine_jumps_once() {
+        // 37 frames of a 440 Hz tone lost at frame 10000
+        let x = splice(&sine(440f32, 0.5, FS as usize), &[10000], 37);
+        for block in [1, 7, 480, 1024] {
+            assert_eq!(jumps(&x, 1, block), [(10000, 0)], "{block}");
+        }
+        // Too quiet to hear
+        let x = splice(&sine(440f32, 0.01, FS as usize), &[10000], 37);
+        assert_eq!(jumps(&x, 1, 480), []);
+    }
+
+    #[test]
+    fn reports_the_right_channel() {
+        let left = sine(440f32, 0.5, FS as usize);
+        let right = splice(&sine(660f32, 0.5, FS as usize + 50), &[15000], 50);
+        let x: Vec<f32> = left
+            .iter()
+            .zip(&right)
+            .flat_map(|(l, r)| [*l, *r])
+            .collect();
+        for block in [1, 96, 480] {
+            assert_eq!(jumps(&x, 2, block), [(15000, 1)], "{block}");
+        }
+    }
+
+    #[test]
+    fn hold_suppresses_repeats() {
+        let hold = (JumpDetector::HOLD_MS * FS as f32 / 1000f32) as usize;
+        let x = sine(1000f32, 0.5, FS as usize);
+        // A second splice inside the hold is not reported, one after it is
+        let x = splice(&x, &[10000, 10000 + 37 + hold / 2], 37);
+        let x = splice(&x, &[20000, 20000 + 37 + 2 * hold], 37);
+        assert_eq!(
+            jumps(&x, 1, 480),
+            [(10000, 0), (20000, 0), (20000 + 2 * hold, 0)]
+        );
+    }
+
+    #[test]
+    fn start_and_reset_are_quiet() {
+        let mut detector = JumpDetector::new(1, FS);
+        let x = sine(440f32, 0.8, 4800);
+        // Starting mid-wave is no click
+        assert_eq!(detector.scan(&x[1000..]), None);
+        detector.reset();
+        assert_eq!(detector.scan(&x[2000..]), None);
+    }
+
+    #[test]
+    fn display() {
+        let glitch = Glitch {
+            kind: GlitchKind::Overrun { frames: 96 },
+            at: Duration::from_millis(12345),
+            output: Some(1),
+            fill: 960,
+            capacity: 120000,
+            period: 128,
+        };
+        assert_eq!(
+            glitch.to_string(),
+            "12.345s output 1: overrun, 96 frames lost, ring 960/120000 frames, period 128"
+        );
+        let glitch = Glitch {
+            kind: GlitchKind::Discontinuity,
+            output: None,
+            ..glitch
+        };
+        assert_eq!(
+            glitch.to_string(),
+            "12.345s capture: discontinuity, ring 960/120000 frames, period 128"
+        );
+    }
+
+    const PERIOD: usize = 480;
+    const BUFFER: usize = 2 * PERIOD;
+    const MAX_LATENCY: usize = 6 * PERIOD;
+    const RING: usize = 2 * MAX_LATENCY;
+
+    // One pipe output the way `PipeStreamInfo` drives it, with a simulated endpoint that plays a
+    // period per cycle. Mono bytes, one per frame
+    struct Pipe {
+        writer: BroadcastWriter,
+        reader: BroadcastReader,
+        monitor: OutputMonitor,
+        // Frames in the endpoint buffer
+        padding: usize,
+        glitches: Vec<GlitchKind>,
+    }
+
+    impl Pipe {
+        fn new() -> Self {
+            let (writer, mut readers) = broadcast(&[RING], 1);
+            Self {
+                writer,
+                reader: readers.remove(0),
+                monitor: OutputMonitor::new(MAX_LATENCY, PERIOD),
+                padding: 0,
+                glitches: Vec::new(),
+            }
+        }
+
+        fn capture(&mut self, frames: usize) {
+            self.writer.push(&vec![0; frames]);
+            let dropped = self.writer.dropped_frames(0);
+            self.glitches.extend(self.monitor.captured(dropped));
+        }
+
+        fn render(&mut self) {
+            self.glitches.extend(self.monitor.trim(&mut self.reader));
+            let underrun = self.monitor.underrun(&self.reader, self.padding);
+            self.glitches.extend(underrun);
+            let mut out = vec![0; BUFFER - self.padding];
+            let frames = self.reader.pull(&mut out);
+            self.monitor.rendered(frames);
+            self.padding += frames;
+        }
+
+        // The endpoint plays a period, whether there was audio or not
+        fn play(&mut self) {
+            self.padding = self.padding.saturating_sub(PERIOD);
+        }
+
+        fn cycle(&mut self, capture: usize) {
+            self.capture(capture);
+            self.render();
+            self.play();
+        }
+
+        fn take(&mut self) -> Vec<GlitchKind> {
+            std::mem::take(&mut self.glitches)
+        }
+    }
+
+    fn overrun_frames(glitches: &[GlitchKind]) -> usize {
+        glitches
+            .iter()
+            .map(|x| match x {
+                GlitchKind::Overrun { frames } => *frames,
+                _ => panic!("{x:?}"),
+            })
+            .sum()
+    }
+
+    #[test]
+    fn a_steady_pipe_is_clean() {
+        let mut pipe = Pipe::new();
+        for _ in 0..1000 {
+            pipe.cycle(PERIOD);
+        }
+        assert_eq!(pipe.take(), []);
+        // Nothing builds up past what one cycle brings
+        assert!(pipe.reader.available_frames() <= PERIOD);
+    }
+
+    #[test]
+    fn a_stalled_output_overruns_then_trims() {
+        let mut pipe = Pipe::new();
+        for _ in 0..10 {
+            pipe.cycle(PERIOD);
+        }
+        let queued = pipe.reader.available_frames();
+        // The render side stalls while the capture keeps going
+        for _ in 0..20 {
+            pipe.capture(PERIOD);
+        }
+        let glitches = pipe.take();
+        let lost = queued + 20 * PERIOD - RING;
+        assert_eq!(overrun_frames(&glitches), lost);
+        // One report per capture that lost frames, none before the ring was full
+        assert_eq!(glitches.len(), lost.div_ceil(PERIOD));
+        assert_eq!(pipe.reader.available_frames(), RING);
+
+        // Back to rendering: the backlog beyond the latency limit goes at once
+        pipe.render();
+        assert_eq!(
+            pipe.take(),
+            [GlitchKind::Overrun {
+                frames: RING - MAX_LATENCY
+            }]
+        );
+        for _ in 0..100 {
+            pipe.play();
+            pipe.cycle(PERIOD);
+        }
+        assert_eq!(pipe.take(), []);
+    }
+
+    #[test]
+    fn a_burst_past_the_latency_limit_is_trimmed() {
+        let mut pipe = Pipe::new();
+        for _ in 0..10 {
+            pipe.cycle(PERIOD);
+        }
+        let queued = pipe.reader.available_frames();
+        // Fits in the ring, so nothing is lost on the capture side
+        pipe.capture(MAX_LATENCY + 1000 - queued);
+        assert_eq!(pipe.take(), []);
+        pipe.render();
+        assert_eq!(pipe.take(), [GlitchKind::Overrun { frames: 1000 }]);
+        for _ in 0..100 {
+            pipe.cycle(PERIOD);
+        }
+        assert_eq!(pipe.take(), []);
+    }
+
+    #[test]
+    fn underruns_count_once_per_dry_spell() {
+        let mut pipe = Pipe::new();
+        // Nothing was written yet, an empty endpoint is no underrun
+        for _ in 0..10 {
+            pipe.cycle(0);
+        }
+        assert_eq!(pipe.take(), []);
+        pipe.cycle(BUFFER);
+        // The capture stops: the endpoint still holds a period, then runs dry once
+        pipe.cycle(0);
+        assert_eq!(pipe.take(), []);
+        for _ in 0..10 {
+            pipe.cycle(0);
+        }
+        assert_eq!(pipe.take(), [GlitchKind::Underrun]);
+        // Audio again, then another dry spell
+        for _ in 0..10 {
+            pipe.cycle(PERIOD);
+        }
+        for _ in 0..10 {
+            pipe.cycle(0);
+        }
+        assert_eq!(pipe.take(), [GlitchKind::Underrun]);
+    }
+
+    #[test]
+    fn a_full_endpoint_is_no_underrun() {
+        let mut pipe = Pipe::new();
+        pipe.capture(BUFFER);
+        pipe.render();
+        // The ring is dry but the endpoint still holds a period or more
+        pipe.play();
+        pipe.render();
+        assert_eq!(pipe.padding, PERIOD);
+        assert_eq!(pipe.take(), []);
+        pipe.play();
+        pipe.render();
+        assert_eq!(pipe.take(), [GlitchKind::Underrun]);
+    }
+}
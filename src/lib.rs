@@ -0,0 +1,124 @@
+//! Low latency audio piping on top of WASAPI.
+//!
+//! Captures from an endpoint or from a single process (`ActivateAudioInterfaceAsync` with
+//! `VIRTUAL_AUDIO_DEVICE_PROCESS_LOOPBACK`) and renders to an endpoint with the smallest engine
+//! period `IAudioClient3` allows.
+//!
+//! - Device discovery: [`device::DeviceSelector`] over [`device::DeviceInfo`], filled by
+//!   `endpoints::device_infos`
+//! - Process discovery: [`process::ProcessSelector`] over any [`process::ProcessProvider`]
+//! - Process capture: `activate_audio_async::ActivationParamsBuilder`
+//! - Streaming: `pipe::PipeStreamInfo` for a blocking capture to render loop, or
+//!   [`stream::AudioInput`] / [`stream::AudioOutput`] for async code
+//! - One capture to several outputs: `pipe::PipeStreamInfo::open_fanout` over [`broadcast`]
+//! - Glitches in a pipe (underruns, overruns, discontinuities, clicks): [`glitch::Glitch`], clicks
+//!   found by [`glitch::JumpDetector`]
+//! - Test signals in place of a capture: `generate::GeneratorPipe` over [`generator::Generator`]
+//! - Round-trip latency: `measure::RoundTripMeter` finds a [`roundtrip::Stimulus`] again by
+//!   [`roundtrip::correlate`]
+//! - Mixing several inputs into one output: `mix::MixPipe` over [`mixer::Mixer`]
+//! - Processing between capture and render: a [`processor::Chain`] of [`processor::Processor`]s,
+//!   such as [`dynamics::Gate`], [`eq::Equalizer`], [`stereo::StereoTools`],
+//!   [`dynamics::Compressor`] and [`dynamics::Limiter`]
+//! - Aligning pipes: [`delay::Delay`] per channel, adjustable while streaming through a
+//!   [`delay::DelayControl`]
+//! - Ducking one pipe under another: [`duck::Sidechain`] between a [`duck::SidechainKey`] and a
+//!   [`duck::Ducker`]
+//! - Loudness: [`loudness::LoudnessMeter`] after EBU R128, [`loudness::AutoGain`] toward a target
+//! - Logging goes through `tracing`, real-time loops hand fixed-size records to [`rtlog::RtLog`]
+//!   instead
+//! - Shared or exclusive mode per endpoint: [`engine::StreamConfig`], exclusive formats are
+//!   negotiated over [`format::rank_candidates`]
+//!
+//! Everything touching WASAPI only exists on Windows. The rest, including the simulated backend in
+//! [`sim`], builds anywhere.
+
+use std::time::Duration;
+
+pub mod broadcast;
+pub mod completion;
+pub mod delay;
+pub mod device;
+pub mod duck;
+pub mod dynamics;
+pub mod engine;
+pub mod eq;
+pub mod format;
+pub mod generator;
+pub mod glitch;
+pub mod loudness;
+pub mod mixer;
+pub mod process;
+pub mod processor;
+pub mod roundtrip;
+pub mod rtlog;
+pub mod sim;
+pub mod stereo;
+pub mod stream;
+
+#[cfg(windows)]
+pub mod activate_audio_async;
+#[cfg(windows)]
+pub mod endpoints;
+#[cfg(windows)]
+pub mod generate;
+#[cfg(windows)]
+pub mod measure;
+#[cfg(windows)]
+pub mod mix;
+#[cfg(windows)]
+pub mod pipe;
+#[cfg(windows)]
+pub mod system_processes;
+#[cfg(windows)]
+pub mod utils;
+#[cfg(windows)]
+pub mod wasapi_stream;
+
+pub use completion::{CancelToken, WaitError};
+pub use delay::{Delay, DelayControl, DelayTime};
+pub use device::{DeviceInfo, DeviceSelectError, DeviceSelector, DeviceState, DeviceStateMask};
+pub use duck::{DuckSettings, Ducker, Sidechain, SidechainKey};
+pub use dynamics::{
+    Compressor, CompressorSettings, Gate, GateSettings, Limiter, LimiterSettings, TruePeak,
+};
+pub use engine::{InputKind, PeriodRequest, ShareMode, StreamConfig, StreamKind};
+pub use eq::{Band, Equalizer, FilterKind};
+pub use format::{FormatSpec, FrameSource, SampleConverter, SampleFormat, SampleWriter};
+pub use generator::{Generator, Signal};
+pub use glitch::{Glitch, GlitchKind, JumpDetector, OutputMonitor};
+pub use loudness::{AutoGain, AutoGainSettings, LoudnessMeter, LoudnessReadings};
+pub use mixer::{MixOptions, Mixer, MixerInput};
+pub use process::{ProcessInfo, ProcessLoopbackMode, ProcessSelectError, ProcessSelector};
+pub use processor::{Chain, Pipeline, Processor};
+pub use roundtrip::{Correlation, Stimulus};
+pub use rtlog::{RtEvent, RtLog};
+pub use stereo::{PanLaw, StereoSettings, StereoTools};
+pub use stream::{AudioInput, AudioOutput, StreamError};
+
+#[cfg(windows)]
+pub use activate_audio_async::{ActivationError, ActivationOptions, ActivationParamsBuilder};
+#[cfg(windows)]
+pub use generate::GeneratorPipe;
+#[cfg(windows)]
+pub use measure::{RoundTrip, RoundTripMeter};
+#[cfg(windows)]
+pub use mix::{MixPipe, MixSource};
+#[cfg(windows)]
+pub use pipe::{ClientSource, InitInfo, PipeStreamInfo};
+
+/// 100ns units, as used by `REFERENCE_TIME`
+pub fn to_reference_time(d: Duration) -> i64 {
+    (d.as_nanos() / 100) as i64
+}
+
+/// Duration of `frames` at `sample_rate` in 100ns units, rounded the way the exclusive mode
+/// buffer alignment retry expects
+pub fn frames_to_reference_time(frames: u32, sample_rate: u32) -> i64 {
+    (10_000_000f64 * frames as f64 / sample_rate as f64 + 0.5) as i64
+}
+
+/// Whole frames in `hns` 100ns units at `sample_rate`, rounded to the nearest frame
+pub fn reference_time_to_frames(hns: i64, sample_rate: u32) -> u32 {
+    ((hns.max(0) as u64 * sample_rate as u64 + 5_000_000) / 10_000_000) as u32
+}
@@ -0,0 +1,545 @@
+use std::{
+    f64::consts::PI,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+
+use crate::{dynamics::time_coeff, processor::Processor};
+
+/// Loudness of silence, and of anything not measured yet
+pub const SILENCE_LUFS: f64 = f64::NEG_INFINITY;
+
+const ABSOLUTE_GATE_LUFS: f64 = -70f64;
+const RELATIVE_GATE_LU: f64 = -10f64;
+// Integrated loudness keeps gating blocks in a histogram, so the meter runs forever in constant
+// memory. Energies are summed exactly, only the gate decision is rounded to a bin
+const HISTOGRAM_MIN_LUFS: f64 = ABSOLUTE_GATE_LUFS;
+const HISTOGRAM_MAX_LUFS: f64 = 10f64;
+const HISTOGRAM_BINS_PER_LU: f64 = 100f64;
+const HISTOGRAM_BINS: usize =
+    ((HISTOGRAM_MAX_LUFS - HISTOGRAM_MIN_LUFS) * HISTOGRAM_BINS_PER_LU) as usize;
+
+// 100 ms steps: momentary is the last 4, short-term the last 30
+const MOMENTARY_STEPS: usize = 4;
+const SHORT_TERM_STEPS: usize = 30;
+
+fn energy_to_lufs(energy: f64) -> f64 {
+    if energy <= 0f64 {
+        return SILENCE_LUFS;
+    }
+    -0.691f64 + 10f64 * energy.log10()
+}
+
+/// Channel weights of BS.1770. Five and six channel layouts are taken as L R C (LFE) Ls Rs, the
+/// LFE is left out and the surrounds count 1.41 times. Anything else weighs every channel the same
+pub fn channel_weights(channels: usize) -> Vec<f64> {
+    match channels {
+        5 => vec![1f64, 1f64, 1f64, 1.41f64, 1.41f64],
+        6 => vec![1f64, 1f64, 1f64, 0f64, 1.41f64, 1.41f64],
+        _ => vec![1f64; channels],
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Biquad {
+    b: [f64; 3],
+    a: [f64; 2],
+}
+
+/// The two K-weighting stages of BS.1770 (high shelf, then RLB high-pass), derived for any rate
+fn k_weighting(sample_rate: u32) -> [Biquad; 2] {
+    let fs = sample_rate as f64;
+
+    let f0 = 1681.974450955533f64;
+    let g = 3.999843853973347f64;
+    let q = 0.7071752369554196f64;
+    let k = (PI * f0 / fs).tan();
+    let vh = 10f64.powf(g / 20f64);
+    let vb = vh.powf(0.4996667741545416f64);
+    let a0 = 1f64 + k / q + k * k;
+    let shelf = Biquad {
+        b: [
+            (vh + vb * k / q + k * k) / a0,
+            2f64 * (k * k - vh) / a0,
+            (vh - vb * k / q + k * k) / a0,
+        ],
+        a: [2f64 * (k * k - 1f64) / a0, (1f64 - k / q + k * k) / a0],
+    };
+
+    let f0 = 38.13547087602444f64;
+    let q = 0.5003270373238773f64;
+    let k = (PI * f0 / fs).tan();
+    let a0 = 1f64 + k / q + k * k;
+    let high_pass = Biquad {
+        b: [1f64, -2f64, 1f64],
+        a: [2f64 * (k * k - 1f64) / a0, (1f64 - k / q + k * k) / a0],
+    };
+
+    [shelf, high_pass]
+}
+
+/// Latest readings of a [`LoudnessMeter`], readable from any thread. Updated every 100 ms
+#[derive(Debug)]
+pub struct LoudnessReadings {
+    momentary: AtomicU64,
+    short_term: AtomicU64,
+    integrated: AtomicU64,
+}
+
+impl LoudnessReadings {
+    fn new() -> Self {
+        Self {
+            momentary: AtomicU64::new(SILENCE_LUFS.to_bits()),
+            short_term: AtomicU64::new(SILENCE_LUFS.to_bits()),
+            integrated: AtomicU64::new(SILENCE_LUFS.to_bits()),
+        }
+    }
+
+    /// Over the last 400 ms, in LUFS
+    pub fn momentary(&self) -> f64 {
+        f64::from_bits(self.momentary.load(Ordering::Relaxed))
+    }
+
+    /// Over the last 3 s, in LUFS
+    pub fn short_term(&self) -> f64 {
+        f64::from_bits(self.short_term.load(Ordering::Relaxed))
+    }
+
+    /// Gated over everything measured so far, in LUFS
+    pub fn integrated(&self) -> f64 {
+        f64::from_bits(self.integrated.load(Ordering::Relaxed))
+    }
+}
+
+/// EBU R128 / ITU-R BS.1770 loudness meter. Passes audio through untouched and measures
+/// momentary, short-term and gated integrated loudness. Real-time safe once prepared
+pub struct LoudnessMeter {
+    readings: Arc<LoudnessReadings>,
+    channels: usize,
+    weights: Vec<f64>,
+    filters: [Biquad; 2],
+    // Two biquad states per channel
+    state: Vec<[[f64; 2]; 2]>,
+    step_frames: usize,
+    step_pos: usize,
+    step_energy: f64,
+    // Weighted energy sums of the last `SHORT_TERM_STEPS` 100 ms steps
+    steps: [f64; SHORT_TERM_STEPS],
+    steps_pos: usize,
+    steps_seen: usize,
+    histogram_count: Vec<u64>,
+    histogram_energy: Vec<f64>,
+}
+
+impl Default for LoudnessMeter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LoudnessMeter {
+    pub fn new() -> Self {
+        Self {
+            readings: Arc::new(LoudnessReadings::new()),
+            channels: 0,
+            weights: Vec::new(),
+            filters: k_weighting(48000),
+            state: Vec::new(),
+            step_frames: 4800,
+            step_pos: 0,
+            step_energy: 0f64,
+            steps: [0f64; SHORT_TERM_STEPS],
+            steps_pos: 0,
+            steps_seen: 0,
+            histogram_count: vec![0; HISTOGRAM_BINS],
+            histogram_energy: vec![0f64; HISTOGRAM_BINS],
+        }
+    }
+
+    pub fn readings(&self) -> Arc<LoudnessReadings> {
+        self.readings.clone()
+    }
+
+    pub fn momentary(&self) -> f64 {
+        self.window_lufs(MOMENTARY_STEPS)
+    }
+
+    pub fn short_term(&self) -> f64 {
+        self.window_lufs(SHORT_TERM_STEPS)
+    }
+
+    /// Absolute gate at -70 LUFS, then a relative gate 10 LU below the absolutely gated loudness
+    pub fn integrated(&self) -> f64 {
+        let gated = |from: usize| {
+            let bins = from..HISTOGRAM_BINS;
+            let count: u64 = self.histogram_count[bins.clone()].iter().sum();
+            let energy: f64 = self.histogram_energy[bins].iter().sum();
+            (count > 0).then(|| energy / count as f64)
+        };
+        let Some(absolute) = gated(0) else {
+            return SILENCE_LUFS;
+        };
+        let relative = energy_to_lufs(absolute) + RELATIVE_GATE_LU;
+        match gated(Self::bin(relative)) {
+            Some(energy) => energy_to_lufs(energy),
+            None => SILENCE_LUFS,
+        }
+    }
+
+    fn bin(lufs: f64) -> usize {
+        (((lufs - HISTOGRAM_MIN_LUFS) * HISTOGRAM_BINS_PER_LU).max(0f64) as usize)
+            .min(HISTOGRAM_BINS - 1)
+    }
+
+    // Mean square over the last `n` full steps, silence until that many were seen
+    fn window_energy(&self, n: usize) -> Option<f64> {
+        if self.steps_seen < n {
+            return None;
+        }
+        let sum: f64 = (0..n)
+            .map(|i| self.steps[(self.steps_pos + SHORT_TERM_STEPS - 1 - i) % SHORT_TERM_STEPS])
+            .sum();
+        Some(sum / (n * self.step_frames) as f64)
+    }
+
+    fn window_lufs(&self, n: usize) -> f64 {
+        self.window_energy(n).map_or(SILENCE_LUFS, energy_to_lufs)
+    }
+
+    fn finish_step(&mut self) {
+        self.steps[self.steps_pos] = self.step_energy;
+        self.steps_pos = (self.steps_pos + 1) % SHORT_TERM_STEPS;
+        self.steps_seen = self.steps_seen.saturating_add(1);
+        self.step_energy = 0f64;
+        self.step_pos = 0;
+
+        // Every 400 ms block, overlapping by 75%, is a gating block
+        if let Some(energy) = self.window_energy(MOMENTARY_STEPS) {
+            let lufs = energy_to_lufs(energy);
+            if lufs > ABSOLUTE_GATE_LUFS {
+                let bin = Self::bin(lufs);
+                self.histogram_count[bin] += 1;
+                self.histogram_energy[bin] += energy;
+            }
+        }
+
+        let r = &self.readings;
+        r.momentary
+            .store(self.momentary().to_bits(), Ordering::Relaxed);
+        r.short_term
+            .store(self.short_term().to_bits(), Ordering::Relaxed);
+        r.integrated
+            .store(self.integrated().to_bits(), Ordering::Relaxed);
+    }
+
+    /// Feeds frames without touching them
+    pub fn measure(&mut self, buf: &[f32]) {
+        if self.channels == 0 {
+            return;
+        }
+        for frame in buf.chunks_exact(self.channels) {
+            for ((x, state), weight) in frame.iter().zip(&mut self.state).zip(&self.weights) {
+                let mut y = *x as f64;
+                for (f, s) in self.filters.iter().zip(state.iter_mut()) {
+                    let out = f.b[0] * y + s[0];
+                    s[0] = f.b[1] * y - f.a[0] * out + s[1];
+                    s[1] = f.b[2] * y - f.a[1] * out;
+                    y = out;
+                }
+                self.step_energy += weight * y * y;
+            }
+            self.step_pos += 1;
+            if self.step_pos == self.step_frames {
+                self.finish_step();
+            }
+        }
+    }
+}
+
+impl Processor for LoudnessMeter {
+    fn prepare(&mut self, channels: usize, sample_rate: u32) {
+        self.channels = channels;
+        self.weights = channel_weights(channels);
+        self.filters = k_weighting(sample_rate);
+        self.state = vec![[[0f64; 2]; 2]; channels];
+        self.step_frames = (sample_rate as usize / 10).max(1);
+        self.reset();
+    }
+
+    fn process(&mut self, buf: &mut [f32]) {
+        self.measure(buf);
+    }
+
+    fn reset(&mut self) {
+        self.state.fill([[0f64; 2]; 2]);
+        self.step_pos = 0;
+        self.step_energy = 0f64;
+        self.steps = [0f64; SHORT_TERM_STEPS];
+        self.steps_pos = 0;
+        self.steps_seen = 0;
+        self.histogram_count.fill(0);
+        self.histogram_energy.fill(0f64);
+        let r = &self.readings;
+        for x in [&r.momentary, &r.short_term, &r.integrated] {
+            x.store(SILENCE_LUFS.to_bits(), Ordering::Relaxed);
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AutoGainSettings {
+    /// Short-term loudness to steer toward
+    pub target_lufs: f64,
+    /// Largest boost or cut, in dB
+    pub max_gain_db: f64,
+    /// Fastest gain change, in dB per second
+    pub speed_db_per_s: f64,
+    /// Material quieter than this many LU below the target is left alone, so pauses are not
+    /// pulled up into noise
+    pub gate_lu: f64,
+}
+
+impl Default for AutoGainSettings {
+    fn default() -> Self {
+        Self {
+            target_lufs: -23f64,
+            max_gain_db: 12f64,
+            speed_db_per_s: 3f64,
+            gate_lu: 20f64,
+        }
+    }
+}
+
+/// Steers a pipe toward a target loudness. Measures its input's short-term loudness and moves
+/// the gain toward the difference at a limited speed, applied with per-sample smoothing
+pub struct AutoGain {
+    settings: AutoGainSettings,
+    meter: LoudnessMeter,
+    channels: usize,
+    sample_rate: u32,
+    gain_db: f64,
+    gain: f32,
+    smoothing: f32,
+    steps_seen: usize,
+}
+
+impl AutoGain {
+    // The applied gain follows the 100 ms decisions this smoothly
+    const SMOOTHING_MS: f32 = 50f32;
+
+    pub fn new(settings: AutoGainSettings) -> Self {
+        Self {
+            settings,
+            meter: LoudnessMeter::new(),
+            channels: 0,
+            sample_rate: 0,
+            gain_db: 0f64,
+            gain: 1f32,
+            smoothing: 1f32,
+            steps_seen: 0,
+        }
+    }
+
+    pub fn settings(&self) -> AutoGainSettings {
+        self.settings
+    }
+
+    pub fn set(&mut self, settings: AutoGainSettings) {
+        self.settings = settings;
+    }
+
+    /// Gain the pipe is heading to, in dB
+    pub fn gain_db(&self) -> f64 {
+        self.gain_db
+    }
+
+    /// Readings of the input, before the gain
+    pub fn readings(&self) -> Arc<LoudnessReadings> {
+        self.meter.readings()
+    }
+
+    fn update(&mut self) {
+        let s = self.settings;
+        // Momentary until the first 3 s are in, so the gain does not sit still that long
+        let loudness = match self.meter.short_term() {
+            x if x.is_finite() => x,
+            _ => self.meter.momentary(),
+        };
+        if !loudness.is_finite() || loudness < s.target_lufs - s.gate_lu {
+            return;
+        }
+        let wanted = (s.target_lufs - loudness).clamp(-s.max_gain_db, s.max_gain_db);
+        let step = s.speed_db_per_s * self.meter.step_frames as f64 / self.sample_rate as f64;
+        self.gain_db += (wanted - self.gain_db).clamp(-step, step);
+    }
+}
+
+impl Processor for AutoGain {
+    fn prepare(&mut self, channels: usize, sample_rate: u32) {
+        self.channels = channels;
+        self.sample_rate = sample_rate;
+        self.smoothing = time_coeff(Self::SMOOTHING_MS, sample_rate);
+        self.meter.prepare(channels, sample_rate);
+        self.steps_seen = 0;
+    }
+
+    fn process(&mut self, buf: &mut [f32]) {
+        if self.channels == 0 {
+            return;
+        }
+        // Measure and decide in 100 ms steps, so a decision never waits on the block size
+        let step = self.meter.step_frames * self.channels;
+        let mut rest = buf;
+        while !rest.is_empty() {
+            let left = step - self.meter.step_pos * self.channels;
+            let (chunk, tail) = rest.split_at_mut(left.min(rest.len()));
+            self.meter.measure(chunk);
+            if self.meter.steps_seen != self.steps_seen {
+                self.steps_seen = self.meter.steps_seen;
+                self.update();
+            }
+            let target = 10f32.powf(self.gain_db as f32 / 20f32);
+            for frame in chunk.chunks_exact_mut(self.channels) {
+                self.gain += (target - self.gain) * self.smoothing;
+                for x in frame.iter_mut() {
+                    *x *= self.gain;
+                }
+            }
+            rest = tail;
+        }
+    }
+
+    fn reset(&mut self) {
+        self.meter.reset();
+        self.steps_seen = 0;
+        self.gain_db = 0f64;
+        self.gain = 1f32;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Stereo 1 kHz sine as in EBU Tech 3341, in segments of (dBFS per channel, seconds)
+    fn sine(sample_rate: u32, segments: &[(f64, f64)]) -> Vec<f32> {
+        let mut out = Vec::new();
+        let mut n = 0u64;
+        for &(db, secs) in segments {
+            let amplitude = 10f64.powf(db / 20f64);
+            for _ in 0..(secs * sample_rate as f64).round() as u64 {
+                let x = amplitude * (2f64 * PI * 1000f64 * n as f64 / sample_rate as f64).sin();
+                out.extend_from_slice(&[x as f32; 2]);
+                n += 1;
+            }
+        }
+        out
+    }
+
+    fn meter(sample_rate: u32, segments: &[(f64, f64)]) -> LoudnessMeter {
+        let mut meter = LoudnessMeter::new();
+        meter.prepare(2, sample_rate);
+        let mut buf = sine(sample_rate, segments);
+        // 10 ms blocks at 44.1 kHz, so steps end mid-block at 48 kHz
+        for block in buf.chunks_mut(2 * 441) {
+            meter.process(block);
+        }
+        meter
+    }
+
+    fn assert_close(actual: f64, expected: f64, tolerance: f64) {
+        assert!(
+            (actual - expected).abs() <= tolerance,
+            "{actual} vs {expected}"
+        );
+    }
+
+    #[test]
+    fn tech_3341_case_1_and_2() {
+        for sample_rate in [48000, 44100] {
+            for db in [-23f64, -33f64] {
+                let m = meter(sample_rate, &[(db, 20f64)]);
+                assert_close(m.momentary(), db, 0.1);
+                assert_close(m.short_term(), db, 0.1);
+                assert_close(m.integrated(), db, 0.1);
+                assert_eq!(m.readings().integrated(), m.integrated());
+            }
+        }
+    }
+
+    #[test]
+    fn tech_3341_gating() {
+        // Case 3: the relative gate drops the -36 dBFS parts
+        let m = meter(48000, &[(-36f64, 10f64), (-23f64, 60f64), (-36f64, 10f64)]);
+        assert_close(m.integrated(), -23f64, 0.1);
+        // Case 4: the absolute gate drops -72 dBFS as well
+        let segments = [
+            (-72f64, 10f64),
+            (-36f64, 10f64),
+            (-23f64, 60f64),
+            (-36f64, 10f64),
+            (-72f64, 10f64),
+        ];
+        assert_close(meter(48000, &segments).integrated(), -23f64, 0.1);
+    }
+
+    #[test]
+    fn nothing_before_the_first_window() {
+        let m = meter(48000, &[(-23f64, 0.3)]);
+        assert_eq!(m.momentary(), SILENCE_LUFS);
+        assert_eq!(m.short_term(), SILENCE_LUFS);
+        assert_eq!(m.integrated(), SILENCE_LUFS);
+    }
+
+    fn run_auto_gain(settings: AutoGainSettings, segments: &[(f64, f64)]) -> (AutoGain, Vec<f32>) {
+        let mut auto_gain = AutoGain::new(settings);
+        auto_gain.prepare(2, 48000);
+        let mut buf = sine(48000, segments);
+        for block in buf.chunks_mut(2 * 480) {
+            auto_gain.process(block);
+        }
+        (auto_gain, buf)
+    }
+
+    #[test]
+    fn auto_gain_converges() {
+        let settings = AutoGainSettings {
+            target_lufs: -16f64,
+            ..Default::default()
+        };
+        let (auto_gain, mut out) = run_auto_gain(settings, &[(-26f64, 20f64)]);
+        assert_close(auto_gain.gain_db(), 10f64, 0.05);
+        // What comes out reads the target once the gain has arrived
+        let mut meter = LoudnessMeter::new();
+        meter.prepare(2, 48000);
+        meter.process(&mut out[2 * 48000 * 15..]);
+        assert_close(meter.integrated(), -16f64, 0.1);
+    }
+
+    #[test]
+    fn auto_gain_speed_limit() {
+        let settings = AutoGainSettings {
+            target_lufs: -16f64,
+            ..Default::default()
+        };
+        let (auto_gain, _) = run_auto_gain(settings, &[(-26f64, 1f64)]);
+        let gain_db = auto_gain.gain_db();
+        assert!(gain_db > 1f64 && gain_db <= 3f64 + 1e-9, "{gain_db}");
+    }
+
+    #[test]
+    fn auto_gain_cap_and_gate() {
+        let settings = AutoGainSettings {
+            target_lufs: -16f64,
+            speed_db_per_s: 100f64,
+            ..Default::default()
+        };
+        let (auto_gain, _) = run_auto_gain(settings, &[(-30f64, 5f64)]);
+        assert_close(auto_gain.gain_db(), 12f64, 1e-9);
+        // More than `gate_lu` below the target is left alone
+        let (auto_gain, _) = run_auto_gain(settings, &[(-50f64, 5f64)]);
+        assert_eq!(auto_gain.gain_db(), 0f64);
+    }
+}
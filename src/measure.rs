@@ -0,0 +1,278 @@
+use core::slice;
+use std::{
+    ptr,
+    time::{Duration, Instant},
+};
+
+use anyhow::{Result, bail};
+use tracing::info;
+use windows::{
+    Win32::{
+        Foundation::HANDLE,
+        Media::Audio::{
+            AUDCLNT_BUFFERFLAGS_DATA_DISCONTINUITY, AUDCLNT_BUFFERFLAGS_SILENT,
+            IAudioCaptureClient, IAudioClient, IAudioRenderClient,
+        },
+        System::Threading::{CreateEventW, WaitForSingleObject},
+    },
+    core::Owned,
+};
+
+use crate::{
+    engine::StreamConfig,
+    format::FormatSpec,
+    pipe::{ClientSource, InitInfo, capture_format, init_client},
+    roundtrip::{Correlation, Stimulus, correlate},
+    utils::WaveFormat,
+};
+
+/// One round trip through a [`RoundTripMeter`]
+#[derive(Debug, Clone, Copy)]
+pub struct RoundTrip {
+    pub frames: usize,
+    pub sample_rate: u32,
+    pub clarity_db: f64,
+}
+
+impl RoundTrip {
+    pub fn millis(&self) -> f64 {
+        self.frames as f64 * 1000f64 / self.sample_rate as f64
+    }
+}
+
+/// Plays a [`Stimulus`] on an output and finds it again on an input. Both run in one loop on one
+/// event the way [`crate::pipe::PipeStreamInfo`] does, every captured frame lets one frame be
+/// rendered, so the figure is what a pipe between the two endpoints adds plus the path in between
+pub struct RoundTripMeter {
+    capture_client: IAudioClient,
+    capture_info: InitInfo,
+    capture_spec: FormatSpec,
+    render_client: IAudioClient,
+    render_info: InitInfo,
+    render_spec: FormatSpec,
+    // Both clients signal it, closed on drop
+    ev: Owned<HANDLE>,
+}
+
+impl RoundTripMeter {
+    // Silence before the stimulus, lets both streams settle
+    const PREROLL_MS: usize = 200;
+    // Longest round trip looked for
+    const MAX_LAG_MS: usize = 1000;
+    // On top of preroll, stimulus and lag before a stalled input counts as gone
+    const SLACK_MS: usize = 2000;
+
+    pub fn open(
+        capture: ClientSource,
+        capture_config: StreamConfig,
+        render: ClientSource,
+        render_config: StreamConfig,
+        wfx: WaveFormat,
+    ) -> Result<Self> {
+        unsafe {
+            let ev = Owned::new(CreateEventW(None, false, false, None)?);
+            info!(
+                share_mode = %render_config.share_mode,
+                kind = %render_config.kind,
+                "initialising output"
+            );
+            let (render_client, render_info) =
+                init_client(&render, Some(wfx), *ev, &render_config)?;
+            info!(
+                share_mode = %capture_config.share_mode,
+                kind = %capture_config.kind,
+                "initialising input"
+            );
+            let (capture_client, capture_info) = init_client(
+                &capture,
+                Some(capture_format(&render_info, &capture_config)),
+                *ev,
+                &capture_config,
+            )?;
+
+            let (Some(render_spec), Some(capture_spec)) =
+                (render_info.wfx.spec(), capture_info.wfx.spec())
+            else {
+                bail!(
+                    "cannot measure between {:?} and {:?}",
+                    capture_info.wfx,
+                    render_info.wfx
+                );
+            };
+            if render_spec.sample_rate != capture_spec.sample_rate {
+                bail!("input runs at {capture_spec}, output at {render_spec}");
+            }
+            Ok(Self {
+                capture_client,
+                capture_info,
+                capture_spec,
+                render_client,
+                render_info,
+                render_spec,
+                ev,
+            })
+        }
+    }
+
+    /// What the engine periods alone add up to: one `min_period` on each side
+    pub fn theoretical_frames(&self) -> usize {
+        (self.capture_info.min_period + self.render_info.min_period) as usize
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.render_spec.sample_rate
+    }
+
+    /// Plays `stimulus` once at `level_db` on every output channel and correlates the input, mixed
+    /// down to mono, against it
+    pub fn measure(&mut self, stimulus: Stimulus, level_db: f32) -> Result<RoundTrip> {
+        let rate = self.sample_rate() as usize;
+        let amplitude = 10f32.powf(level_db / 20f32);
+        let reference = stimulus.samples();
+        let preroll = Self::PREROLL_MS * rate / 1000;
+        let wanted = reference.len() + Self::MAX_LAG_MS * rate / 1000;
+        let budget_ms = Self::PREROLL_MS + wanted * 1000 / rate + Self::SLACK_MS;
+        let deadline = Instant::now() + Duration::from_millis(budget_ms as u64);
+
+        let out_channels = self.render_spec.channels as usize;
+        let in_channels = self.capture_spec.channels as usize;
+        let mut out = vec![0f32; self.render_info.buf_size as usize * out_channels];
+        let mut input = vec![0f32; self.capture_info.buf_size as usize * in_channels];
+        let mut recorded = Vec::with_capacity(wanted + self.capture_info.buf_size as usize);
+
+        unsafe {
+            let cac: IAudioCaptureClient = self.capture_client.GetService()?;
+            let crc: IAudioRenderClient = self.render_client.GetService()?;
+            // Whatever is queued from before would only blur the figure
+            self.drain(&cac)?;
+
+            let mut silence = 0;
+            // Frames of stimulus written, `None` until it starts
+            let mut played: Option<usize> = None;
+            // Frames captured but not yet matched by a rendered frame
+            let mut owed = 0;
+            while recorded.len() < wanted {
+                if Instant::now() > deadline {
+                    bail!(
+                        "gave up after {budget_ms} ms, the input delivered {} of {wanted} frames",
+                        recorded.len()
+                    );
+                }
+                WaitForSingleObject(*self.ev, 100);
+                loop {
+                    let mut cbuf = ptr::null_mut();
+                    let mut ftr = 0;
+                    let mut flags = 0;
+                    cac.GetBuffer(&mut cbuf, &mut ftr, &mut flags, None, None)?;
+                    if cbuf.is_null() || ftr == 0 {
+                        break;
+                    }
+                    if played.is_some()
+                        && flags & AUDCLNT_BUFFERFLAGS_DATA_DISCONTINUITY.0 as u32 != 0
+                    {
+                        bail!("the input glitched during the measurement");
+                    }
+                    let frames = ftr as usize;
+                    let samples = &mut input[..frames * in_channels];
+                    if flags & AUDCLNT_BUFFERFLAGS_SILENT.0 as u32 != 0 {
+                        samples.fill(0f32);
+                    } else {
+                        let bytes =
+                            slice::from_raw_parts(cbuf, frames * self.capture_info.block as usize);
+                        self.capture_spec.sample_format.read_samples(bytes, samples);
+                    }
+                    cac.ReleaseBuffer(ftr)?;
+                    if played.is_some() {
+                        recorded.extend(
+                            samples
+                                .chunks_exact(in_channels)
+                                .map(|x| x.iter().sum::<f32>() / in_channels as f32),
+                        );
+                    }
+                    owed += frames;
+                }
+
+                let padding = self.render_client.GetCurrentPadding()?;
+                let frames = ((self.render_info.buf_size - padding) as usize).min(owed);
+                if frames == 0 {
+                    continue;
+                }
+                let samples = &mut out[..frames * out_channels];
+                samples.fill(0f32);
+                match played {
+                    // Starts on a chunk boundary, so the recording begins right with it
+                    None if silence >= preroll => {
+                        write_stimulus(samples, out_channels, &reference, 0, amplitude);
+                        played = Some(frames);
+                    }
+                    None => silence += frames,
+                    Some(n) => {
+                        write_stimulus(samples, out_channels, &reference, n, amplitude);
+                        played = Some(n + frames);
+                    }
+                }
+                let cbuf = crc.GetBuffer(frames as u32)?;
+                let bytes =
+                    slice::from_raw_parts_mut(cbuf, frames * self.render_info.block as usize);
+                self.render_spec.sample_format.write_samples(samples, bytes);
+                crc.ReleaseBuffer(frames as u32, 0)?;
+                owed -= frames;
+            }
+        }
+
+        let Some(correlation) = correlate(&reference, &recorded) else {
+            bail!("nothing came back on the input");
+        };
+        if !correlation.is_clear() {
+            bail!(
+                "no clear echo, correlation peak only {:.1} dB above its average (need {} dB)",
+                correlation.clarity_db,
+                Correlation::MIN_CLARITY_DB
+            );
+        }
+        Ok(RoundTrip {
+            frames: correlation.lag,
+            sample_rate: self.sample_rate(),
+            clarity_db: correlation.clarity_db,
+        })
+    }
+
+    fn drain(&self, cac: &IAudioCaptureClient) -> Result<()> {
+        unsafe {
+            loop {
+                let mut cbuf = ptr::null_mut();
+                let mut ftr = 0;
+                let mut flags = 0;
+                cac.GetBuffer(&mut cbuf, &mut ftr, &mut flags, None, None)?;
+                if cbuf.is_null() || ftr == 0 {
+                    return Ok(());
+                }
+                cac.ReleaseBuffer(ftr)?;
+            }
+        }
+    }
+}
+
+impl Drop for RoundTripMeter {
+    fn drop(&mut self) {
+        unsafe {
+            // Nothing to do about a client that cannot stop, the event closes either way
+            let _ = self.render_client.Stop();
+            let _ = self.capture_client.Stop();
+        }
+    }
+}
+
+// `reference[from..]` into every channel of `out`, as far as either goes
+fn write_stimulus(
+    out: &mut [f32],
+    channels: usize,
+    reference: &[f32],
+    from: usize,
+    amplitude: f32,
+) {
+    let rest = reference.get(from..).unwrap_or_default();
+    for (frame, x) in out.chunks_exact_mut(channels).zip(rest) {
+        frame.fill(x * amplitude);
+    }
+}
@@ -0,0 +1,127 @@
+use anyhow::{Result, bail};
+use tracing::info;
+use windows::Win32::{
+    Foundation::HANDLE, Media::Audio::IAudioClient, System::Threading::CreateEventW,
+};
+
+use crate::{
+    engine::StreamConfig,
+    mixer::{MixOptions, Mixer, MixerInput},
+    pipe::{ClientSource, InitInfo, init_client, spawn},
+    processor::Chain,
+    stream::frame_channel,
+    utils::{ComSend, WaveFormat},
+    wasapi_stream::{BRIDGE_BUFFERS, capture_loop, source_loop},
+};
+
+/// One input of a [`MixPipe`]
+pub struct MixSource {
+    pub source: ClientSource,
+    pub config: StreamConfig,
+    pub gain_db: f32,
+    /// Runs on this input before it is mixed, e.g. a [`crate::duck::Ducker`]
+    pub processors: Chain,
+}
+
+/// Several captures mixed into one render client. Every input runs its own MMCSS capture thread
+/// in its native format, conversion, drift compensation and mixing happen on the render side
+pub struct MixPipe {
+    mixer: Mixer,
+    render_client: IAudioClient,
+    render_info: InitInfo,
+    ev: HANDLE,
+}
+
+impl MixPipe {
+    pub fn open(
+        inputs: Vec<MixSource>,
+        render: ClientSource,
+        render_config: StreamConfig,
+        wfx: WaveFormat,
+        options: MixOptions,
+    ) -> Result<Self> {
+        unsafe {
+            let ev = CreateEventW(None, false, false, None)?;
+            info!(
+                share_mode = %render_config.share_mode,
+                kind = %render_config.kind,
+                "initialising output"
+            );
+            let (render_client, render_info) = init_client(&render, Some(wfx), ev, &render_config)?;
+            let Some(out) = render_info.wfx.spec() else {
+                bail!("cannot mix into {:?}", render_info.wfx);
+            };
+            let max_frames = render_info.buf_size as usize;
+            let mut mixer = Mixer::new(out, max_frames, options);
+
+            for (i, input) in inputs.into_iter().enumerate() {
+                info!(
+                    input = i,
+                    share_mode = %input.config.share_mode,
+                    kind = %input.config.kind,
+                    gain_db = input.gain_db,
+                    "initialising input"
+                );
+                let input_ev = CreateEventW(None, false, false, None)?;
+                let (client, info) = init_client(&input.source, None, input_ev, &input.config)?;
+                let Some(spec) = info.wfx.spec() else {
+                    bail!("cannot mix from {:?}", info.wfx);
+                };
+
+                // Two capture periods and one render period of slack ride out scheduling jitter
+                let render_period =
+                    render_info.period as u64 * spec.sample_rate as u64 / out.sample_rate as u64;
+                let target = info.period as usize * 2 + render_period as usize;
+                let (bridge, consumer) =
+                    frame_channel(info.buf_size as usize * BRIDGE_BUFFERS, info.block as usize);
+                mixer.add_input(
+                    MixerInput::new(consumer, spec, out, max_frames)
+                        .gain_db(input.gain_db)
+                        .drift_compensation(target)
+                        .processors(input.processors, out),
+                );
+
+                let ctx = ComSend((client, input_ev));
+                spawn("wasapi mix input", move || {
+                    let (client, ev) = ctx.into_inner();
+                    capture_loop(&client, ev, &info, bridge)
+                });
+            }
+
+            Ok(Self {
+                mixer,
+                render_client,
+                render_info,
+                ev,
+            })
+        }
+    }
+
+    pub fn mixer(&self) -> &Mixer {
+        &self.mixer
+    }
+
+    pub fn mixer_mut(&mut self) -> &mut Mixer {
+        &mut self.mixer
+    }
+
+    /// Runs on the mix, see [`Mixer::set_processors`]
+    pub fn set_processors(&mut self, processors: Chain) {
+        self.mixer.set_processors(processors);
+        info!(
+            latency_ms = self.mixer.processing_latency() as f64 * 1000f64
+                / self.mixer.spec().sample_rate as f64,
+            "processing stage ready"
+        );
+    }
+
+    pub fn run(&mut self) -> Result<()> {
+        source_loop(
+            &self.render_client,
+            self.ev,
+            &self.render_info,
+            &mut self.mixer,
+            |_| {},
+        )
+    }
+}
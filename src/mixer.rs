@@ -0,0 +1,611 @@
+use crate::{
+    format::{FormatSpec, FrameSource},
+    processor::{Chain, Processor},
+    stream::FrameConsumer,
+};
+
+/// Linear gain for `db` decibels
+pub fn db_to_gain(db: f32) -> f32 {
+    10f32.powf(db / 20f32)
+}
+
+/// Interleaved channel conversion. Mono is copied to every output channel, anything folded down
+/// to mono is averaged, other layouts keep the channels they share and silence the rest
+pub fn remix_channels(input: &[f32], in_channels: usize, out: &mut [f32], out_channels: usize) {
+    let frames = (input.len() / in_channels).min(out.len() / out_channels);
+    let input = input.chunks_exact(in_channels).take(frames);
+    let out = out.chunks_exact_mut(out_channels).take(frames);
+
+    if in_channels == out_channels {
+        for (i, o) in input.zip(out) {
+            o.copy_from_slice(i);
+        }
+    } else if in_channels == 1 {
+        for (i, o) in input.zip(out) {
+            o.fill(i[0]);
+        }
+    } else if out_channels == 1 {
+        for (i, o) in input.zip(out) {
+            o[0] = i.iter().sum::<f32>() / in_channels as f32;
+        }
+    } else {
+        let shared = in_channels.min(out_channels);
+        for (i, o) in input.zip(out) {
+            o[..shared].copy_from_slice(&i[..shared]);
+            o[shared..].fill(0f32);
+        }
+    }
+}
+
+/// Above `SOFT_CLIP_KNEE` the signal bends smoothly toward full scale instead of clipping
+pub const SOFT_CLIP_KNEE: f32 = 0.75;
+
+/// Unity below the knee, `tanh` shaped above it and never beyond `[-1, 1]`
+pub fn soft_clip(x: f32) -> f32 {
+    let a = x.abs();
+    if a <= SOFT_CLIP_KNEE {
+        return x;
+    }
+    let range = 1f32 - SOFT_CLIP_KNEE;
+    (SOFT_CLIP_KNEE + range * ((a - SOFT_CLIP_KNEE) / range).tanh()).copysign(x)
+}
+
+/// Streaming linear interpolation over interleaved frames. The ratio may change on every call,
+/// which is how drift compensation steers it
+#[derive(Debug, Clone)]
+pub struct Resampler {
+    channels: usize,
+    // Fractional read position into the next input block
+    pos: f64,
+}
+
+impl Resampler {
+    pub fn new(channels: usize) -> Self {
+        Self {
+            channels,
+            pos: 0f64,
+        }
+    }
+
+    /// `ratio` is input frames per output frame. Returns `(consumed, produced)` frames, input past
+    /// `consumed` must be passed again on the next call
+    pub fn process(&mut self, input: &[f32], output: &mut [f32], ratio: f64) -> (usize, usize) {
+        let ch = self.channels;
+        let in_frames = input.len() / ch;
+        let out_frames = output.len() / ch;
+
+        let mut produced = 0;
+        while produced < out_frames {
+            let i = self.pos as usize;
+            if i + 1 >= in_frames {
+                break;
+            }
+            let frac = (self.pos - i as f64) as f32;
+            let a = &input[i * ch..(i + 1) * ch];
+            let b = &input[(i + 1) * ch..(i + 2) * ch];
+            for ((o, a), b) in output[produced * ch..(produced + 1) * ch]
+                .iter_mut()
+                .zip(a)
+                .zip(b)
+            {
+                *o = a + (b - a) * frac;
+            }
+            produced += 1;
+            self.pos += ratio;
+        }
+
+        let consumed = (self.pos as usize).min(in_frames);
+        self.pos -= consumed as f64;
+        (consumed, produced)
+    }
+}
+
+/// Keeps an input ring around `target` frames by nudging the resampling ratio. Capture and render
+/// clocks never agree exactly, without this the ring slowly fills up or runs dry
+#[derive(Debug, Clone)]
+pub struct DriftCompensator {
+    target: f64,
+    smoothed: f64,
+    integral: f64,
+    /// Largest correction, as a fraction of the nominal ratio
+    pub max_correction: f64,
+}
+
+impl DriftCompensator {
+    const SMOOTHING: f64 = 0.01;
+    const KP: f64 = 0.005;
+    const KI: f64 = 0.00001;
+
+    pub fn new(target_frames: usize) -> Self {
+        Self {
+            target: target_frames.max(1) as f64,
+            smoothed: target_frames as f64,
+            integral: 0f64,
+            max_correction: 0.002,
+        }
+    }
+
+    pub fn target(&self) -> usize {
+        self.target as usize
+    }
+
+    /// Multiplier for the nominal ratio given the current fill level, above 1 drains faster
+    pub fn update(&mut self, fill_frames: usize) -> f64 {
+        self.smoothed += (fill_frames as f64 - self.smoothed) * Self::SMOOTHING;
+        let err = (self.smoothed - self.target) / self.target;
+        self.integral =
+            (self.integral + err * Self::KI).clamp(-self.max_correction, self.max_correction);
+        1f64 + (err * Self::KP + self.integral).clamp(-self.max_correction, self.max_correction)
+    }
+}
+
+/// One source of a [`Mixer`]: decodes whatever the capture delivers, converts it to the output
+/// channel count and rate, and adds it to the mix with its own gain
+pub struct MixerInput {
+    consumer: FrameConsumer,
+    spec: FormatSpec,
+    out_channels: usize,
+    pub gain: f32,
+    nominal_ratio: f64,
+    resampler: Resampler,
+    drift: Option<DriftCompensator>,
+    processors: Chain,
+    raw: Vec<u8>,
+    decoded: Vec<f32>,
+    // Converted frames not consumed by the resampler yet
+    remixed: Vec<f32>,
+    buffered: usize,
+    resampled: Vec<f32>,
+}
+
+impl MixerInput {
+    /// `max_frames` is the largest block the mixer will ask for, every buffer is allocated here
+    pub fn new(
+        consumer: FrameConsumer,
+        spec: FormatSpec,
+        out: FormatSpec,
+        max_frames: usize,
+    ) -> Self {
+        let nominal_ratio = spec.sample_rate as f64 / out.sample_rate as f64;
+        let out_channels = out.channels as usize;
+        // Room for the largest ratio drift compensation may ask for, plus interpolation neighbours
+        let max_in = (max_frames as f64 * nominal_ratio * 1.01).ceil() as usize + 4;
+        Self {
+            consumer,
+            spec,
+            out_channels,
+            gain: 1f32,
+            nominal_ratio,
+            resampler: Resampler::new(out_channels),
+            drift: None,
+            processors: Chain::new(),
+            raw: vec![0; max_in * spec.block_align()],
+            decoded: vec![0f32; max_in * spec.channels as usize],
+            remixed: vec![0f32; max_in * out_channels],
+            buffered: 0,
+            resampled: vec![0f32; max_frames * out_channels],
+        }
+    }
+
+    pub fn gain_db(mut self, db: f32) -> Self {
+        self.gain = db_to_gain(db);
+        self
+    }
+
+    /// Steer the ring toward `target_frames` of captured audio
+    pub fn drift_compensation(mut self, target_frames: usize) -> Self {
+        self.drift = Some(DriftCompensator::new(target_frames));
+        self
+    }
+
+    /// Runs on this input alone, after conversion to the output format and before its gain
+    pub fn processors(mut self, mut processors: Chain, out: FormatSpec) -> Self {
+        processors.prepare(out.channels as usize, out.sample_rate);
+        self.processors = processors;
+        self
+    }
+
+    pub fn spec(&self) -> FormatSpec {
+        self.spec
+    }
+
+    /// Captured frames waiting, in the input rate
+    pub fn available_frames(&self) -> usize {
+        self.consumer.available_frames() + self.buffered
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.consumer.is_closed()
+    }
+
+    /// Adds up to `out.len() / channels` frames to `out`, returns how many were available. Missing
+    /// frames are left untouched, which is silence in a mix
+    pub fn mix_into(&mut self, out: &mut [f32]) -> usize {
+        let ch = self.out_channels;
+        let frames = (out.len() / ch).min(self.resampled.len() / ch);
+        let capacity = self.remixed.len() / ch;
+        let ratio = match &mut self.drift {
+            Some(drift) => {
+                let fill = self.consumer.available_frames() + self.buffered;
+                self.nominal_ratio * drift.update(fill)
+            }
+            None => self.nominal_ratio,
+        };
+
+        let needed = ((frames as f64 * ratio).ceil() as usize + 2).min(capacity);
+        if self.buffered < needed {
+            let block = self.spec.block_align();
+            let in_ch = self.spec.channels as usize;
+            let want = needed - self.buffered;
+            let got = self.consumer.read(&mut self.raw[..want * block]) / block;
+            self.spec
+                .sample_format
+                .read_samples(&self.raw[..got * block], &mut self.decoded[..got * in_ch]);
+            remix_channels(
+                &self.decoded[..got * in_ch],
+                in_ch,
+                &mut self.remixed[self.buffered * ch..(self.buffered + got) * ch],
+                ch,
+            );
+            self.buffered += got;
+        }
+
+        let (consumed, produced) = self.resampler.process(
+            &self.remixed[..self.buffered * ch],
+            &mut self.resampled[..frames * ch],
+            ratio,
+        );
+        self.remixed
+            .copy_within(consumed * ch..self.buffered * ch, 0);
+        self.buffered -= consumed;
+
+        self.processors
+            .process(&mut self.resampled[..produced * ch]);
+        for (o, x) in out.iter_mut().zip(&self.resampled[..produced * ch]) {
+            *o += x * self.gain;
+        }
+        produced
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MixOptions {
+    /// Attenuation applied to the sum, so several loud inputs do not clip right away
+    pub headroom_db: f32,
+    pub soft_clip: bool,
+}
+
+impl Default for MixOptions {
+    fn default() -> Self {
+        Self {
+            headroom_db: 6f32,
+            soft_clip: true,
+        }
+    }
+}
+
+/// Sums [`MixerInput`]s into blocks of the output format. Real-time safe once built, nothing in
+/// `process` allocates or blocks
+pub struct Mixer {
+    inputs: Vec<MixerInput>,
+    out: FormatSpec,
+    options: MixOptions,
+    headroom: f32,
+    max_frames: usize,
+    processors: Chain,
+}
+
+impl Mixer {
+    pub fn new(out: FormatSpec, max_frames: usize, options: MixOptions) -> Self {
+        Self {
+            inputs: Vec::new(),
+            out,
+            options,
+            headroom: db_to_gain(-options.headroom_db),
+            max_frames,
+            processors: Chain::new(),
+        }
+    }
+
+    pub fn add_input(&mut self, input: MixerInput) {
+        self.inputs.push(input);
+    }
+
+    pub fn inputs(&self) -> &[MixerInput] {
+        &self.inputs
+    }
+
+    pub fn inputs_mut(&mut self) -> &mut [MixerInput] {
+        &mut self.inputs
+    }
+
+    pub fn spec(&self) -> FormatSpec {
+        self.out
+    }
+
+    pub fn options(&self) -> MixOptions {
+        self.options
+    }
+
+    /// Runs on the sum after headroom, before soft clipping
+    pub fn set_processors(&mut self, mut processors: Chain) {
+        processors.prepare(self.out.channels as usize, self.out.sample_rate);
+        self.processors = processors;
+    }
+
+    pub fn processors_mut(&mut self) -> &mut Chain {
+        &mut self.processors
+    }
+
+    /// Frames the processors hold back
+    pub fn processing_latency(&self) -> usize {
+        self.processors.latency()
+    }
+
+    /// Mixes into `out` as float samples, `out.len()` is capped at the `max_frames` given to `new`
+    pub fn process(&mut self, out: &mut [f32]) {
+        let len = out.len().min(self.max_frames * self.out.channels as usize);
+        let out = &mut out[..len];
+        out.fill(0f32);
+        for input in &mut self.inputs {
+            input.mix_into(out);
+        }
+        for x in out.iter_mut() {
+            *x *= self.headroom;
+        }
+        self.processors.process(out);
+        if self.options.soft_clip {
+            for x in out.iter_mut() {
+                *x = soft_clip(*x);
+            }
+        }
+    }
+}
+
+impl FrameSource for Mixer {
+    fn spec(&self) -> FormatSpec {
+        self.out
+    }
+
+    fn fill(&mut self, out: &mut [f32]) {
+        self.process(out);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        format::{SampleFormat, SampleWriter},
+        stream::{InputBridge, frame_channel},
+    };
+
+    const OUT: FormatSpec = FormatSpec {
+        sample_format: SampleFormat::F32,
+        channels: 2,
+        sample_rate: 48000,
+    };
+
+    fn input(spec: FormatSpec, frames: usize) -> (InputBridge, MixerInput) {
+        let (bridge, consumer) = frame_channel(frames, spec.block_align());
+        (bridge, MixerInput::new(consumer, spec, OUT, 480))
+    }
+
+    fn push(bridge: &mut InputBridge, spec: FormatSpec, samples: &[f32]) {
+        let mut bytes = vec![0; samples.len() * spec.sample_format.bytes()];
+        spec.sample_format.write_samples(samples, &mut bytes);
+        assert_eq!(bridge.push(&bytes), bytes.len());
+    }
+
+    fn no_headroom() -> MixOptions {
+        MixOptions {
+            headroom_db: 0f32,
+            soft_clip: false,
+        }
+    }
+
+    #[test]
+    fn sums_with_gain_and_headroom() {
+        let mono = FormatSpec {
+            sample_format: SampleFormat::I16,
+            channels: 1,
+            ..OUT
+        };
+        let (mut a, input_a) = input(OUT, 4800);
+        let (mut b, input_b) = input(mono, 4800);
+        push(&mut a, OUT, &[0.25f32, -0.25].repeat(960));
+        push(&mut b, mono, &[0.5f32; 960]);
+
+        let mut mixer = Mixer::new(OUT, 480, no_headroom());
+        mixer.add_input(input_a);
+        mixer.add_input(input_b.gain_db(-6.0206));
+        let mut out = vec![1f32; 480 * 2];
+        mixer.process(&mut out);
+        for frame in out.chunks_exact(2) {
+            assert!((frame[0] - 0.5).abs() < 1e-3, "{frame:?}");
+            assert!(frame[1].abs() < 1e-3, "{frame:?}");
+        }
+
+        // 6dB of headroom halves the sum
+        let mut mixer = Mixer::new(
+            OUT,
+            480,
+            MixOptions {
+                headroom_db: 6.0206,
+                soft_clip: false,
+            },
+        );
+        let (mut a, input_a) = input(OUT, 4800);
+        push(&mut a, OUT, &[0.8f32; 960 * 2]);
+        mixer.add_input(input_a);
+        let (mut b, input_b) = input(OUT, 4800);
+        push(&mut b, OUT, &[0.6f32; 960 * 2]);
+        mixer.add_input(input_b);
+        mixer.process(&mut out);
+        assert!(
+            out.iter().all(|x| (x - 0.7).abs() < 1e-3),
+            "{:?}",
+            &out[..4]
+        );
+    }
+
+    #[test]
+    fn missing_input_is_silence() {
+        let (_bridge, input) = input(OUT, 4800);
+        let mut mixer = Mixer::new(OUT, 480, MixOptions::default());
+        mixer.add_input(input);
+        let mut out = vec![1f32; 480 * 2];
+        mixer.process(&mut out);
+        assert!(out.iter().all(|x| *x == 0f32));
+    }
+
+    #[test]
+    fn render_encodes_the_output_format() {
+        let out = FormatSpec {
+            sample_format: SampleFormat::I16,
+            ..OUT
+        };
+        let (mut bridge, consumer) = frame_channel(4800, OUT.block_align());
+        push(&mut bridge, OUT, &[0.5f32; 960 * 2]);
+        let mut mixer = Mixer::new(out, 480, no_headroom());
+        mixer.add_input(MixerInput::new(consumer, OUT, out, 480));
+        let mut writer = SampleWriter::new(out, 480);
+        // More room than `max_frames`
+        let mut bytes = vec![0; 960 * out.block_align()];
+        assert_eq!(writer.render(&mut mixer, &mut bytes), 480);
+        let first = i16::from_le_bytes([bytes[0], bytes[1]]);
+        assert_eq!(first, 16384);
+    }
+
+    #[test]
+    fn soft_clip_bounds() {
+        for x in [-100f32, -2f32, -1f32, 1f32, 2f32, 100f32] {
+            let y = soft_clip(x);
+            assert!(y.abs() <= 1f32, "{x} -> {y}");
+            assert_eq!(y.signum(), x.signum());
+        }
+        assert!(soft_clip(5f32) > 0.99);
+        for x in [0f32, 0.25, -0.5, SOFT_CLIP_KNEE, -SOFT_CLIP_KNEE] {
+            assert_eq!(soft_clip(x), x);
+        }
+        assert_eq!(soft_clip(-0.8), -soft_clip(0.8));
+        // Monotonic
+        let ys: Vec<_> = (0..=400).map(|i| soft_clip(i as f32 / 100f32)).collect();
+        assert!(ys.windows(2).all(|x| x[1] >= x[0]));
+    }
+
+    #[test]
+    fn soft_clip_is_continuous_at_the_knee() {
+        let eps = 1e-4;
+        let below = soft_clip(SOFT_CLIP_KNEE - eps);
+        let above = soft_clip(SOFT_CLIP_KNEE + eps);
+        assert!((above - below - 2f32 * eps).abs() < 1e-5);
+        // Slope is still 1 right above the knee
+        let slope = (soft_clip(SOFT_CLIP_KNEE + 2f32 * eps) - above) / eps;
+        assert!((slope - 1f32).abs() < 1e-2, "{slope}");
+    }
+
+    #[test]
+    fn remix_mono_and_stereo() {
+        let mut stereo = [0f32; 6];
+        remix_channels(&[0.1, 0.2, 0.3], 1, &mut stereo, 2);
+        assert_eq!(stereo, [0.1, 0.1, 0.2, 0.2, 0.3, 0.3]);
+
+        let mut mono = [0f32; 2];
+        remix_channels(&[0.2, 0.4, 1f32, -1f32], 2, &mut mono, 1);
+        assert!((mono[0] - 0.3).abs() < 1e-6);
+        assert_eq!(mono[1], 0f32);
+
+        // Shared channels are kept, the rest silenced
+        let mut quad = [1f32; 4];
+        remix_channels(&[0.1, 0.2], 2, &mut quad, 4);
+        assert_eq!(quad, [0.1, 0.2, 0f32, 0f32]);
+        let mut stereo = [0f32; 2];
+        remix_channels(&[0.1, 0.2, 0.3, 0.4], 4, &mut stereo, 2);
+        assert_eq!(stereo, [0.1, 0.2]);
+
+        // Stops at whichever side runs out first
+        let mut short = [9f32; 2];
+        remix_channels(&[0.1, 0.2, 0.3], 1, &mut short, 2);
+        assert_eq!(short, [0.1, 0.1]);
+    }
+
+    #[test]
+    fn resampler_unity_passes_through() {
+        let input: Vec<f32> = (0..20).map(|x| x as f32).collect();
+        let mut output = [0f32; 20];
+        let mut resampler = Resampler::new(2);
+        // The last frame is held back as the right side of the next interpolation
+        assert_eq!(resampler.process(&input, &mut output, 1f64), (9, 9));
+        assert_eq!(output[..18], input[..18]);
+
+        // Carries on where it left off
+        let mut resampler = Resampler::new(1);
+        let mut output = [0f32; 4];
+        assert_eq!(resampler.process(&input[..5], &mut output, 1f64), (4, 4));
+        assert_eq!(output, [0f32, 1f32, 2f32, 3f32]);
+        assert_eq!(resampler.process(&input[4..9], &mut output, 1f64), (4, 4));
+        assert_eq!(output, [4f32, 5f32, 6f32, 7f32]);
+    }
+
+    #[test]
+    fn resampler_counts() {
+        let input: Vec<f32> = (0..10).map(|x| x as f32).collect();
+        let mut output = [0f32; 100];
+
+        let mut resampler = Resampler::new(1);
+        assert_eq!(resampler.process(&input, &mut output, 2f64), (10, 5));
+        assert_eq!(output[..5], [0f32, 2f32, 4f32, 6f32, 8f32]);
+
+        let mut resampler = Resampler::new(1);
+        assert_eq!(resampler.process(&input[..5], &mut output, 0.5), (4, 8));
+        assert_eq!(output[..4], [0f32, 0.5, 1f32, 1.5]);
+
+        // Output space runs out first
+        let mut resampler = Resampler::new(1);
+        assert_eq!(resampler.process(&input, &mut output[..3], 0.5), (1, 3));
+    }
+
+    #[test]
+    fn drift_stays_within_bounds() {
+        let mut drift = DriftCompensator::new(1000);
+        let ratio = (0..2000).map(|_| drift.update(2000)).last().unwrap();
+        assert!(ratio > 1f64 && ratio <= 1f64 + drift.max_correction);
+        let mut drift = DriftCompensator::new(1000);
+        let ratio = (0..2000).map(|_| drift.update(0)).last().unwrap();
+        assert!(ratio < 1f64 && ratio >= 1f64 - drift.max_correction);
+        let mut drift = DriftCompensator::new(1000);
+        assert_eq!(drift.update(1000), 1f64);
+    }
+
+    #[test]
+    fn drift_holds_a_slowly_filling_ring() {
+        // The capture clock runs 0.05% fast, without correction the ring would grow by 2400
+        // frames over the run
+        let spec = FormatSpec::float(1, 48000);
+        let (mut bridge, consumer) = frame_channel(48000, 4);
+        let mut input = MixerInput::new(consumer, spec, spec, 480).drift_compensation(960);
+        bridge.push(&[0; 4 * 960]);
+        let block = [0; 4 * 481];
+        let mut out = [0f32; 480];
+        let mut owed = 0f64;
+        let mut fills = Vec::new();
+        for _ in 0..10000 {
+            owed += 480f64 * 1.0005;
+            let frames = owed as usize;
+            owed -= frames as f64;
+            bridge.push(&block[..frames * 4]);
+            input.mix_into(&mut out);
+            fills.push(input.available_frames());
+        }
+        // The ring plus what the input holds back for the next period settles at the target
+        let settled = &fills[fills.len() - 2000..];
+        let max = *settled.iter().max().unwrap();
+        let min = *settled.iter().min().unwrap();
+        assert!(max - min <= 8, "{min}..{max}");
+        assert!(
+            min + 480 >= 960 - 16 && max + 480 <= 960 + 16,
+            "{min}..{max}"
+        );
+    }
+}
@@ -0,0 +1,774 @@
+use core::slice;
+use std::{
+    mem, ptr,
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use anyhow::{Result, bail};
+use tracing::{debug, info, warn};
+use windows::Win32::{
+    Foundation::S_OK,
+    Media::{
+        Audio::{
+            AUDCLNT_BUFFERFLAGS_DATA_DISCONTINUITY, AUDCLNT_E_BUFFER_SIZE_NOT_ALIGNED,
+            AUDCLNT_SHAREMODE_EXCLUSIVE, AUDCLNT_SHAREMODE_SHARED,
+            AUDCLNT_STREAMFLAGS_EVENTCALLBACK, AUDCLNT_STREAMFLAGS_LOOPBACK, AudioCategory_Media,
+            AudioClientProperties, IAudioCaptureClient, IAudioClient, IAudioClient3,
+            IAudioRenderClient, IMMDevice, WAVEFORMATEX,
+        },
+        Multimedia::WAVE_FORMAT_IEEE_FLOAT,
+    },
+    System::{
+        Com::{CLSCTX_ALL, COINIT_MULTITHREADED, COINIT_SPEED_OVER_MEMORY, CoInitializeEx},
+        Threading::{AvSetMmThreadCharacteristicsW, CreateEventW, WaitForSingleObject},
+    },
+};
+use windows_core::Interface;
+use windows_strings::w;
+
+use crate::{
+    broadcast::{BroadcastReader, BroadcastWriter, broadcast},
+    engine::{EnginePeriods, ShareMode, StreamConfig, StreamKind, legacy_init, snap_period},
+    format::{FormatSpec, SampleConverter, SampleFormat, rank_candidates},
+    frames_to_reference_time,
+    glitch::{Glitch, GlitchKind, JumpDetector, OutputMonitor},
+    processor::{Chain, Pipeline},
+    rtlog::{RtEvent, RtLog},
+    utils::WaveFormat,
+};
+
+// Spawn a COM multithreaded and set MMCSS Pro Audio task
+pub fn spawn<F, T>(name: &str, f: F) -> JoinHandle<T>
+where
+    F: FnOnce() -> Result<T> + Send + 'static,
+    T: Send + 'static,
+{
+    // let a = f().unwrap();
+    // thread::spawn(|| a)
+    thread::Builder::new()
+        .name(name.into())
+        .spawn(|| unsafe {
+            CoInitializeEx(None, COINIT_SPEED_OVER_MEMORY | COINIT_MULTITHREADED)
+                .ok()
+                .unwrap();
+            let mut task_idx = 0;
+            AvSetMmThreadCharacteristicsW(w!("Pro Audio"), &mut task_idx).unwrap();
+            debug!(task_idx, "registered for the MMCSS Pro Audio task");
+            f().unwrap()
+        })
+        .unwrap()
+}
+
+// Ring size per output, in multiples of what it may queue before `trim` drops the excess. Leaves
+// room for the packets captured while a render is late
+const RING_LATENCIES: usize = 2;
+
+// Queued audio above this is logged when it gets there, then at most once per interval while it
+// stays
+const LATENCY_WARN_MS: usize = 30;
+const LATENCY_LOG_INTERVAL: Duration = Duration::from_secs(1);
+
+/// One render client fed by a [`PipeStreamInfo`]
+struct PipeOutput {
+    reader: BroadcastReader,
+    client: IAudioClient,
+    info: InitInfo,
+    monitor: OutputMonitor,
+    // When queued audio was last logged as too high, `None` while it is below the threshold
+    latency_logged: Option<Instant>,
+    jumps: Option<JumpDetector>,
+    decoded: Vec<f32>,
+}
+
+/// Capture client piped straight into one or more render clients, all driven by the same event.
+/// Every output has its own ring, sized from its own buffer and the capture's
+pub struct PipeStreamInfo {
+    capture: BroadcastWriter,
+    capture_client: IAudioClient,
+    capture_info: InitInfo,
+    outputs: Vec<PipeOutput>,
+    // Runs on every captured packet before it is handed to the outputs
+    pipeline: Option<Pipeline>,
+    processed: Vec<u8>,
+    // Set when the input and outputs only agree on channels and rate
+    converter: Option<SampleConverter>,
+    started: Instant,
+    // The run loop logs through this, never straight to stdout
+    log: RtLog,
+    ev: windows::Win32::Foundation::HANDLE,
+    #[allow(unused)]
+    wfx: WaveFormat,
+}
+
+impl PipeStreamInfo {
+    /// `capture` is a loopback client, like the one `capture_process_sync` returns
+    pub fn new(capture: IAudioClient, render: IAudioClient, wfx: WaveFormat) -> Result<Self> {
+        Self::open(
+            capture.into(),
+            StreamConfig::new(StreamKind::Loopback),
+            render.into(),
+            StreamConfig::new(StreamKind::Render),
+            wfx,
+        )
+    }
+
+    pub fn open(
+        capture: ClientSource,
+        capture_config: StreamConfig,
+        render: ClientSource,
+        render_config: StreamConfig,
+        wfx: WaveFormat,
+    ) -> Result<Self> {
+        Self::open_fanout(capture, capture_config, vec![(render, render_config)], wfx)
+    }
+
+    /// The render side is initialised first, capture then asks for whatever format the first
+    /// output settled on. Samples are copied as bytes, so every side must end up with the same
+    /// format
+    pub fn open_fanout(
+        capture: ClientSource,
+        capture_config: StreamConfig,
+        renders: Vec<(ClientSource, StreamConfig)>,
+        wfx: WaveFormat,
+    ) -> Result<Self> {
+        if renders.is_empty() {
+            bail!("a pipe needs at least one output");
+        }
+        unsafe {
+            let ev = CreateEventW(None, false, false, None)?;
+            let mut wfx = Some(wfx);
+            let mut clients = Vec::with_capacity(renders.len());
+            for (i, (render, render_config)) in renders.iter().enumerate() {
+                info!(
+                    output = i,
+                    share_mode = %render_config.share_mode,
+                    kind = %render_config.kind,
+                    "initialising output"
+                );
+                let (client, info) = init_client(render, wfx, ev, render_config)?;
+                if let Some((_, first)) = clients.first()
+                    && !same_format(&info, first)
+                {
+                    bail!(
+                        "output {i} format {:?} does not match output 0 format {:?}",
+                        info.wfx,
+                        first.wfx
+                    );
+                }
+                wfx = Some(info.wfx);
+                clients.push((client, info));
+            }
+            let render_info = clients[0].1;
+
+            info!(
+                share_mode = %capture_config.share_mode,
+                kind = %capture_config.kind,
+                "initialising input"
+            );
+            let (capture_client, capture_info) = init_client(
+                &capture,
+                Some(capture_format(&render_info, &capture_config)),
+                ev,
+                &capture_config,
+            )?;
+
+            let converter = if same_format(&capture_info, &render_info) {
+                None
+            } else {
+                let converter = capture_info
+                    .wfx
+                    .spec()
+                    .zip(render_info.wfx.spec())
+                    .and_then(|(from, to)| {
+                        SampleConverter::new(from, to, capture_info.buf_size as usize)
+                    });
+                let Some(converter) = converter else {
+                    bail!(
+                        "input format {:?} cannot be converted to output format {:?}, the sample \
+                         rate and channels have to match",
+                        capture_info.wfx,
+                        render_info.wfx
+                    );
+                };
+                info!(
+                    input = ?capture_info.wfx.spec(),
+                    output = ?render_info.wfx.spec(),
+                    "converting sample format"
+                );
+                Some(converter)
+            };
+
+            let block = render_info.block as usize;
+            let max_latency: Vec<_> = clients
+                .iter()
+                .map(|(_, info)| (info.buf_size + capture_info.buf_size) as usize * 2)
+                .collect();
+            let frames: Vec<_> = max_latency.iter().map(|x| x * RING_LATENCIES).collect();
+            let (capture2, readers) = broadcast(&frames, block);
+            let outputs = clients
+                .into_iter()
+                .zip(readers)
+                .zip(max_latency)
+                .map(|(((client, info), reader), max_latency)| PipeOutput {
+                    reader,
+                    client,
+                    monitor: OutputMonitor::new(max_latency, info.period as usize),
+                    info,
+                    latency_logged: None,
+                    jumps: None,
+                    decoded: Vec::new(),
+                })
+                .collect();
+
+            Ok(Self {
+                capture_client,
+                capture_info,
+                outputs,
+                pipeline: None,
+                processed: Vec::new(),
+                converter,
+                started: Instant::now(),
+                log: RtLog::new(),
+                ev,
+                wfx: render_info.wfx,
+                capture: capture2,
+            })
+        }
+    }
+
+    /// Processes the capture once, before it is split to the outputs. Only float and PCM formats
+    /// can be processed
+    pub fn set_processors(&mut self, processors: Chain) -> Result<()> {
+        if processors.is_empty() {
+            self.pipeline = None;
+            return Ok(());
+        }
+        let Some(spec) = self.capture_info.wfx.spec() else {
+            bail!("cannot process {:?}", self.capture_info.wfx);
+        };
+        let frames = self.capture_info.buf_size as usize;
+        let pipeline = Pipeline::new(processors, spec, frames);
+        info!(
+            latency_ms = pipeline.latency() as f64 * 1000f64 / spec.sample_rate as f64,
+            "processing stage ready"
+        );
+        self.pipeline = Some(pipeline);
+        self.processed = vec![0; frames * spec.block_align()];
+        Ok(())
+    }
+
+    /// Frames the processing stage holds back
+    pub fn processing_latency(&self) -> usize {
+        self.pipeline.as_ref().map_or(0, |x| x.latency())
+    }
+
+    /// Current capture to render latency of output `i` in frames: one capture period, the
+    /// queued audio, the processing stage and the render buffer
+    pub fn latency_frames(&self, i: usize) -> usize {
+        let output = &self.outputs[i];
+        self.capture_info.period as usize
+            + output.reader.available_frames()
+            + self.processing_latency()
+            + output.info.buf_size as usize
+    }
+
+    /// Also looks for clicks in what every output plays, at the cost of decoding it. Underruns,
+    /// overruns and discontinuities are always reported
+    pub fn detect_jumps(&mut self) {
+        for output in &mut self.outputs {
+            let Some(spec) = output.info.wfx.spec() else {
+                warn!(wfx = ?output.info.wfx, "cannot look for jumps in this format");
+                continue;
+            };
+            output.jumps = Some(JumpDetector::new(spec.channels as usize, spec.sample_rate));
+            output.decoded = vec![0f32; output.info.buf_size as usize * spec.channels as usize];
+        }
+    }
+
+    // Logs a glitch seen on `output`, or on the capture side
+    fn glitch(&mut self, output: Option<usize>, kind: GlitchKind) {
+        let (fill, capacity, period) = match output {
+            Some(i) => {
+                let output = &self.outputs[i];
+                (
+                    output.reader.available_frames(),
+                    output.reader.capacity_frames(),
+                    output.info.period,
+                )
+            }
+            // The fullest ring is the one the capture side is up against
+            None => {
+                let (fill, capacity) = self
+                    .outputs
+                    .iter()
+                    .map(|x| (x.reader.available_frames(), x.reader.capacity_frames()))
+                    .min_by_key(|(fill, capacity)| capacity - fill)
+                    .unwrap_or_default();
+                (fill, capacity, self.capture_info.period)
+            }
+        };
+        let glitch = Glitch {
+            kind,
+            at: self.started.elapsed(),
+            output,
+            fill,
+            capacity,
+            period,
+        };
+        self.log.log(RtEvent::Glitch(glitch));
+    }
+
+    pub fn run(&mut self) -> Result<()> {
+        unsafe {
+            self.started = Instant::now();
+            let cac: IAudioCaptureClient = self.capture_client.GetService()?;
+            let crcs = self
+                .outputs
+                .iter()
+                .map(|x| x.client.GetService())
+                .collect::<windows::core::Result<Vec<IAudioRenderClient>>>()?;
+            loop {
+                WaitForSingleObject(self.ev, 2);
+                loop {
+                    for (i, crc) in crcs.iter().enumerate() {
+                        while !self.render(i, crc)? {}
+                    }
+                    if self.outputs.iter().all(|x| x.reader.available_frames() > 0) {
+                        break;
+                    }
+                    if self.capture(&cac)? {
+                        break;
+                    }
+                }
+            }
+        }
+    }
+
+    // bool: Wait for signal
+    fn capture(&mut self, cac: &IAudioCaptureClient) -> Result<bool> {
+        unsafe {
+            let mut cbuf = ptr::null_mut();
+            let mut ftr = 0;
+            let mut flags = 0;
+            cac.GetBuffer(&mut cbuf, &mut ftr, &mut flags, None, None)?;
+            if flags & AUDCLNT_BUFFERFLAGS_DATA_DISCONTINUITY.0 as u32 != 0 {
+                self.glitch(None, GlitchKind::Discontinuity);
+            } else if flags != 0 {
+                self.log.log(RtEvent::CaptureFlags(flags));
+            }
+            if cbuf.is_null() {
+                return Ok(true);
+            }
+
+            // Outputs without room lose this packet, the others still get it
+            let rbuf = slice::from_raw_parts(cbuf, ftr as usize * self.capture_info.block as usize);
+            match &mut self.pipeline {
+                Some(pipeline) => {
+                    for chunk in rbuf.chunks(self.processed.len()) {
+                        let processed = &mut self.processed[..chunk.len()];
+                        processed.copy_from_slice(chunk);
+                        pipeline.process(processed);
+                        push(&mut self.capture, &mut self.converter, processed);
+                    }
+                }
+                None => push(&mut self.capture, &mut self.converter, rbuf),
+            }
+            cac.ReleaseBuffer(ftr)?;
+
+            for i in 0..self.outputs.len() {
+                let dropped = self.capture.dropped_frames(i);
+                if let Some(overrun) = self.outputs[i].monitor.captured(dropped) {
+                    self.glitch(Some(i), overrun);
+                }
+            }
+
+            let nps = cac.GetNextPacketSize()?;
+            return Ok(nps == 0);
+        }
+    }
+
+    // bool: Wait for signal
+    fn render(&mut self, i: usize, crc: &IAudioRenderClient) -> Result<bool> {
+        unsafe {
+            let output = &mut self.outputs[i];
+            let padding = output.client.GetCurrentPadding()?;
+            let available = output.info.buf_size - padding;
+            if available == 0 {
+                return Ok(true);
+            }
+            if let Some(overrun) = output.monitor.trim(&mut output.reader) {
+                self.glitch(Some(i), overrun);
+            }
+            let output = &mut self.outputs[i];
+            if let Some(underrun) = output.monitor.underrun(&output.reader, padding as usize) {
+                self.glitch(Some(i), underrun);
+            }
+            let output = &mut self.outputs[i];
+            let cbuf = crc.GetBuffer(available)?;
+            let rbuf =
+                slice::from_raw_parts_mut(cbuf, available as usize * output.info.block as usize);
+            let processing = self.pipeline.as_ref().map_or(0, |x| x.latency());
+            let ms = (output.reader.available_frames() + processing) * 1000
+                / (*output.info.wfx).nSamplesPerSec as usize;
+            if ms <= LATENCY_WARN_MS {
+                output.latency_logged = None;
+            } else if output
+                .latency_logged
+                .is_none_or(|at| at.elapsed() >= LATENCY_LOG_INTERVAL)
+            {
+                output.latency_logged = Some(Instant::now());
+                self.log.log(RtEvent::Latency { output: i, ms });
+            }
+            let len = output.reader.pull(rbuf);
+            output.monitor.rendered(len / output.info.block as usize);
+            let jump = match &mut output.jumps {
+                Some(jumps) => {
+                    let spec = output.info.wfx.spec().expect("checked in detect_jumps");
+                    let samples = len / spec.sample_format.bytes();
+                    spec.sample_format
+                        .read_samples(&rbuf[..len], &mut output.decoded[..samples]);
+                    jumps.scan(&output.decoded[..samples])
+                }
+                None => None,
+            };
+            crc.ReleaseBuffer(len as u32 / output.info.block, 0)?;
+            let drained = output.reader.available_frames() == 0;
+            if let Some(jump) = jump {
+                self.glitch(Some(i), GlitchKind::Jump(jump));
+            }
+            return Ok(drained);
+        }
+    }
+}
+
+fn same_format(a: &InitInfo, b: &InitInfo) -> bool {
+    a.wfx.spec() == b.wfx.spec() && a.block == b.block
+}
+
+/// Format to ask of an input that feeds `render`. Shared mode only takes float, so an exclusive
+/// output that settled on integer samples leaves the conversion to the pipe
+pub(crate) fn capture_format(render: &InitInfo, capture_config: &StreamConfig) -> WaveFormat {
+    match render.wfx.spec() {
+        Some(spec)
+            if capture_config.share_mode == ShareMode::Shared && !spec.sample_format.is_float() =>
+        {
+            FormatSpec::float(spec.channels, spec.sample_rate).into()
+        }
+        _ => render.wfx,
+    }
+}
+
+// Captured frames into the ring, in the output format
+fn push(capture: &mut BroadcastWriter, converter: &mut Option<SampleConverter>, data: &[u8]) {
+    match converter {
+        Some(converter) => {
+            let chunk = converter.max_frames() * converter.from().block_align();
+            for data in data.chunks(chunk) {
+                capture.push(converter.convert(data));
+            }
+        }
+        None => {
+            capture.push(data);
+        }
+    }
+}
+
+/// Where a client comes from. Exclusive mode may have to throw a client away and activate a new
+/// one, which is only possible for endpoints
+pub enum ClientSource {
+    Device(IMMDevice),
+    Client(IAudioClient),
+}
+
+impl ClientSource {
+    fn activate(&self) -> Result<IAudioClient> {
+        match self {
+            ClientSource::Device(dev) => unsafe { Ok(dev.Activate(CLSCTX_ALL, None)?) },
+            ClientSource::Client(ac) => Ok(ac.clone()),
+        }
+    }
+}
+
+impl From<IMMDevice> for ClientSource {
+    fn from(value: IMMDevice) -> Self {
+        ClientSource::Device(value)
+    }
+}
+
+impl From<IAudioClient> for ClientSource {
+    fn from(value: IAudioClient) -> Self {
+        ClientSource::Client(value)
+    }
+}
+
+/// What `init_ac` settled on for a client
+#[derive(Clone, Copy)]
+pub struct InitInfo {
+    pub block: u32,
+    pub wfx: WaveFormat,
+    pub min_period: u32,
+    /// Engine period the stream was initialised with, in frames
+    pub period: u32,
+    pub buf_size: u32,
+}
+
+pub(crate) fn init_ac(
+    ac: &IAudioClient,
+    wfx: Option<WaveFormat>,
+    ev: windows::Win32::Foundation::HANDLE,
+    config: &StreamConfig,
+) -> Result<InitInfo> {
+    unsafe {
+        // InitializeSharedAudioStream has no loopback flag
+        let ac3: Option<IAudioClient3> = if config.kind == StreamKind::Loopback {
+            None
+        } else {
+            ac.cast()
+                .inspect_err(|_| warn!("this client does not support IAudioClient3"))
+                .ok()
+        };
+
+        let wfx = wfx.unwrap_or(ac.GetMixFormat().map(|x| x.into()).unwrap_or_else(|_| {
+            warn!("this client does not support GetMixFormat, assuming 48kHz stereo float");
+            let wfx_new = WAVEFORMATEX {
+                wFormatTag: WAVE_FORMAT_IEEE_FLOAT as u16,
+                nChannels: 2,
+                nSamplesPerSec: 48000,
+                nAvgBytesPerSec: 384000,
+                nBlockAlign: 8,
+                wBitsPerSample: 32,
+                cbSize: 22,
+            };
+
+            WaveFormat::Ex(wfx_new)
+        }));
+        debug!(?wfx, "wave format");
+
+        let (min_period, period) = if let Some(ac) = &ac3 {
+            let mut props = AudioClientProperties::default();
+            props.cbSize = mem::size_of_val(&props) as u32;
+            props.eCategory = AudioCategory_Media;
+            ac.SetClientProperties(&props)?;
+
+            let mut default_period = 0;
+            let mut fundamental_period = 0;
+            let mut min_period = 0;
+            let mut max_period = 0;
+
+            ac.GetSharedModeEnginePeriod(
+                wfx.as_mut_ptr(),
+                &mut default_period,
+                &mut fundamental_period,
+                &mut min_period,
+                &mut max_period,
+            )?;
+
+            let periods = EnginePeriods {
+                default: default_period,
+                fundamental: fundamental_period,
+                min: min_period,
+                max: max_period,
+            };
+            let period = match config.period.frames(wfx.nSamplesPerSec) {
+                Some(requested) => snap_period(requested, &periods),
+                None => min_period,
+            };
+
+            let latency_ms = (period as f64 * 1000f64) / wfx.nSamplesPerSec as f64;
+            debug!(
+                default_period,
+                fundamental_period, min_period, max_period, "shared mode engine periods"
+            );
+            info!(period, requested = %config.period, latency_ms, "engine period");
+            ac.InitializeSharedAudioStream(
+                AUDCLNT_STREAMFLAGS_EVENTCALLBACK,
+                period,
+                wfx.as_mut_ptr(),
+                None,
+            )?;
+            (min_period, period)
+        } else {
+            let mut default_period = 0;
+            let default_period = ac
+                .GetDevicePeriod(Some(&mut default_period), None)
+                .inspect_err(|_| debug!("this client does not report its device period"))
+                .ok()
+                .map(|_| default_period);
+            let init = legacy_init(
+                config.kind,
+                default_period,
+                config.period,
+                wfx.nSamplesPerSec,
+            );
+
+            let mut flags = AUDCLNT_STREAMFLAGS_EVENTCALLBACK;
+            if init.loopback {
+                flags |= AUDCLNT_STREAMFLAGS_LOOPBACK;
+            }
+            let latency_ms = (init.period as f64 * 1000f64) / wfx.nSamplesPerSec as f64;
+            info!(
+                period = init.period,
+                requested = %config.period,
+                buffer_duration_hns = init.buffer_duration,
+                latency_ms,
+                "engine period"
+            );
+            ac.Initialize(
+                AUDCLNT_SHAREMODE_SHARED,
+                flags,
+                init.buffer_duration,
+                0,
+                wfx.as_mut_ptr(),
+                None,
+            )?;
+            (init.period, init.period)
+        };
+
+        let bfs = ac.GetBufferSize()?;
+        info!(frames = bfs, "buffer size");
+
+        ac.SetEventHandle(ev)?;
+        ac.Start()?;
+
+        Ok(InitInfo {
+            block: (*wfx).nBlockAlign as u32,
+            buf_size: bfs,
+            min_period,
+            period,
+            wfx,
+        })
+    }
+}
+
+/// Activates and starts a client from `source` in the share mode asked for by `config`
+pub(crate) fn init_client(
+    source: &ClientSource,
+    wfx: Option<WaveFormat>,
+    ev: windows::Win32::Foundation::HANDLE,
+    config: &StreamConfig,
+) -> Result<(IAudioClient, InitInfo)> {
+    let ac = source.activate()?;
+    match config.share_mode {
+        ShareMode::Shared => {
+            let info = init_ac(&ac, wfx, ev, config)?;
+            Ok((ac, info))
+        }
+        ShareMode::Exclusive if config.kind == StreamKind::Loopback => {
+            bail!("loopback capture only works in shared mode")
+        }
+        ShareMode::Exclusive => init_exclusive(source, ac, wfx, ev, config),
+    }
+}
+
+fn init_exclusive(
+    source: &ClientSource,
+    ac: IAudioClient,
+    wfx: Option<WaveFormat>,
+    ev: windows::Win32::Foundation::HANDLE,
+    config: &StreamConfig,
+) -> Result<(IAudioClient, InitInfo)> {
+    unsafe {
+        let preferred = wfx
+            .or_else(|| ac.GetMixFormat().ok().map(|x| x.into()))
+            .and_then(|x| x.spec())
+            .unwrap_or(FormatSpec {
+                sample_format: SampleFormat::F32,
+                channels: 2,
+                sample_rate: 48000,
+            });
+        let wfx = negotiate_exclusive(&ac, preferred)?;
+        debug!(?wfx, "wave format");
+
+        let mut default_period = 0;
+        let mut min_period = 0;
+        ac.GetDevicePeriod(Some(&mut default_period), Some(&mut min_period))?;
+        debug!(
+            default_period_hns = default_period,
+            min_period_hns = min_period,
+            "exclusive mode device periods"
+        );
+
+        // Exclusive periods have no fundamental, anything from the minimum up goes
+        let period = match config.period.frames(wfx.nSamplesPerSec) {
+            Some(frames) => frames_to_reference_time(frames, wfx.nSamplesPerSec).max(min_period),
+            None => min_period,
+        };
+        info!(period_hns = period, requested = %config.period, "exclusive period");
+
+        // Event driven exclusive streams need the buffer duration equal to the period
+        let ac = match initialize_exclusive(&ac, period, wfx) {
+            Err(e) if e.code() == AUDCLNT_E_BUFFER_SIZE_NOT_ALIGNED => {
+                let ClientSource::Device(_) = source else {
+                    bail!("buffer size not aligned and the client cannot be activated again");
+                };
+                let frames = ac.GetBufferSize()?;
+                let aligned = frames_to_reference_time(frames, wfx.nSamplesPerSec);
+                warn!(
+                    frames,
+                    aligned_hns = aligned,
+                    "buffer size not aligned, retrying"
+                );
+                drop(ac);
+
+                let ac = source.activate()?;
+                initialize_exclusive(&ac, aligned, wfx)?;
+                ac
+            }
+            rs => {
+                rs?;
+                ac
+            }
+        };
+
+        let bfs = ac.GetBufferSize()?;
+        let latency_ms = (bfs as f64 * 1000f64) / wfx.nSamplesPerSec as f64;
+        info!(frames = bfs, latency_ms, "buffer size");
+
+        ac.SetEventHandle(ev)?;
+        ac.Start()?;
+
+        Ok((
+            ac,
+            InitInfo {
+                block: wfx.nBlockAlign as u32,
+                buf_size: bfs,
+                min_period: bfs,
+                period: bfs,
+                wfx,
+            },
+        ))
+    }
+}
+
+// First candidate the driver takes as is, exclusive mode has no closest match
+fn negotiate_exclusive(ac: &IAudioClient, preferred: FormatSpec) -> Result<WaveFormat> {
+    for spec in rank_candidates(preferred) {
+        let wfx: WaveFormat = spec.into();
+        let hr =
+            unsafe { ac.IsFormatSupported(AUDCLNT_SHAREMODE_EXCLUSIVE, wfx.as_mut_ptr(), None) };
+        if hr == S_OK {
+            return Ok(wfx);
+        }
+    }
+    bail!(
+        "the endpoint supports none of the exclusive mode formats tried, starting from {preferred}"
+    )
+}
+
+fn initialize_exclusive(
+    ac: &IAudioClient,
+    duration: i64,
+    wfx: WaveFormat,
+) -> windows::core::Result<()> {
+    unsafe {
+        ac.Initialize(
+            AUDCLNT_SHAREMODE_EXCLUSIVE,
+            AUDCLNT_STREAMFLAGS_EVENTCALLBACK,
+            duration,
+            duration,
+            wfx.as_mut_ptr(),
+            None,
+        )
+    }
+}
@@ -0,0 +1,345 @@
+use std::{fmt::Display, str::FromStr};
+
+use anyhow::Result;
+use thiserror::Error;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProcessInfo {
+    pub pid: u32,
+    pub parent_pid: u32,
+    pub exe: String,
+    pub window_titles: Vec<String>,
+    /// Owns an active audio session on some render endpoint
+    pub audio_active: bool,
+}
+
+impl Display for ProcessInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:<6} {}", self.pid, self.exe)?;
+        if let Some(title) = self.window_titles.first() {
+            write!(f, " - {title}")?;
+        }
+        Ok(())
+    }
+}
+
+pub trait ProcessProvider {
+    fn processes(&self) -> Result<Vec<ProcessInfo>>;
+}
+
+impl ProcessProvider for [ProcessInfo] {
+    fn processes(&self) -> Result<Vec<ProcessInfo>> {
+        Ok(self.to_vec())
+    }
+}
+
+pub fn audio_processes(provider: &(impl ProcessProvider + ?Sized)) -> Result<Vec<ProcessInfo>> {
+    let mut procs = provider.processes()?;
+    procs.retain(|x| x.audio_active);
+    Ok(procs)
+}
+
+/// What a process loopback captures, relative to the target process tree
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ProcessLoopbackMode {
+    /// Only the target process and its children
+    #[default]
+    Include,
+    /// Everything rendered on the system except the target process and its children
+    Exclude,
+}
+
+impl Display for ProcessLoopbackMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            ProcessLoopbackMode::Include => "include",
+            ProcessLoopbackMode::Exclude => "exclude",
+        })
+    }
+}
+
+impl FromStr for ProcessLoopbackMode {
+    type Err = ProcessSelectError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "include" => Ok(ProcessLoopbackMode::Include),
+            "exclude" => Ok(ProcessLoopbackMode::Exclude),
+            _ => Err(ProcessSelectError::InvalidLoopbackMode(s.into())),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProcessSelector {
+    Pid(u32),
+    Exe(String),
+    Title(String),
+    /// Executable name or window title
+    Name(String),
+}
+
+#[derive(Debug, Error)]
+pub enum ProcessSelectError {
+    #[error("invalid process id: `{0}`")]
+    InvalidPid(String),
+
+    #[error("invalid loopback mode: `{0}`, expected `include` or `exclude`")]
+    InvalidLoopbackMode(String),
+
+    #[error("no process matches `{0}`")]
+    NotFound(String),
+
+    #[error("`{selector}` matches multiple processes:{}", candidates.iter().map(|x| format!("\n  {x}")).collect::<String>())]
+    Ambiguous {
+        selector: String,
+        candidates: Vec<String>,
+    },
+}
+
+impl Display for ProcessSelector {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProcessSelector::Pid(pid) => write!(f, "pid:{pid}"),
+            ProcessSelector::Exe(exe) => write!(f, "exe:{exe}"),
+            ProcessSelector::Title(title) => write!(f, "title:{title}"),
+            ProcessSelector::Name(name) => write!(f, "{name}"),
+        }
+    }
+}
+
+/// Accepted forms:
+/// - `1234` or `pid:1234`: process id
+/// - `exe:game` or `game.exe`: executable name, the `.exe` suffix is optional
+/// - `title:Spotify`: case-insensitive substring of a top level window title
+/// - anything else: executable name or window title
+impl FromStr for ProcessSelector {
+    type Err = ProcessSelectError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if let Ok(pid) = s.parse() {
+            return Ok(ProcessSelector::Pid(pid));
+        }
+        if let Some(pid) = s.strip_prefix("pid:") {
+            let pid = pid.trim();
+            return pid
+                .parse()
+                .map(ProcessSelector::Pid)
+                .map_err(|_| ProcessSelectError::InvalidPid(pid.into()));
+        }
+        if let Some(exe) = s.strip_prefix("exe:") {
+            return Ok(ProcessSelector::Exe(exe.trim().into()));
+        }
+        if let Some(title) = s.strip_prefix("title:") {
+            return Ok(ProcessSelector::Title(title.trim().into()));
+        }
+        if s.to_ascii_lowercase().ends_with(".exe") {
+            return Ok(ProcessSelector::Exe(s.into()));
+        }
+
+        Ok(ProcessSelector::Name(s.into()))
+    }
+}
+
+impl ProcessSelector {
+    pub fn matches(&self, proc: &ProcessInfo) -> bool {
+        match self {
+            ProcessSelector::Pid(pid) => proc.pid == *pid,
+            ProcessSelector::Exe(exe) => exe_matches(&proc.exe, exe),
+            ProcessSelector::Title(title) => title_matches(proc, title),
+            ProcessSelector::Name(name) => {
+                exe_matches(&proc.exe, name) || title_matches(proc, name)
+            }
+        }
+    }
+
+    /// Executable names win over window titles. Multi-process applications usually match more
+    /// than once, so the candidates are narrowed to the ones playing audio, then to the root of
+    /// their process tree before giving up
+    pub fn select<'a>(
+        &self,
+        procs: &'a [ProcessInfo],
+    ) -> Result<&'a ProcessInfo, ProcessSelectError> {
+        let mut found: Vec<_> = procs.iter().filter(|x| self.matches(x)).collect();
+        if let ProcessSelector::Name(name) = self
+            && found.iter().any(|x| exe_matches(&x.exe, name))
+        {
+            found.retain(|x| exe_matches(&x.exe, name));
+        }
+        if let [proc] = found[..] {
+            return Ok(proc);
+        }
+        if found.is_empty() {
+            return Err(ProcessSelectError::NotFound(self.to_string()));
+        }
+
+        let audio: Vec<_> = found.iter().copied().filter(|x| x.audio_active).collect();
+        if let [proc] = audio[..] {
+            return Ok(proc);
+        }
+
+        let roots: Vec<_> = found
+            .iter()
+            .copied()
+            .filter(|x| {
+                !found
+                    .iter()
+                    .any(|p| p.pid == x.parent_pid && p.pid != x.pid)
+            })
+            .collect();
+        if let [proc] = roots[..] {
+            return Ok(proc);
+        }
+
+        Err(ProcessSelectError::Ambiguous {
+            selector: self.to_string(),
+            candidates: found.iter().map(|x| x.to_string()).collect(),
+        })
+    }
+
+    pub fn resolve(&self, provider: &(impl ProcessProvider + ?Sized)) -> Result<ProcessInfo> {
+        let procs = provider.processes()?;
+        Ok(self.select(&procs)?.clone())
+    }
+}
+
+fn exe_matches(exe: &str, pattern: &str) -> bool {
+    let stem = |x: &str| {
+        let len = x.len();
+        if x.to_ascii_lowercase().ends_with(".exe") {
+            x[..len - 4].to_ascii_lowercase()
+        } else {
+            x.to_ascii_lowercase()
+        }
+    };
+    stem(exe) == stem(pattern)
+}
+
+fn title_matches(proc: &ProcessInfo, pattern: &str) -> bool {
+    let pattern = pattern.to_lowercase();
+    proc.window_titles
+        .iter()
+        .any(|x| x.to_lowercase().contains(&pattern))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn process(pid: u32, parent_pid: u32, exe: &str, titles: &[&str], audio: bool) -> ProcessInfo {
+        ProcessInfo {
+            pid,
+            parent_pid,
+            exe: exe.into(),
+            window_titles: titles.iter().map(|x| x.to_string()).collect(),
+            audio_active: audio,
+        }
+    }
+
+    fn processes() -> Vec<ProcessInfo> {
+        vec![
+            process(1, 0, "explorer.exe", &["chrome - File Explorer"], false),
+            process(10, 1, "chrome.exe", &["YouTube - Chrome"], false),
+            process(11, 10, "chrome.exe", &[], false),
+            process(12, 10, "chrome.exe", &[], false),
+            process(20, 1, "game.exe", &["My Game"], true),
+            process(21, 1, "Game.exe", &[], false),
+            process(30, 1, "a.exe", &[], false),
+            process(31, 1, "a.exe", &[], false),
+        ]
+    }
+
+    fn select(selector: &str) -> Result<u32, ProcessSelectError> {
+        let procs = processes();
+        let selector: ProcessSelector = selector.parse()?;
+        selector.select(&procs).map(|x| x.pid)
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!(
+            "12".parse::<ProcessSelector>().unwrap(),
+            ProcessSelector::Pid(12)
+        );
+        assert_eq!(
+            "pid: 12".parse::<ProcessSelector>().unwrap(),
+            ProcessSelector::Pid(12)
+        );
+        assert!(matches!(
+            "pid:x".parse::<ProcessSelector>(),
+            Err(ProcessSelectError::InvalidPid(x)) if x == "x"
+        ));
+        assert_eq!(
+            "Game.EXE".parse::<ProcessSelector>().unwrap(),
+            ProcessSelector::Exe("Game.EXE".into())
+        );
+        assert_eq!(
+            "title:you".parse::<ProcessSelector>().unwrap(),
+            ProcessSelector::Title("you".into())
+        );
+        assert_eq!(
+            "chrome".parse::<ProcessSelector>().unwrap(),
+            ProcessSelector::Name("chrome".into())
+        );
+        assert_eq!(
+            "Exclude".parse::<ProcessLoopbackMode>().unwrap(),
+            ProcessLoopbackMode::Exclude
+        );
+        assert!("foo".parse::<ProcessLoopbackMode>().is_err());
+    }
+
+    #[test]
+    fn exe_wins_over_title() {
+        // explorer.exe has "chrome" in a title, the chrome.exe tree wins and narrows to its root
+        assert_eq!(select("chrome").unwrap(), 10);
+        // Only a title matches
+        assert_eq!(select("youtube").unwrap(), 10);
+        // Titles alone: explorer.exe is the parent of the chrome.exe tree
+        assert_eq!(select("title:chrome").unwrap(), 1);
+    }
+
+    #[test]
+    fn narrows_to_audio_active() {
+        // Two unrelated game.exe, only one plays audio
+        assert_eq!(select("GAME.EXE").unwrap(), 20);
+        assert_eq!(select("exe:game").unwrap(), 20);
+    }
+
+    #[test]
+    fn narrows_to_root_of_tree() {
+        assert_eq!(select("exe:chrome").unwrap(), 10);
+        let procs = processes();
+        let children = &procs[2..4];
+        // Siblings without their parent are two roots
+        assert!(matches!(
+            ProcessSelector::Exe("chrome".into()).select(children),
+            Err(ProcessSelectError::Ambiguous { .. })
+        ));
+    }
+
+    #[test]
+    fn ambiguous_and_missing() {
+        assert_eq!(
+            select("a").unwrap_err().to_string(),
+            "`a` matches multiple processes:\n  30     a.exe\n  31     a.exe"
+        );
+        assert_eq!(select("12").unwrap(), 12);
+        assert!(matches!(select("99"), Err(ProcessSelectError::NotFound(x)) if x == "pid:99"));
+        assert!(matches!(
+            select("spotify"),
+            Err(ProcessSelectError::NotFound(_))
+        ));
+    }
+
+    #[test]
+    fn provider() {
+        let procs = processes();
+        let audio = audio_processes(&procs[..]).unwrap();
+        assert_eq!(audio.iter().map(|x| x.pid).collect::<Vec<_>>(), [20]);
+        let found = ProcessSelector::Name("my game".into())
+            .resolve(&procs[..])
+            .unwrap();
+        assert_eq!(found, procs[4]);
+    }
+}
@@ -0,0 +1,118 @@
+use crate::format::FormatSpec;
+
+/// Real-time stage between capture and render, working in place on interleaved float frames.
+/// `prepare` runs before streaming and may allocate, `process` must neither allocate nor block
+pub trait Processor: Send {
+    fn prepare(&mut self, channels: usize, sample_rate: u32);
+
+    fn process(&mut self, buf: &mut [f32]);
+
+    /// Frames of delay this stage adds
+    fn latency(&self) -> usize {
+        0
+    }
+
+    /// Forget any state, as if nothing had been processed yet
+    fn reset(&mut self) {}
+}
+
+/// Processors run one after the other
+#[derive(Default)]
+pub struct Chain {
+    processors: Vec<Box<dyn Processor>>,
+}
+
+impl Chain {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with(mut self, processor: impl Processor + 'static) -> Self {
+        self.push(processor);
+        self
+    }
+
+    pub fn push(&mut self, processor: impl Processor + 'static) {
+        self.processors.push(Box::new(processor));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.processors.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.processors.len()
+    }
+}
+
+impl Processor for Chain {
+    fn prepare(&mut self, channels: usize, sample_rate: u32) {
+        for p in &mut self.processors {
+            p.prepare(channels, sample_rate);
+        }
+    }
+
+    fn process(&mut self, buf: &mut [f32]) {
+        for p in &mut self.processors {
+            p.process(buf);
+        }
+    }
+
+    fn latency(&self) -> usize {
+        self.processors.iter().map(|x| x.latency()).sum()
+    }
+
+    fn reset(&mut self) {
+        for p in &mut self.processors {
+            p.reset();
+        }
+    }
+}
+
+/// Runs a [`Chain`] over raw device buffers: decodes, processes and encodes back in place. An
+/// empty chain leaves the bytes untouched
+pub struct Pipeline {
+    chain: Chain,
+    spec: FormatSpec,
+    scratch: Vec<f32>,
+}
+
+impl Pipeline {
+    /// `max_frames` is the largest block `process` will see, the scratch buffer is allocated here
+    pub fn new(mut chain: Chain, spec: FormatSpec, max_frames: usize) -> Self {
+        chain.prepare(spec.channels as usize, spec.sample_rate);
+        Self {
+            chain,
+            spec,
+            scratch: vec![0f32; max_frames * spec.channels as usize],
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.chain.is_empty()
+    }
+
+    pub fn latency(&self) -> usize {
+        self.chain.latency()
+    }
+
+    pub fn chain_mut(&mut self) -> &mut Chain {
+        &mut self.chain
+    }
+
+    /// Blocks larger than `max_frames` are processed in pieces
+    pub fn process(&mut self, bytes: &mut [u8]) {
+        if self.chain.is_empty() {
+            return;
+        }
+        let format = self.spec.sample_format;
+        let block = self.scratch.len() * format.bytes();
+        for bytes in bytes.chunks_mut(block) {
+            let samples = bytes.len() / format.bytes();
+            let scratch = &mut self.scratch[..samples];
+            format.read_samples(bytes, scratch);
+            self.chain.process(scratch);
+            format.write_samples(scratch, bytes);
+        }
+    }
+}
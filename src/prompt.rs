@@ -0,0 +1,47 @@
+use std::{
+    fmt::Display,
+    fs,
+    io::{self, BufRead, BufReader, Write},
+    str::FromStr,
+    sync::{LazyLock, Mutex},
+};
+
+use anyhow::{Result, anyhow};
+
+pub fn prompt_with<T: FromStr>(q: impl Display, input: &mut impl BufRead) -> Result<T>
+where
+    T::Err: std::error::Error + Send + Sync + 'static,
+{
+    print!("{q}");
+    io::stdout().flush()?;
+    let mut buf = String::new();
+    input.read_line(&mut buf)?;
+    Ok(buf.trim().parse::<T>()?)
+}
+
+pub fn prompt_stdio<T: FromStr>(q: impl Display) -> Result<T>
+where
+    T::Err: std::error::Error + Send + Sync + 'static,
+{
+    prompt_with(q, &mut std::io::stdin().lock())
+}
+
+pub fn prompt<T: FromStr>(q: impl Display) -> Result<T>
+where
+    T::Err: std::error::Error + Send + Sync + 'static,
+{
+    if let Some(lp) = LP.as_ref() {
+        let mut guard = lp.lock().map_err(|_| anyhow!("cannot lock"))?;
+        prompt_with(q, &mut &mut *guard)
+    } else {
+        prompt_stdio(q)
+    }
+}
+
+static LP: LazyLock<Option<Box<Mutex<dyn BufRead + Send + Sync>>>> = LazyLock::new(|| {
+    fs::File::open("./stdio.txt")
+        .ok()
+        .map(|x| BufReader::new(x))
+        .map(|x| Mutex::new(x))
+        .map(|x| Box::new(x) as Box<Mutex<dyn BufRead + Send + Sync>>)
+});
@@ -0,0 +1,277 @@
+use std::{f64::consts::TAU, fmt::Display, str::FromStr};
+
+use thiserror::Error;
+
+/// What a round-trip measurement plays
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Stimulus {
+    /// One full-level sample, easy to see on a scope but easily lost in room noise
+    Impulse,
+    /// Maximum length sequence, correlates far above the noise floor
+    #[default]
+    Mls,
+}
+
+impl Stimulus {
+    pub const ALL: [Stimulus; 2] = [Stimulus::Impulse, Stimulus::Mls];
+
+    /// Samples at full scale, one channel
+    pub fn samples(self) -> Vec<f32> {
+        match self {
+            Stimulus::Impulse => vec![1f32],
+            Stimulus::Mls => mls(),
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+#[error("invalid stimulus, expected `impulse` or `mls`")]
+pub struct InvalidStimulus;
+
+impl FromStr for Stimulus {
+    type Err = InvalidStimulus;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "impulse" => Ok(Stimulus::Impulse),
+            "mls" => Ok(Stimulus::Mls),
+            _ => Err(InvalidStimulus),
+        }
+    }
+}
+
+impl Display for Stimulus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Stimulus::Impulse => write!(f, "impulse"),
+            Stimulus::Mls => write!(f, "mls"),
+        }
+    }
+}
+
+// x^13 + x^12 + x^11 + x^8 + 1, 8191 samples (170ms at 48kHz)
+const MLS_ORDER: u32 = 13;
+const MLS_TAPS: [u32; 4] = [13, 12, 11, 8];
+
+/// One period of the maximum length sequence as ±1
+fn mls() -> Vec<f32> {
+    let mut state = 1u32;
+    (0..(1 << MLS_ORDER) - 1)
+        .map(|_| {
+            let out = state & 1;
+            let bit = MLS_TAPS
+                .iter()
+                .fold(0, |acc, tap| acc ^ (state >> (MLS_ORDER - tap)));
+            state = (state >> 1) | ((bit & 1) << (MLS_ORDER - 1));
+            if out == 1 { 1f32 } else { -1f32 }
+        })
+        .collect()
+}
+
+/// Peak of the cross-correlation between a reference and a recording of it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Correlation {
+    /// Frames into the recording where the reference starts
+    pub lag: usize,
+    /// Peak over the RMS of the whole correlation, in dB
+    pub clarity_db: f64,
+}
+
+impl Correlation {
+    /// Below this, the peak may just as well be noise
+    pub const MIN_CLARITY_DB: f64 = 20f64;
+
+    pub fn is_clear(&self) -> bool {
+        self.clarity_db >= Self::MIN_CLARITY_DB
+    }
+}
+
+/// Finds where `reference` starts in `recorded`, whatever its polarity. `None` when the recording
+/// is shorter than the reference or silent
+pub fn correlate(reference: &[f32], recorded: &[f32]) -> Option<Correlation> {
+    if reference.is_empty() || recorded.len() < reference.len() {
+        return None;
+    }
+    // Through the spectrum instead of a dot product per lag. No lag reaches past the end of the
+    // recording, so a transform as long as the recording never wraps around
+    let n = recorded.len().next_power_of_two();
+    let spectrum = |x: &[f32]| {
+        let mut out = vec![(0f64, 0f64); n];
+        for (o, x) in out.iter_mut().zip(x) {
+            o.0 = *x as f64;
+        }
+        fft(&mut out, false);
+        out
+    };
+    let mut product = spectrum(recorded);
+    for (a, b) in product.iter_mut().zip(spectrum(reference)) {
+        // a * conj(b)
+        *a = (a.0 * b.0 + a.1 * b.1, a.1 * b.0 - a.0 * b.1);
+    }
+    fft(&mut product, true);
+    let correlation: Vec<f64> = product[..=recorded.len() - reference.len()]
+        .iter()
+        .map(|x| x.0 / n as f64)
+        .collect();
+    let (lag, peak) = correlation
+        .iter()
+        .map(|x| x.abs())
+        .enumerate()
+        .max_by(|a, b| a.1.total_cmp(&b.1))?;
+    let rms = (correlation.iter().map(|x| x * x).sum::<f64>() / correlation.len() as f64).sqrt();
+    if peak == 0f64 {
+        return None;
+    }
+    Some(Correlation {
+        lag,
+        clarity_db: 20f64 * (peak / rms).log10(),
+    })
+}
+
+// In-place radix-2 transform of a power of two length, unscaled either way
+fn fft(x: &mut [(f64, f64)], inverse: bool) {
+    let n = x.len();
+    let mut j = 0;
+    for i in 1..n {
+        let mut bit = n >> 1;
+        while j & bit != 0 {
+            j ^= bit;
+            bit >>= 1;
+        }
+        j |= bit;
+        if i < j {
+            x.swap(i, j);
+        }
+    }
+    let sign = if inverse { 1f64 } else { -1f64 };
+    let mut len = 2;
+    while len <= n {
+        let angle = sign * TAU / len as f64;
+        let twiddles: Vec<(f64, f64)> = (0..len / 2)
+            .map(|k| ((angle * k as f64).cos(), (angle * k as f64).sin()))
+            .collect();
+        for block in x.chunks_exact_mut(len) {
+            let (lo, hi) = block.split_at_mut(len / 2);
+            for ((a, b), w) in lo.iter_mut().zip(hi).zip(&twiddles) {
+                let t = (b.0 * w.0 - b.1 * w.1, b.0 * w.1 + b.1 * w.0);
+                *b = (a.0 - t.0, a.1 - t.1);
+                *a = (a.0 + t.0, a.1 + t.1);
+            }
+        }
+        len <<= 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Uniform in [-1, 1), enough for test noise
+    struct Noise(u64);
+
+    impl Noise {
+        fn next(&mut self) -> f32 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            (self.0 >> 40) as f32 / (1u64 << 23) as f32 - 1f32
+        }
+    }
+
+    // `reference` at `lag` into a recording `len` long, times `gain`, over noise of `noise` peak
+    fn record(reference: &[f32], lag: usize, gain: f32, noise: f32, len: usize) -> Vec<f32> {
+        let mut rng = Noise(0x2545_f491_4f6c_dd1d);
+        let mut out: Vec<f32> = (0..len).map(|_| noise * rng.next()).collect();
+        for (o, x) in out[lag..].iter_mut().zip(reference) {
+            *o += gain * x;
+        }
+        out
+    }
+
+    #[test]
+    fn parse() {
+        for stimulus in Stimulus::ALL {
+            assert_eq!(stimulus.to_string().parse::<Stimulus>().unwrap(), stimulus);
+        }
+        assert_eq!("MLS".parse::<Stimulus>().unwrap(), Stimulus::Mls);
+        assert!("sweep".parse::<Stimulus>().is_err());
+    }
+
+    #[test]
+    fn mls_is_maximal() {
+        let x = mls();
+        assert_eq!(x.len(), 8191);
+        // One more +1 than -1, and every state but zero visited once
+        assert_eq!(x.iter().filter(|x| **x > 0f32).count(), 4096);
+        // Circular autocorrelation is N at 0 and -1 at every other shift
+        for shift in [0, 1, 2, 100, 4095, 8190] {
+            let sum: f32 = (0..x.len()).map(|i| x[i] * x[(i + shift) % x.len()]).sum();
+            let expected = if shift == 0 { 8191f32 } else { -1f32 };
+            assert_eq!(sum, expected, "{shift}");
+        }
+    }
+
+    #[test]
+    fn fft_matches_the_definition() {
+        let mut rng = Noise(0x9e37_79b9_7f4a_7c15);
+        let x: Vec<(f64, f64)> = (0..64)
+            .map(|_| (rng.next() as f64, rng.next() as f64))
+            .collect();
+        let mut y = x.clone();
+        fft(&mut y, false);
+        for (k, y) in y.iter().enumerate() {
+            let expected = x.iter().enumerate().fold((0f64, 0f64), |acc, (n, x)| {
+                let angle = -TAU * (k * n) as f64 / 64f64;
+                let (c, s) = (angle.cos(), angle.sin());
+                (acc.0 + x.0 * c - x.1 * s, acc.1 + x.0 * s + x.1 * c)
+            });
+            assert!((y.0 - expected.0).abs() < 1e-9 && (y.1 - expected.1).abs() < 1e-9);
+        }
+        // And back, scaled by the length
+        fft(&mut y, true);
+        for (y, x) in y.iter().zip(&x) {
+            assert!((y.0 / 64f64 - x.0).abs() < 1e-12 && (y.1 / 64f64 - x.1).abs() < 1e-12);
+        }
+    }
+
+    #[test]
+    fn mls_lag_through_gain_and_noise() {
+        let reference = Stimulus::Mls.samples();
+        for (lag, gain) in [(0, 1f32), (333, 0.05), (1777, -0.2)] {
+            // Noise 20 dB above the stimulus still correlates clearly
+            let recorded = record(
+                &reference,
+                lag,
+                gain,
+                gain.abs() * 10f32,
+                reference.len() + 2000,
+            );
+            let correlation = correlate(&reference, &recorded).unwrap();
+            assert_eq!(correlation.lag, lag);
+            assert!(correlation.is_clear(), "{}", correlation.clarity_db);
+        }
+    }
+
+    #[test]
+    fn impulse_lag() {
+        let reference = Stimulus::Impulse.samples();
+        let recorded = record(&reference, 480, 0.5, 0.01, 4800);
+        let correlation = correlate(&reference, &recorded).unwrap();
+        assert_eq!(correlation.lag, 480);
+        assert!(correlation.is_clear(), "{}", correlation.clarity_db);
+        // Buried in noise, an impulse no longer stands out
+        let recorded = record(&reference, 480, 0.5, 0.5, 4800);
+        let correlation = correlate(&reference, &recorded).unwrap();
+        assert!(!correlation.is_clear(), "{}", correlation.clarity_db);
+    }
+
+    #[test]
+    fn nothing_to_find() {
+        let reference = Stimulus::Mls.samples();
+        assert_eq!(correlate(&reference, &reference[1..]), None);
+        assert_eq!(correlate(&reference, &vec![0f32; 9000]), None);
+        assert_eq!(correlate(&[], &reference), None);
+        let noise = record(&reference, 0, 0f32, 1f32, reference.len() + 1000);
+        assert!(!correlate(&reference, &noise).unwrap().is_clear());
+    }
+}
@@ -0,0 +1,112 @@
+use std::{
+    thread,
+    time::{Duration, Instant},
+};
+
+use rtrb::{Consumer, Producer, RingBuffer};
+use tracing::{debug, warn};
+
+use crate::glitch::Glitch;
+
+/// Something a real-time loop has to say. Plain data, so logging it neither allocates nor formats
+/// on the audio thread
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RtEvent {
+    Glitch(Glitch),
+    /// A capture packet came with flags other than a discontinuity
+    CaptureFlags(u32),
+    /// Audio queued for an output has grown past what the pipe aims for. Sent when it crosses
+    /// the threshold, then at most once a second while it stays above
+    Latency {
+        output: usize,
+        ms: usize,
+    },
+}
+
+struct Record {
+    at: Instant,
+    event: RtEvent,
+    // Records that did not fit before this one
+    lost: usize,
+}
+
+/// Log handle for a real-time thread. [`RtLog::log`] only pushes into a lock-free ring, a
+/// background thread hands the records to `tracing`, so the audio thread never waits on stdout
+pub struct RtLog {
+    producer: Producer<Record>,
+    lost: usize,
+}
+
+impl RtLog {
+    const CAPACITY: usize = 1024;
+    const DRAIN_INTERVAL: Duration = Duration::from_millis(10);
+
+    /// Spawns the draining thread, it ends once the `RtLog` is dropped and the ring is empty
+    pub fn new() -> Self {
+        let (producer, consumer) = RingBuffer::new(Self::CAPACITY);
+        thread::Builder::new()
+            .name("rt-log".into())
+            .spawn(move || drain(consumer))
+            .unwrap();
+        Self { producer, lost: 0 }
+    }
+
+    /// Never blocks. When the ring is full the record is counted and reported with the next one
+    /// that fits
+    pub fn log(&mut self, event: RtEvent) {
+        let record = Record {
+            at: Instant::now(),
+            event,
+            lost: self.lost,
+        };
+        match self.producer.push(record) {
+            Ok(()) => self.lost = 0,
+            Err(_) => self.lost += 1,
+        }
+    }
+}
+
+impl Default for RtLog {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn drain(mut consumer: Consumer<Record>) {
+    loop {
+        while let Ok(record) = consumer.pop() {
+            emit(record);
+        }
+        if consumer.is_abandoned() && consumer.is_empty() {
+            return;
+        }
+        thread::sleep(RtLog::DRAIN_INTERVAL);
+    }
+}
+
+fn emit(record: Record) {
+    if record.lost > 0 {
+        warn!(
+            records = record.lost,
+            "real-time log ring full, records lost"
+        );
+    }
+    // How long the record waited in the ring
+    let queued_ms = record.at.elapsed().as_secs_f64() * 1000f64;
+    match record.event {
+        RtEvent::Glitch(glitch) => warn!(
+            at_s = glitch.at.as_secs_f64(),
+            output = glitch.output,
+            fill = glitch.fill,
+            capacity = glitch.capacity,
+            period = glitch.period,
+            queued_ms,
+            "glitch: {}",
+            glitch.kind
+        ),
+        RtEvent::CaptureFlags(flags) => debug!(flags, queued_ms, "capture flags not 0"),
+        RtEvent::Latency { output, ms } => {
+            warn!(output, latency_ms = ms, queued_ms, "latency building up")
+        }
+    }
+}
@@ -0,0 +1,115 @@
+use std::{
+    sync::{Arc, Mutex},
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use crate::stream::{AudioInput, AudioOutput, input_channel, output_channel};
+
+/// Stands in for an endpoint driven by a real-time thread: every `period`, `frames_per_period`
+/// frames are captured or rendered, just like an event driven WASAPI client
+#[derive(Debug, Clone, Copy)]
+pub struct SimulatedDevice {
+    pub block_align: usize,
+    pub frames_per_period: usize,
+    pub period: Duration,
+    /// Ring buffer size between the device thread and the async side, in periods
+    pub buffer_periods: usize,
+}
+
+impl SimulatedDevice {
+    pub fn new(block_align: usize, frames_per_period: usize, period: Duration) -> Self {
+        Self {
+            block_align,
+            frames_per_period,
+            period,
+            buffer_periods: 8,
+        }
+    }
+
+    /// `generate` fills one period of interleaved frames at a time, until the input is dropped
+    pub fn input<F>(&self, mut generate: F) -> AudioInput
+    where
+        F: FnMut(&mut [u8]) + Send + 'static,
+    {
+        let (mut bridge, input) = input_channel(
+            self.frames_per_period * self.buffer_periods,
+            self.block_align,
+        );
+        let mut buf = vec![0; self.frames_per_period * self.block_align];
+        let period = self.period;
+        thread::spawn(move || {
+            while !bridge.is_closed() {
+                generate(&mut buf);
+                bridge.push(&buf);
+                thread::sleep(period);
+            }
+        });
+        input
+    }
+
+    /// Renders until the output is closed and drained. Periods with nothing to render are counted
+    /// as underruns once the first frame has arrived
+    pub fn output(&self) -> (AudioOutput, SimulatedRender) {
+        let (output, mut bridge) = output_channel(
+            self.frames_per_period * self.buffer_periods,
+            self.block_align,
+        );
+        let rendered = Arc::new(Mutex::new(Vec::new()));
+        let underruns = Arc::new(Mutex::new(0));
+        let mut buf = vec![0; self.frames_per_period * self.block_align];
+        let period = self.period;
+
+        let handle = {
+            let rendered = rendered.clone();
+            let underruns = underruns.clone();
+            thread::spawn(move || {
+                let mut started = false;
+                while !bridge.is_finished() {
+                    let len = bridge.pull(&mut buf);
+                    if len > 0 {
+                        started = true;
+                        rendered.lock().unwrap().extend_from_slice(&buf[..len]);
+                    }
+                    if started && len < buf.len() {
+                        *underruns.lock().unwrap() += 1;
+                    }
+                    thread::sleep(period);
+                }
+            })
+        };
+
+        (
+            output,
+            SimulatedRender {
+                rendered,
+                underruns,
+                handle,
+            },
+        )
+    }
+}
+
+pub struct SimulatedRender {
+    rendered: Arc<Mutex<Vec<u8>>>,
+    underruns: Arc<Mutex<usize>>,
+    handle: JoinHandle<()>,
+}
+
+impl SimulatedRender {
+    pub fn rendered(&self) -> Vec<u8> {
+        self.rendered.lock().unwrap().clone()
+    }
+
+    pub fn underruns(&self) -> usize {
+        *self.underruns.lock().unwrap()
+    }
+
+    /// Waits for the output to be closed and drained, then returns everything rendered
+    pub fn join(self) -> Vec<u8> {
+        self.handle.join().unwrap();
+        Arc::try_unwrap(self.rendered)
+            .map(|x| x.into_inner().unwrap())
+            .unwrap_or_else(|x| x.lock().unwrap().clone())
+    }
+}
@@ -0,0 +1,354 @@
+use std::{f32::consts::FRAC_PI_2, fmt::Display, str::FromStr};
+
+use thiserror::Error;
+
+use crate::processor::Processor;
+
+/// How pan position maps to left and right gain
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum PanLaw {
+    /// Balance control: the far side is turned down, the near side stays at unity
+    #[default]
+    Balance,
+    /// Sine/cosine taper, -3 dB at centre, constant power
+    ConstantPower,
+    /// Halfway between constant power and linear, -4.5 dB at centre
+    Compromise,
+    /// Linear taper, -6 dB at centre, constant amplitude
+    Linear,
+}
+
+impl PanLaw {
+    pub const ALL: [PanLaw; 4] = [
+        PanLaw::Balance,
+        PanLaw::ConstantPower,
+        PanLaw::Compromise,
+        PanLaw::Linear,
+    ];
+
+    /// Left and right gain for `pan` in `[-1, 1]`, -1 is hard left
+    pub fn gains(self, pan: f32) -> (f32, f32) {
+        let pan = pan.clamp(-1f32, 1f32);
+        let t = (pan + 1f32) / 2f32;
+        match self {
+            PanLaw::Balance => ((1f32 - pan).min(1f32), (1f32 + pan).min(1f32)),
+            PanLaw::ConstantPower => ((t * FRAC_PI_2).cos(), (t * FRAC_PI_2).sin()),
+            PanLaw::Compromise => (
+                ((1f32 - t) * (t * FRAC_PI_2).cos()).sqrt(),
+                (t * (t * FRAC_PI_2).sin()).sqrt(),
+            ),
+            PanLaw::Linear => (1f32 - t, t),
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+#[error("invalid pan law, expected balance, 3db, 4.5db or 6db")]
+pub struct InvalidPanLaw;
+
+impl FromStr for PanLaw {
+    type Err = InvalidPanLaw;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().trim_start_matches('-') {
+            "balance" | "0db" => Ok(PanLaw::Balance),
+            "3db" | "constant-power" => Ok(PanLaw::ConstantPower),
+            "4.5db" | "compromise" => Ok(PanLaw::Compromise),
+            "6db" | "linear" => Ok(PanLaw::Linear),
+            _ => Err(InvalidPanLaw),
+        }
+    }
+}
+
+impl Display for PanLaw {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            PanLaw::Balance => "balance",
+            PanLaw::ConstantPower => "-3db",
+            PanLaw::Compromise => "-4.5db",
+            PanLaw::Linear => "-6db",
+        })
+    }
+}
+
+/// Settings of a [`StereoTools`] processor. Applied in order: swap, width, pan, mono fold-down
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct StereoSettings {
+    /// -1 is hard left, 1 hard right
+    pub pan: f32,
+    pub pan_law: PanLaw,
+    /// Side level relative to mid: 0 is mono, 1 unchanged, 2 twice as wide
+    pub width: f32,
+    pub swap: bool,
+    /// Fold down to mono on both channels, for compatibility checks
+    pub mono: bool,
+}
+
+impl Default for StereoSettings {
+    fn default() -> Self {
+        Self {
+            pan: 0f32,
+            pan_law: PanLaw::default(),
+            width: 1f32,
+            swap: false,
+            mono: false,
+        }
+    }
+}
+
+impl StereoSettings {
+    /// Whether these settings leave the signal untouched
+    pub fn is_identity(&self) -> bool {
+        self.matrix() == IDENTITY
+    }
+
+    /// The 2x2 matrix `[ll, rl, lr, rr]` mapping input left/right to output left/right
+    pub fn matrix(&self) -> [f32; 4] {
+        let mut m = IDENTITY;
+        if self.swap {
+            m = mul([0f32, 1f32, 1f32, 0f32], m);
+        }
+        if self.width != 1f32 {
+            let (a, b) = ((1f32 + self.width) / 2f32, (1f32 - self.width) / 2f32);
+            m = mul([a, b, b, a], m);
+        }
+        let (gl, gr) = self.pan_law.gains(self.pan);
+        if (gl, gr) != (1f32, 1f32) {
+            m = mul([gl, 0f32, 0f32, gr], m);
+        }
+        if self.mono {
+            m = mul([0.5f32; 4], m);
+        }
+        m
+    }
+}
+
+const IDENTITY: [f32; 4] = [1f32, 0f32, 0f32, 1f32];
+
+fn mul(a: [f32; 4], b: [f32; 4]) -> [f32; 4] {
+    [
+        a[0] * b[0] + a[1] * b[2],
+        a[0] * b[1] + a[1] * b[3],
+        a[2] * b[0] + a[3] * b[2],
+        a[2] * b[1] + a[3] * b[3],
+    ]
+}
+
+/// Pan, balance, width, swap and mono fold-down on the first two channels, any others pass
+/// through. Every setting folds into one 2x2 matrix that glides to its new value, so changes
+/// while streaming do not click
+pub struct StereoTools {
+    settings: StereoSettings,
+    target: [f32; 4],
+    current: [f32; 4],
+    channels: usize,
+    // Per-sample smoothing coefficient, about 10 ms
+    coeff: f32,
+}
+
+impl StereoTools {
+    const SMOOTHING_SECS: f32 = 0.01;
+
+    pub fn new(settings: StereoSettings) -> Self {
+        let target = settings.matrix();
+        Self {
+            settings,
+            target,
+            current: target,
+            channels: 2,
+            coeff: 1f32,
+        }
+    }
+
+    pub fn settings(&self) -> StereoSettings {
+        self.settings
+    }
+
+    /// Takes effect smoothly over the next few milliseconds
+    pub fn set(&mut self, settings: StereoSettings) {
+        self.settings = settings;
+        self.target = settings.matrix();
+    }
+}
+
+impl Processor for StereoTools {
+    fn prepare(&mut self, channels: usize, sample_rate: u32) {
+        self.channels = channels;
+        self.coeff = 1f32 - (-1f32 / (Self::SMOOTHING_SECS * sample_rate as f32)).exp();
+    }
+
+    fn process(&mut self, buf: &mut [f32]) {
+        if self.channels < 2 {
+            return;
+        }
+        let settled = self.current == self.target;
+        if settled && self.current == IDENTITY {
+            return;
+        }
+        for frame in buf.chunks_exact_mut(self.channels) {
+            if !settled {
+                for (c, t) in self.current.iter_mut().zip(self.target) {
+                    *c += (t - *c) * self.coeff;
+                    if (t - *c).abs() < 1e-4 {
+                        *c = t;
+                    }
+                }
+            }
+            let [ll, rl, lr, rr] = self.current;
+            let (l, r) = (frame[0], frame[1]);
+            frame[0] = ll * l + rl * r;
+            frame[1] = lr * l + rr * r;
+        }
+    }
+
+    fn reset(&mut self) {
+        self.current = self.target;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn db(gain: f32) -> f32 {
+        20f32 * gain.log10()
+    }
+
+    fn assert_matrix(settings: StereoSettings, expected: [f32; 4]) {
+        let m = settings.matrix();
+        for (a, b) in m.iter().zip(expected) {
+            assert!((a - b).abs() < 1e-6, "{settings:?}: {m:?} != {expected:?}");
+        }
+    }
+
+    #[test]
+    fn pan_law_at_centre() {
+        assert_eq!(PanLaw::Balance.gains(0f32), (1f32, 1f32));
+        let (l, r) = PanLaw::ConstantPower.gains(0f32);
+        assert!((db(l) + 3.01).abs() < 0.02 && (l - r).abs() < 1e-6);
+        let (l, r) = PanLaw::Compromise.gains(0f32);
+        assert!(
+            (db(l) + 4.5).abs() < 0.1 && (l - r).abs() < 1e-6,
+            "{}",
+            db(l)
+        );
+        assert_eq!(PanLaw::Linear.gains(0f32), (0.5, 0.5));
+    }
+
+    #[test]
+    fn pan_law_at_the_extremes() {
+        for law in PanLaw::ALL {
+            let (l, r) = law.gains(-1f32);
+            assert!((l - 1f32).abs() < 1e-6 && r.abs() < 1e-6, "{law}");
+            let (l, r) = law.gains(1f32);
+            assert!(l.abs() < 1e-6 && (r - 1f32).abs() < 1e-6, "{law}");
+            // Out of range is clamped
+            assert_eq!(law.gains(5f32), law.gains(1f32));
+            assert_eq!(law.to_string().parse::<PanLaw>().unwrap(), law);
+        }
+        for pan in [-0.7f32, -0.2, 0.3, 0.9] {
+            let (l, r) = PanLaw::ConstantPower.gains(pan);
+            assert!((l * l + r * r - 1f32).abs() < 1e-5);
+            let (l, r) = PanLaw::Linear.gains(pan);
+            assert!((l + r - 1f32).abs() < 1e-6);
+        }
+        assert!("x".parse::<PanLaw>().is_err());
+    }
+
+    #[test]
+    fn matrix() {
+        assert!(StereoSettings::default().is_identity());
+        assert_matrix(
+            StereoSettings {
+                swap: true,
+                ..Default::default()
+            },
+            [0f32, 1f32, 1f32, 0f32],
+        );
+        // Width 0 and mono fold-down both average the channels
+        assert_matrix(
+            StereoSettings {
+                width: 0f32,
+                ..Default::default()
+            },
+            [0.5; 4],
+        );
+        assert_matrix(
+            StereoSettings {
+                mono: true,
+                ..Default::default()
+            },
+            [0.5; 4],
+        );
+        // Swapping a mono fold-down changes nothing
+        assert_matrix(
+            StereoSettings {
+                mono: true,
+                swap: true,
+                ..Default::default()
+            },
+            [0.5; 4],
+        );
+        // Mid kept, side doubled
+        assert_matrix(
+            StereoSettings {
+                width: 2f32,
+                ..Default::default()
+            },
+            [1.5, -0.5, -0.5, 1.5],
+        );
+        // Pan comes after the swap
+        assert_matrix(
+            StereoSettings {
+                swap: true,
+                pan: 0.5,
+                ..Default::default()
+            },
+            [0f32, 0.5, 1f32, 0f32],
+        );
+    }
+
+    #[test]
+    fn process_applies_the_matrix() {
+        let mut tools = StereoTools::new(StereoSettings {
+            pan: 0.5,
+            ..Default::default()
+        });
+        tools.prepare(2, 48000);
+        let mut buf = [0.5f32, -0.25, 0.1, 0.3];
+        tools.process(&mut buf);
+        assert_eq!(buf, [0.25, -0.25, 0.05, 0.3]);
+    }
+
+    #[test]
+    fn glides_to_the_target() {
+        let mut tools = StereoTools::new(StereoSettings::default());
+        tools.prepare(3, 48000);
+        tools.set(StereoSettings {
+            swap: true,
+            ..Default::default()
+        });
+        let mut buf: Vec<f32> = (0..4800).flat_map(|_| [1f32, 0f32, 7f32]).collect();
+        tools.process(&mut buf);
+        let frames: Vec<_> = buf.chunks_exact(3).collect();
+        // No jump at the start, no step along the way
+        assert!(frames[0][0] > 0.99 && frames[0][1] < 0.01);
+        for pair in frames.windows(2) {
+            assert!((pair[0][0] - pair[1][0]).abs() < 0.01);
+        }
+        // A third of the way left after one 10ms time constant, settled after 100ms
+        assert!(
+            (frames[480][0] - (-1f32).exp()).abs() < 0.01,
+            "{}",
+            frames[480][0]
+        );
+        assert_eq!(frames[4799], [0f32, 1f32, 7f32]);
+        assert!(frames.iter().all(|x| x[2] == 7f32));
+
+        tools.set(StereoSettings::default());
+        tools.reset();
+        let mut buf = [1f32, 0f32, 7f32];
+        tools.process(&mut buf);
+        assert_eq!(buf, [1f32, 0f32, 7f32]);
+    }
+}
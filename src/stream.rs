@@ -0,0 +1,481 @@
+use std::{
+    pin::Pin,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    task::{Context, Poll},
+};
+
+use futures::{Sink, Stream, task::AtomicWaker};
+use rtrb::{Consumer, Producer, RingBuffer};
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum StreamError {
+    #[error("the real-time side of the stream is gone")]
+    Closed,
+}
+
+// Whichever side drops first closes the channel, the other one stops on its next poll
+struct Shared {
+    waker: AtomicWaker,
+    closed: AtomicBool,
+}
+
+impl Shared {
+    fn new() -> Arc<Self> {
+        Arc::new(Self {
+            waker: AtomicWaker::new(),
+            closed: AtomicBool::new(false),
+        })
+    }
+
+    fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::Acquire)
+    }
+
+    fn close(&self) {
+        self.closed.store(true, Ordering::Release);
+        self.waker.wake();
+    }
+}
+
+/// Ring buffer between a real-time capture thread and an [`AudioInput`], sized in frames
+pub fn input_channel(frames: usize, block_align: usize) -> (InputBridge, AudioInput) {
+    let (producer, consumer) = RingBuffer::new(frames * block_align);
+    let shared = Shared::new();
+    (
+        InputBridge {
+            producer,
+            shared: shared.clone(),
+            block_align,
+        },
+        AudioInput {
+            consumer,
+            shared,
+            block_align,
+        },
+    )
+}
+
+/// Ring buffer between an [`AudioOutput`] and a real-time render thread, sized in frames
+pub fn output_channel(frames: usize, block_align: usize) -> (AudioOutput, OutputBridge) {
+    let (producer, consumer) = RingBuffer::new(frames * block_align);
+    let shared = Shared::new();
+    (
+        AudioOutput {
+            producer,
+            shared: shared.clone(),
+            pending: Vec::new(),
+            offset: 0,
+        },
+        OutputBridge {
+            consumer,
+            shared,
+            block_align,
+        },
+    )
+}
+
+/// Ring buffer between a real-time capture thread and another real-time reader, such as a
+/// [`MixerInput`](crate::mixer::MixerInput), sized in frames
+pub fn frame_channel(frames: usize, block_align: usize) -> (InputBridge, FrameConsumer) {
+    let (producer, consumer) = RingBuffer::new(frames * block_align);
+    let shared = Shared::new();
+    (
+        InputBridge {
+            producer,
+            shared: shared.clone(),
+            block_align,
+        },
+        FrameConsumer {
+            consumer,
+            shared,
+            block_align,
+        },
+    )
+}
+
+/// Copies as many whole frames as `consumer` holds and `out` has room for, returns the number of
+/// bytes. Never blocks, so real-time readers of every ring in the crate go through it
+pub fn read_frames(consumer: &mut Consumer<u8>, block_align: usize, out: &mut [u8]) -> usize {
+    let len = out.len().min(consumer.slots()) / block_align * block_align;
+    if len == 0 {
+        return 0;
+    }
+
+    let chunk = consumer.read_chunk(len).unwrap();
+    let (a, b) = chunk.as_slices();
+    out[..a.len()].copy_from_slice(a);
+    out[a.len()..len].copy_from_slice(b);
+    chunk.commit_all();
+    len
+}
+
+/// Real-time end of an [`AudioInput`] or a [`FrameConsumer`]
+pub struct InputBridge {
+    producer: Producer<u8>,
+    shared: Arc<Shared>,
+    block_align: usize,
+}
+
+impl InputBridge {
+    /// Never blocks. Frames that do not fit are dropped, returns the number of bytes written
+    pub fn push(&mut self, data: &[u8]) -> usize {
+        let len = data.len().min(self.producer.slots()) / self.block_align * self.block_align;
+        if len == 0 {
+            return 0;
+        }
+
+        let chunk = self.producer.write_chunk_uninit(len).unwrap();
+        chunk.fill_from_iter(data[..len].iter().copied());
+        self.shared.waker.wake();
+        len
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.shared.is_closed()
+    }
+}
+
+impl Drop for InputBridge {
+    fn drop(&mut self) {
+        self.shared.close();
+    }
+}
+
+/// Captured audio as a stream of interleaved frame blocks. Every item holds whole frames
+pub struct AudioInput {
+    consumer: Consumer<u8>,
+    shared: Arc<Shared>,
+    block_align: usize,
+}
+
+impl AudioInput {
+    pub fn block_align(&self) -> usize {
+        self.block_align
+    }
+
+    fn read_block(&mut self) -> Option<Vec<u8>> {
+        let len = self.consumer.slots() / self.block_align * self.block_align;
+        if len == 0 {
+            return None;
+        }
+
+        let chunk = self.consumer.read_chunk(len).unwrap();
+        let (a, b) = chunk.as_slices();
+        let mut block = Vec::with_capacity(len);
+        block.extend_from_slice(a);
+        block.extend_from_slice(b);
+        chunk.commit_all();
+        Some(block)
+    }
+}
+
+impl Stream for AudioInput {
+    type Item = Vec<u8>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if let Some(block) = self.read_block() {
+            return Poll::Ready(Some(block));
+        }
+
+        self.shared.waker.register(cx.waker());
+        // Frames may have landed between the first read and the registration
+        if let Some(block) = self.read_block() {
+            return Poll::Ready(Some(block));
+        }
+        if self.shared.is_closed() {
+            return Poll::Ready(None);
+        }
+        Poll::Pending
+    }
+}
+
+impl Drop for AudioInput {
+    fn drop(&mut self) {
+        self.shared.close();
+    }
+}
+
+/// Audio to render, as a sink of interleaved frame blocks
+pub struct AudioOutput {
+    producer: Producer<u8>,
+    shared: Arc<Shared>,
+    pending: Vec<u8>,
+    offset: usize,
+}
+
+impl AudioOutput {
+    fn write_pending(&mut self) {
+        let len = (self.pending.len() - self.offset).min(self.producer.slots());
+        if len == 0 {
+            return;
+        }
+
+        let chunk = self.producer.write_chunk_uninit(len).unwrap();
+        chunk.fill_from_iter(self.pending[self.offset..self.offset + len].iter().copied());
+        self.offset += len;
+    }
+
+    fn poll_pending(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), StreamError>> {
+        self.write_pending();
+        if self.offset == self.pending.len() {
+            return Poll::Ready(Ok(()));
+        }
+        if self.shared.is_closed() {
+            return Poll::Ready(Err(StreamError::Closed));
+        }
+
+        self.shared.waker.register(cx.waker());
+        self.write_pending();
+        if self.offset == self.pending.len() {
+            Poll::Ready(Ok(()))
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+impl Sink<Vec<u8>> for AudioOutput {
+    type Error = StreamError;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.poll_pending(cx)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: Vec<u8>) -> Result<(), Self::Error> {
+        if self.shared.is_closed() {
+            return Err(StreamError::Closed);
+        }
+        self.pending = item;
+        self.offset = 0;
+        self.write_pending();
+        Ok(())
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.poll_pending(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        let rs = self.poll_pending(cx);
+        if rs.is_ready() {
+            self.shared.close();
+        }
+        rs
+    }
+}
+
+impl Drop for AudioOutput {
+    fn drop(&mut self) {
+        self.shared.close();
+    }
+}
+
+/// Real-time end of an [`AudioOutput`]
+pub struct OutputBridge {
+    consumer: Consumer<u8>,
+    shared: Arc<Shared>,
+    block_align: usize,
+}
+
+impl OutputBridge {
+    /// Never blocks. Fills `out` like [`read_frames`] and wakes the sink if anything was taken
+    pub fn pull(&mut self, out: &mut [u8]) -> usize {
+        let len = read_frames(&mut self.consumer, self.block_align, out);
+        if len > 0 {
+            self.shared.waker.wake();
+        }
+        len
+    }
+
+    pub fn available_frames(&self) -> usize {
+        self.consumer.slots() / self.block_align
+    }
+
+    /// The sink is closed and everything sent to it has been pulled
+    pub fn is_finished(&self) -> bool {
+        self.shared.is_closed() && self.available_frames() == 0
+    }
+}
+
+impl Drop for OutputBridge {
+    fn drop(&mut self) {
+        self.shared.close();
+    }
+}
+
+/// Non-blocking reading end of a [`frame_channel`]
+pub struct FrameConsumer {
+    consumer: Consumer<u8>,
+    shared: Arc<Shared>,
+    block_align: usize,
+}
+
+impl FrameConsumer {
+    /// Never blocks, see [`read_frames`]
+    pub fn read(&mut self, out: &mut [u8]) -> usize {
+        read_frames(&mut self.consumer, self.block_align, out)
+    }
+
+    pub fn available_frames(&self) -> usize {
+        self.consumer.slots() / self.block_align
+    }
+
+    pub fn block_align(&self) -> usize {
+        self.block_align
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.shared.is_closed()
+    }
+}
+
+impl Drop for FrameConsumer {
+    fn drop(&mut self) {
+        self.shared.close();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::atomic::{AtomicUsize, Ordering},
+        time::Duration,
+    };
+
+    use futures::{SinkExt, StreamExt};
+
+    use super::*;
+    use crate::sim::SimulatedDevice;
+
+    const BLOCK_ALIGN: usize = 4;
+
+    fn device() -> SimulatedDevice {
+        SimulatedDevice {
+            // Enough room that a slow test runner does not drop frames
+            buffer_periods: 64,
+            ..SimulatedDevice::new(BLOCK_ALIGN, 48, Duration::from_millis(1))
+        }
+    }
+
+    // Every byte one more than the last, so gaps and reordering show up
+    fn counter() -> impl FnMut(&mut [u8]) + Send + 'static {
+        let mut next = 0u8;
+        move |buf| {
+            for x in buf {
+                *x = next;
+                next = next.wrapping_add(1);
+            }
+        }
+    }
+
+    fn assert_counts(data: &[u8]) {
+        for (i, x) in data.iter().enumerate() {
+            assert_eq!(*x, i as u8, "at byte {i}");
+        }
+    }
+
+    #[tokio::test]
+    async fn input_is_ordered_and_complete() {
+        let mut input = device().input(counter());
+        assert_eq!(input.block_align(), BLOCK_ALIGN);
+        let mut received = Vec::new();
+        while received.len() < BLOCK_ALIGN * 48 * 20 {
+            let block = input.next().await.unwrap();
+            assert!(!block.is_empty());
+            assert_eq!(block.len() % BLOCK_ALIGN, 0);
+            received.extend(block);
+        }
+        assert_counts(&received);
+    }
+
+    #[tokio::test]
+    async fn sink_drains_before_finishing() {
+        let (mut output, render) = device().output();
+        let data: Vec<u8> = (0..BLOCK_ALIGN * 48 * 50).map(|i| i as u8).collect();
+        // Far more than the ring holds, `send` has to wait for the render thread
+        for chunk in data.chunks(BLOCK_ALIGN * 100) {
+            output.send(chunk.to_vec()).await.unwrap();
+        }
+        output.close().await.unwrap();
+        let rendered = tokio::task::spawn_blocking(move || render.join())
+            .await
+            .unwrap();
+        assert_eq!(rendered, data);
+    }
+
+    #[tokio::test]
+    async fn input_to_output() {
+        let device = device();
+        let mut input = device.input(counter());
+        let (mut output, render) = device.output();
+        let mut sent = 0;
+        while sent < BLOCK_ALIGN * 48 * 30 {
+            let block = input.next().await.unwrap();
+            sent += block.len();
+            output.send(block).await.unwrap();
+        }
+        drop(input);
+        output.close().await.unwrap();
+        let rendered = tokio::task::spawn_blocking(move || render.join())
+            .await
+            .unwrap();
+        assert_eq!(rendered.len(), sent);
+        assert_counts(&rendered);
+    }
+
+    #[tokio::test]
+    async fn dropping_the_input_stops_capture() {
+        let periods = Arc::new(AtomicUsize::new(0));
+        let input = device().input({
+            let periods = periods.clone();
+            move |_| {
+                periods.fetch_add(1, Ordering::Relaxed);
+            }
+        });
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        drop(input);
+        // At most the period in flight when the stream went away
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        let stopped = periods.load(Ordering::Relaxed);
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert_eq!(periods.load(Ordering::Relaxed), stopped);
+    }
+
+    #[tokio::test]
+    async fn dropping_the_capture_side_ends_the_stream() {
+        let (mut bridge, mut input) = input_channel(16, BLOCK_ALIGN);
+        // Partial frames are not pushed
+        assert_eq!(bridge.push(&[1; BLOCK_ALIGN * 2 + 1]), BLOCK_ALIGN * 2);
+        drop(bridge);
+        // What was captured is still delivered
+        assert_eq!(input.next().await.unwrap(), [1; BLOCK_ALIGN * 2]);
+        assert!(input.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn dropping_the_output_finishes_render() {
+        let (mut output, render) = device().output();
+        output.send(vec![7; BLOCK_ALIGN * 48]).await.unwrap();
+        drop(output);
+        let rendered = tokio::task::spawn_blocking(move || render.join())
+            .await
+            .unwrap();
+        assert_eq!(rendered, [7; BLOCK_ALIGN * 48]);
+    }
+
+    #[tokio::test]
+    async fn dropping_the_render_side_fails_the_sink() {
+        let (mut output, bridge) = output_channel(16, BLOCK_ALIGN);
+        output.send(vec![0; BLOCK_ALIGN * 16]).await.unwrap();
+        drop(bridge);
+        // Nothing will ever make room again
+        assert!(matches!(
+            output.send(vec![0; BLOCK_ALIGN]).await,
+            Err(StreamError::Closed)
+        ));
+    }
+}
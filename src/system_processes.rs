@@ -0,0 +1,151 @@
+use std::mem;
+
+use anyhow::Result;
+use tracing::{debug, warn};
+use windows::Win32::{
+    Foundation::{CloseHandle, HWND, LPARAM},
+    Media::Audio::{
+        AudioSessionStateActive, DEVICE_STATE_ACTIVE, IAudioSessionControl2,
+        IAudioSessionEnumerator, IAudioSessionManager2, IMMDeviceCollection, IMMDeviceEnumerator,
+        MMDeviceEnumerator, eRender,
+    },
+    System::{
+        Com::{CLSCTX_ALL, CoCreateInstance},
+        Diagnostics::ToolHelp::{
+            CreateToolhelp32Snapshot, PROCESSENTRY32W, Process32FirstW, Process32NextW,
+            TH32CS_SNAPPROCESS,
+        },
+    },
+    UI::WindowsAndMessaging::{
+        EnumWindows, GetWindowTextW, GetWindowThreadProcessId, IsWindowVisible,
+    },
+};
+use windows_core::{BOOL, Interface};
+
+use crate::process::{ProcessInfo, ProcessProvider};
+
+/// Processes of the running system, from a toolhelp snapshot
+pub struct SystemProcesses;
+
+impl ProcessProvider for SystemProcesses {
+    fn processes(&self) -> Result<Vec<ProcessInfo>> {
+        let titles = window_titles()?;
+        let audio = audio_session_pids()?;
+
+        unsafe {
+            let snapshot = CreateToolhelp32Snapshot(TH32CS_SNAPPROCESS, 0)?;
+            let mut entry = PROCESSENTRY32W {
+                dwSize: mem::size_of::<PROCESSENTRY32W>() as u32,
+                ..Default::default()
+            };
+
+            let mut procs = Vec::new();
+            let mut next = Process32FirstW(snapshot, &mut entry);
+            while next.is_ok() {
+                let pid = entry.th32ProcessID;
+                let len = entry
+                    .szExeFile
+                    .iter()
+                    .position(|x| *x == 0)
+                    .unwrap_or(entry.szExeFile.len());
+                procs.push(ProcessInfo {
+                    pid,
+                    parent_pid: entry.th32ParentProcessID,
+                    exe: String::from_utf16_lossy(&entry.szExeFile[..len]),
+                    window_titles: titles
+                        .iter()
+                        .filter(|(x, _)| *x == pid)
+                        .map(|(_, title)| title.clone())
+                        .collect(),
+                    audio_active: audio.contains(&pid),
+                });
+                next = Process32NextW(snapshot, &mut entry);
+            }
+
+            CloseHandle(snapshot)?;
+            Ok(procs)
+        }
+    }
+}
+
+fn window_titles() -> Result<Vec<(u32, String)>> {
+    let mut titles: Vec<(u32, String)> = Vec::new();
+    unsafe {
+        EnumWindows(
+            Some(push_window_title),
+            LPARAM(&mut titles as *mut _ as isize),
+        )?;
+    }
+    Ok(titles)
+}
+
+unsafe extern "system" fn push_window_title(hwnd: HWND, lparam: LPARAM) -> BOOL {
+    unsafe {
+        let titles = &mut *(lparam.0 as *mut Vec<(u32, String)>);
+        if IsWindowVisible(hwnd).as_bool() {
+            let mut buf = [0u16; 512];
+            let len = GetWindowTextW(hwnd, &mut buf);
+            if len > 0 {
+                let mut pid = 0;
+                GetWindowThreadProcessId(hwnd, Some(&mut pid));
+                titles.push((pid, String::from_utf16_lossy(&buf[..len as usize])));
+            }
+        }
+        true.into()
+    }
+}
+
+// Pids owning an active session on any active render endpoint. An endpoint or session that can't be
+// queried is skipped, only enumerating the endpoints themselves is an error
+fn audio_session_pids() -> Result<Vec<u32>> {
+    unsafe {
+        let dev_enum: IMMDeviceEnumerator =
+            CoCreateInstance(&MMDeviceEnumerator, None, CLSCTX_ALL)?;
+        let devs = dev_enum.EnumAudioEndpoints(eRender, DEVICE_STATE_ACTIVE)?;
+
+        let mut pids = Vec::new();
+        for i in 0..devs.GetCount()? {
+            let (sessions, count) = match endpoint_sessions(&devs, i) {
+                Ok(x) => x,
+                Err(e) => {
+                    warn!("skipping render endpoint {i}, sessions unavailable: {e}");
+                    continue;
+                }
+            };
+            for j in 0..count {
+                match active_session_pid(&sessions, j) {
+                    Ok(Some(pid)) => pids.push(pid),
+                    Ok(None) => {}
+                    Err(e) => debug!("skipping session {j} on render endpoint {i}: {e}"),
+                }
+            }
+        }
+        Ok(pids)
+    }
+}
+
+fn endpoint_sessions(
+    devs: &IMMDeviceCollection,
+    i: u32,
+) -> windows_core::Result<(IAudioSessionEnumerator, i32)> {
+    unsafe {
+        let manager: IAudioSessionManager2 = devs.Item(i)?.Activate(CLSCTX_ALL, None)?;
+        let sessions = manager.GetSessionEnumerator()?;
+        let count = sessions.GetCount()?;
+        Ok((sessions, count))
+    }
+}
+
+fn active_session_pid(
+    sessions: &IAudioSessionEnumerator,
+    j: i32,
+) -> windows_core::Result<Option<u32>> {
+    unsafe {
+        let session: IAudioSessionControl2 = sessions.GetSession(j)?.cast()?;
+        if session.GetState()? == AudioSessionStateActive {
+            Ok(Some(session.GetProcessId()?))
+        } else {
+            Ok(None)
+        }
+    }
+}
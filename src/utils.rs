@@ -1,24 +1,30 @@
 use std::{
     fmt::{Debug, Display},
-    fs,
-    io::{self, BufRead, BufReader, Write},
     mem,
     ops::Deref,
-    str::FromStr,
-    sync::{LazyLock, Mutex},
 };
 
-use anyhow::{Result, anyhow};
+use anyhow::Result;
 use extension_trait::extension_trait;
-use windows::Win32::{
-    Devices::FunctionDiscovery::PKEY_Device_FriendlyName,
-    Media::{
-        Audio::{IMMDevice, WAVEFORMATEX, WAVEFORMATEXTENSIBLE},
-        KernelStreaming::WAVE_FORMAT_EXTENSIBLE,
+use windows::{
+    Win32::{
+        Devices::FunctionDiscovery::PKEY_Device_FriendlyName,
+        Foundation::HANDLE,
+        Media::{
+            Audio::{
+                IMMDevice, WAVE_FORMAT_PCM, WAVEFORMATEX, WAVEFORMATEXTENSIBLE,
+                WAVEFORMATEXTENSIBLE_0,
+            },
+            KernelStreaming::{KSDATAFORMAT_SUBTYPE_PCM, WAVE_FORMAT_EXTENSIBLE},
+            Multimedia::{KSDATAFORMAT_SUBTYPE_IEEE_FLOAT, WAVE_FORMAT_IEEE_FLOAT},
+        },
+        System::Com::{CoTaskMemFree, STGM_READWRITE},
     },
-    System::Com::{CoTaskMemFree, STGM_READWRITE},
+    core::Interface,
 };
 
+use crate::format::{FormatSpec, SampleFormat, default_channel_mask};
+
 #[extension_trait]
 pub impl IMMDeviceEx for IMMDevice {
     fn display_name(&self) -> Result<impl Display> {
@@ -30,44 +36,19 @@ pub impl IMMDeviceEx for IMMDevice {
     }
 }
 
-pub fn prompt_with<T: FromStr>(q: impl Display, input: &mut impl BufRead) -> Result<T>
-where
-    T::Err: std::error::Error + Send + Sync + 'static,
-{
-    print!("{q}");
-    io::stdout().flush()?;
-    let mut buf = String::new();
-    input.read_line(&mut buf)?;
-    Ok(buf.trim().parse::<T>()?)
-}
+/// Moves a COM interface and the event handle it signals to a stream thread. Interfaces created in
+/// the multithreaded apartment can be used from any other MTA thread, and an event handle from any
+/// thread at all. Only that pair is `Send`, anything else keeps its own auto traits
+pub struct ComSend<T>(pub T);
 
-pub fn prompt_stdio<T: FromStr>(q: impl Display) -> Result<T>
-where
-    T::Err: std::error::Error + Send + Sync + 'static,
-{
-    prompt_with(q, &mut std::io::stdin().lock())
-}
+unsafe impl<I: Interface> Send for ComSend<(I, HANDLE)> {}
 
-pub fn prompt<T: FromStr>(q: impl Display) -> Result<T>
-where
-    T::Err: std::error::Error + Send + Sync + 'static,
-{
-    if let Some(lp) = LP.as_ref() {
-        let mut guard = lp.lock().map_err(|_| anyhow!("cannot lock"))?;
-        prompt_with(q, &mut &mut *guard)
-    } else {
-        prompt_with(q, &mut std::io::stdin().lock())
+impl<T> ComSend<T> {
+    pub fn into_inner(self) -> T {
+        self.0
     }
 }
 
-static LP: LazyLock<Option<Box<Mutex<dyn BufRead + Send + Sync>>>> = LazyLock::new(|| {
-    fs::File::open("./stdio.txt")
-        .ok()
-        .map(|x| BufReader::new(x))
-        .map(|x| Mutex::new(x))
-        .map(|x| Box::new(x) as Box<Mutex<dyn BufRead + Send + Sync>>)
-});
-
 #[extension_trait]
 pub impl Wftex for WAVEFORMATEX {
     fn debug(&self) -> impl Debug + '_ {
@@ -117,6 +98,69 @@ impl WaveFormat {
             }
         }
     }
+
+    /// `None` for anything that is not plain float or integer PCM
+    pub fn spec(&self) -> Option<FormatSpec> {
+        let (float, valid_bits) = match self {
+            WaveFormat::Ex(wfx) => match wfx.wFormatTag as u32 {
+                WAVE_FORMAT_IEEE_FLOAT => (true, wfx.wBitsPerSample),
+                WAVE_FORMAT_PCM => (false, wfx.wBitsPerSample),
+                _ => return None,
+            },
+            WaveFormat::Extensible(wfx) => {
+                let valid_bits = unsafe { wfx.Samples.wValidBitsPerSample };
+                let sub_format = wfx.SubFormat;
+                match sub_format {
+                    KSDATAFORMAT_SUBTYPE_IEEE_FLOAT => (true, valid_bits),
+                    KSDATAFORMAT_SUBTYPE_PCM => (false, valid_bits),
+                    _ => return None,
+                }
+            }
+        };
+
+        let sample_format = match (float, self.wBitsPerSample, valid_bits) {
+            (true, 32, _) => SampleFormat::F32,
+            (false, 32, 32) => SampleFormat::I32,
+            (false, 32, 24) => SampleFormat::I24In32,
+            (false, 24, _) => SampleFormat::I24,
+            (false, 16, _) => SampleFormat::I16,
+            _ => return None,
+        };
+        Some(FormatSpec {
+            sample_format,
+            channels: self.nChannels,
+            sample_rate: self.nSamplesPerSec,
+        })
+    }
+}
+
+impl From<FormatSpec> for WaveFormat {
+    fn from(spec: FormatSpec) -> Self {
+        let block_align = spec.block_align() as u16;
+        let sub_format = if spec.sample_format.is_float() {
+            KSDATAFORMAT_SUBTYPE_IEEE_FLOAT
+        } else {
+            KSDATAFORMAT_SUBTYPE_PCM
+        };
+
+        WaveFormat::Extensible(WAVEFORMATEXTENSIBLE {
+            Format: WAVEFORMATEX {
+                wFormatTag: WAVE_FORMAT_EXTENSIBLE as u16,
+                nChannels: spec.channels,
+                nSamplesPerSec: spec.sample_rate,
+                nAvgBytesPerSec: spec.sample_rate * block_align as u32,
+                nBlockAlign: block_align,
+                wBitsPerSample: spec.sample_format.bytes() as u16 * 8,
+                cbSize: (mem::size_of::<WAVEFORMATEXTENSIBLE>() - mem::size_of::<WAVEFORMATEX>())
+                    as u16,
+            },
+            Samples: WAVEFORMATEXTENSIBLE_0 {
+                wValidBitsPerSample: spec.sample_format.valid_bits(),
+            },
+            dwChannelMask: default_channel_mask(spec.channels),
+            SubFormat: sub_format,
+        })
+    }
 }
 
 /// This will move into an owned type and call CoTaskMemFree on the pointer
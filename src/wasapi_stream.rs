@@ -0,0 +1,200 @@
+use std::{ptr, slice};
+
+use anyhow::Result;
+use windows::{
+    Win32::{
+        Foundation::{HANDLE, WAIT_TIMEOUT},
+        Media::Audio::{
+            AUDCLNT_BUFFERFLAGS_SILENT, IAudioCaptureClient, IAudioClient, IAudioRenderClient,
+            IMMDevice,
+        },
+        System::Threading::{CreateEventW, WaitForSingleObject},
+    },
+    core::Owned,
+};
+
+use crate::{
+    engine::{StreamConfig, StreamKind},
+    format::{FrameSource, SampleWriter},
+    pipe::{ClientSource, InitInfo, init_client, spawn},
+    stream::{AudioInput, AudioOutput, InputBridge, OutputBridge, input_channel, output_channel},
+    utils::{ComSend, WaveFormat},
+};
+
+// Endpoint buffers worth of frames between the MMCSS thread and the async side
+pub(crate) const BRIDGE_BUFFERS: usize = 4;
+
+impl AudioInput {
+    /// Starts `client` on its own MMCSS thread, which only copies packets into the ring buffer and
+    /// wakes the stream. The thread stops once the stream is dropped. `client` is a loopback
+    /// client like the one `capture_process` returns, endpoints go through `open_with`
+    pub fn open(client: IAudioClient, wfx: Option<WaveFormat>) -> Result<(Self, WaveFormat)> {
+        Self::open_with(client.into(), wfx, &StreamConfig::new(StreamKind::Loopback))
+    }
+
+    /// Records everything `render_endpoint` plays, in its mix format
+    pub fn loopback(render_endpoint: IMMDevice) -> Result<(Self, WaveFormat)> {
+        Self::open_with(
+            render_endpoint.into(),
+            None,
+            &StreamConfig::new(StreamKind::Loopback),
+        )
+    }
+
+    pub fn open_with(
+        source: ClientSource,
+        wfx: Option<WaveFormat>,
+        config: &StreamConfig,
+    ) -> Result<(Self, WaveFormat)> {
+        unsafe {
+            let ev = CreateEventW(None, false, false, None)?;
+            let (client, info) = init_client(&source, wfx, ev, config)?;
+            let wfx = info.wfx;
+            let (bridge, input) =
+                input_channel(info.buf_size as usize * BRIDGE_BUFFERS, info.block as usize);
+
+            let ctx = ComSend((client, ev));
+            spawn("wasapi input", move || {
+                let (client, ev) = ctx.into_inner();
+                capture_loop(&client, ev, &info, bridge)
+            });
+            Ok((input, wfx))
+        }
+    }
+}
+
+impl AudioOutput {
+    /// Starts `client` on its own MMCSS thread, which only copies frames out of the ring buffer and
+    /// wakes the sink. The thread stops once the sink is closed and drained
+    pub fn open(client: IAudioClient, wfx: Option<WaveFormat>) -> Result<(Self, WaveFormat)> {
+        Self::open_with(client.into(), wfx, &StreamConfig::new(StreamKind::Render))
+    }
+
+    pub fn open_with(
+        source: ClientSource,
+        wfx: Option<WaveFormat>,
+        config: &StreamConfig,
+    ) -> Result<(Self, WaveFormat)> {
+        unsafe {
+            let ev = CreateEventW(None, false, false, None)?;
+            let (client, info) = init_client(&source, wfx, ev, config)?;
+            let wfx = info.wfx;
+            let (output, bridge) =
+                output_channel(info.buf_size as usize * BRIDGE_BUFFERS, info.block as usize);
+
+            let ctx = ComSend((client, ev));
+            spawn("wasapi output", move || {
+                let (client, ev) = ctx.into_inner();
+                render_loop(&client, ev, &info, bridge)
+            });
+            Ok((output, wfx))
+        }
+    }
+}
+
+pub(crate) fn capture_loop(
+    client: &IAudioClient,
+    ev: HANDLE,
+    info: &InitInfo,
+    mut bridge: InputBridge,
+) -> Result<()> {
+    unsafe {
+        // Closed on every way out, errors included
+        let ev = Owned::new(ev);
+        let cac: IAudioCaptureClient = client.GetService()?;
+        let block = info.block as usize;
+        let silence = vec![0u8; info.buf_size as usize * block];
+
+        while !bridge.is_closed() {
+            WaitForSingleObject(*ev, 100);
+            loop {
+                let mut cbuf = ptr::null_mut();
+                let mut ftr = 0;
+                let mut flags = 0;
+                cac.GetBuffer(&mut cbuf, &mut ftr, &mut flags, None, None)?;
+                if cbuf.is_null() || ftr == 0 {
+                    break;
+                }
+
+                let len = ftr as usize * block;
+                if flags & AUDCLNT_BUFFERFLAGS_SILENT.0 as u32 != 0 {
+                    bridge.push(&silence[..len.min(silence.len())]);
+                } else {
+                    bridge.push(slice::from_raw_parts(cbuf, len));
+                }
+                cac.ReleaseBuffer(ftr)?;
+            }
+        }
+
+        client.Stop()?;
+        Ok(())
+    }
+}
+
+fn render_loop(
+    client: &IAudioClient,
+    ev: HANDLE,
+    info: &InitInfo,
+    mut bridge: OutputBridge,
+) -> Result<()> {
+    unsafe {
+        // Closed on every way out, errors included
+        let ev = Owned::new(ev);
+        let crc: IAudioRenderClient = client.GetService()?;
+        let block = info.block as usize;
+
+        while !bridge.is_finished() {
+            WaitForSingleObject(*ev, 100);
+            let padding = client.GetCurrentPadding()?;
+            let frames = (info.buf_size - padding).min(bridge.available_frames() as u32);
+            if frames == 0 {
+                continue;
+            }
+
+            let cbuf = crc.GetBuffer(frames)?;
+            let rbuf = slice::from_raw_parts_mut(cbuf, frames as usize * block);
+            let len = bridge.pull(rbuf);
+            crc.ReleaseBuffer((len / block) as u32, 0)?;
+        }
+
+        // Let the engine play what is still in the endpoint buffer. A stalled engine stops
+        // signalling, that ends the wait too
+        while client.GetCurrentPadding()? > 0 {
+            if WaitForSingleObject(*ev, 100) == WAIT_TIMEOUT {
+                break;
+            }
+        }
+        client.Stop()?;
+        Ok(())
+    }
+}
+
+/// Renders `source` on `ev` until an error, for pipes that make their audio on the render thread.
+/// `finish` runs on every encoded block before it is released, e.g. a processing stage
+pub(crate) fn source_loop(
+    client: &IAudioClient,
+    ev: HANDLE,
+    info: &InitInfo,
+    source: &mut impl FrameSource,
+    mut finish: impl FnMut(&mut [u8]),
+) -> Result<()> {
+    unsafe {
+        let crc: IAudioRenderClient = client.GetService()?;
+        let block = info.block as usize;
+        let mut writer = SampleWriter::new(source.spec(), info.buf_size as usize);
+        loop {
+            WaitForSingleObject(ev, 100);
+            let padding = client.GetCurrentPadding()?;
+            let frames = info.buf_size - padding;
+            if frames == 0 {
+                continue;
+            }
+
+            let cbuf = crc.GetBuffer(frames)?;
+            let rbuf = slice::from_raw_parts_mut(cbuf, frames as usize * block);
+            let written = writer.render(source, rbuf);
+            finish(&mut rbuf[..written * block]);
+            crc.ReleaseBuffer(written as u32, 0)?;
+        }
+    }
+}